@@ -0,0 +1,103 @@
+//! Times the Lox workloads in `benches/lox` on each backend, through the same
+//! `Lox` API the binary uses. Each workload checks its own result and throws if
+//! it's wrong.
+//!
+//! `cargo bench` runs every workload for about a second per backend and reports
+//! the time per run. Arguments filter the workloads by name, so
+//! `cargo bench -- fib` only runs `fib.lox`. Without `--bench`, as under
+//! `cargo test --benches`, each workload runs once to check that it works.
+//!
+//! There is no method call workload yet, since the language has no classes.
+use lox::{Backend, Lox};
+use std::{
+    env, fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+
+/// How long to keep running a workload for
+const TARGET_TIME: Duration = Duration::from_secs(1);
+
+/// The fewest runs to time a workload over, however long they take
+const MIN_RUNS: u32 = 5;
+
+const BACKENDS: &[(&str, Backend)] = &[("tree", Backend::Tree), ("vm", Backend::Vm)];
+
+fn main()
+{
+    let mut bench = false;
+    let mut filters = Vec::new();
+    for arg in env::args().skip(1)
+    {
+        match arg.as_str()
+        {
+            "--bench" => bench = true,
+            // Options passed by the test runner
+            _ if arg.starts_with("--") => (),
+            _ => filters.push(arg),
+        }
+    }
+
+    let mut workloads: Vec<_> =
+        fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/lox"))
+            .expect("Couldn't read the workloads")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+            .collect();
+    workloads.sort();
+
+    for path in workloads
+    {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str()))
+        {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).expect("Couldn't read the workload");
+        for &(backend_name, backend) in BACKENDS
+        {
+            let label = format!("{name} ({backend_name})");
+            if !bench
+            {
+                run(&label, &source, backend);
+                println!("{label:<24} ok");
+                continue;
+            }
+
+            // The first run warms up the caches and isn't timed
+            run(&label, &source, backend);
+
+            let mut times = Vec::new();
+            let started = Instant::now();
+            while times.len() < MIN_RUNS as usize || started.elapsed() < TARGET_TIME
+            {
+                times.push(run(&label, &source, backend));
+            }
+
+            let mean = times.iter().sum::<Duration>() / times.len() as u32;
+            let fastest = times.iter().min().unwrap();
+            println!(
+                "{label:<24} {:>10.3} ms/run (fastest {:.3} ms, {} runs)",
+                mean.as_secs_f64() * 1000.0,
+                fastest.as_secs_f64() * 1000.0,
+                times.len()
+            );
+        }
+    }
+}
+
+/// Run a workload with a new interpreter, returning how long it took
+fn run(label: &str, source: &str, backend: Backend) -> Duration
+{
+    let lox = Lox::new();
+    lox.set_backend(backend);
+
+    let started = Instant::now();
+    if let Err(error) = lox.run(source.to_string())
+    {
+        panic!("{label} failed: {error}");
+    }
+    started.elapsed()
+}
@@ -1,4 +1,5 @@
 use crate::{object::Object, tokens::*};
+use std::{rc::Rc, time::Duration};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -16,6 +17,14 @@ pub enum LoxResult
         token: Token, message: String
     },
 
+    /// A runtime error raised by the bytecode VM, which only knows the line
+    /// of the failing instruction rather than its token
+    #[error("[line {line}] RuntimeError: {message}")]
+    VmError
+    {
+        line: usize, message: String
+    },
+
     #[error("[line {line}] Error: {message}")]
     LoxError
     {
@@ -28,36 +37,144 @@ pub enum LoxResult
         line: usize, message: String
     },
 
+    /// An error raised by a native function. The interpreter reports it as a
+    /// `RuntimeError` at the call.
+    #[error("NativeError: {message}")]
+    NativeError
+    {
+        message: String
+    },
+
+    /// The script called `exit()`. This unwinds the whole program and can't
+    /// be caught.
+    #[error("Exited with code {code}")]
+    Exit
+    {
+        code: i32
+    },
+
     #[error("SystemError: {message}")]
     SystemError
     {
         message: String
     },
 
-    #[error("")]
-    Break,
+    /// A compiled `.loxc` file is malformed, or was written by an incompatible
+    /// version of the interpreter
+    #[error("BytecodeError: {message}")]
+    BytecodeError
+    {
+        message: String
+    },
+
+    #[error("[line {}] Uncaught exception: {value}", token.line)]
+    Throw
+    {
+        token: Token, value: Object
+    },
 
-    #[error("")]
-    Return
+    /// The script was stopped by one of the interpreter's limits. Like `Exit`
+    /// this can't be caught.
+    #[error("Aborted: {reason}")]
+    Aborted
     {
-        value: Object
+        reason: AbortReason
     },
 }
 
+/// Why a script was aborted
+#[derive(Debug, Error, PartialEq, Clone)]
+pub enum AbortReason
+{
+    #[error("exceeded the limit of {0} steps")]
+    StepLimit(u64),
+
+    #[error("exceeded the timeout of {0:?}")]
+    Timeout(Duration),
+
+    #[error("interrupted")]
+    Interrupted,
+}
+
+#[derive(Debug, Error, PartialEq, Clone)]
+#[error("{message}")]
+/// An operation was applied to values of the wrong type. The interpreter turns
+/// this into a `RuntimeError` at the offending operator.
+pub struct TypeError
+{
+    pub message: String,
+}
+
+impl TypeError
+{
+    /// A binary operator was given operands it doesn't support. `expected`
+    /// describes what the operator accepts, e.g. "numbers".
+    pub fn operands(operator: &str, expected: &str, left: &Object, right: &Object) -> Self
+    {
+        Self {
+            message: format!(
+                "Operands to '{operator}' must be {expected}, got {} and {}",
+                left.type_name(),
+                right.type_name()
+            ),
+        }
+    }
+
+    /// A unary operator was given an operand it doesn't support
+    pub fn operand(operator: &str, expected: &str, right: &Object) -> Self
+    {
+        Self {
+            message: format!(
+                "Operand to '{operator}' must be {expected}, got {}",
+                right.type_name()
+            ),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
-/// Contains information used for error reporting
+/// Contains information used for error reporting. This is also the value bound
+/// by a `catch` clause when an interpreter error is caught.
 pub struct LoxError
 {
     /// The token that relates to the error
     token: Option<Token>,
 
-    /// The line the error occurred on
-    line: usize,
+    /// The line the error occurred on, if it's known
+    line: Option<usize>,
 
     /// The error message
     message: String,
 }
 
+impl LoxError
+{
+    /// Look up a property of the error object (`message` or `line`)
+    pub fn get(&self, name: &str) -> Option<Object>
+    {
+        match name
+        {
+            "message" => Some(Object::Str(self.message.clone().into())),
+            "line" =>
+            {
+                Some(
+                    self.line
+                        .map_or(Object::Nil, |line| Object::Num(line as f64)),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LoxError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        write!(f, "<error {}>", self.message)
+    }
+}
+
 impl LoxResult
 {
     pub fn report(&self)
@@ -65,7 +182,64 @@ impl LoxResult
         eprintln!("{self}");
     }
 
-    pub fn return_value(value: Object) -> Self { Self::Return { value } }
+    /// The process exit code for a script that failed with this error,
+    /// following the sysexits.h convention used by clox.
+    pub fn exit_code(&self) -> i32
+    {
+        match self
+        {
+            // EX_DATAERR: the script itself is malformed
+            Self::ParseError { .. }
+            | Self::LexError { .. }
+            | Self::LoxError { .. }
+            | Self::BytecodeError { .. } => 65,
+            // EX_SOFTWARE: the script failed while running
+            Self::RuntimeError { .. }
+            | Self::NativeError { .. }
+            | Self::Throw { .. }
+            | Self::SystemError { .. }
+            | Self::VmError { .. } => 70,
+            Self::Exit { code } => *code,
+            // EX_TEMPFAIL: the script may succeed given more time
+            Self::Aborted { .. } => 75,
+        }
+    }
+
+    /// The value a `catch` clause binds for this error, if it can be caught at
+    /// all. Thrown values are passed through as they are, interpreter errors
+    /// are wrapped in an error object.
+    pub fn exception(&self) -> Option<Object>
+    {
+        match self
+        {
+            Self::Throw { value, .. } => Some(value.clone()),
+            Self::RuntimeError { token, message } =>
+            {
+                Some(Object::Error(Rc::new(LoxError {
+                    token: Some(token.clone()),
+                    line: Some(token.line),
+                    message: message.clone(),
+                })))
+            }
+            Self::VmError { line, message } =>
+            {
+                Some(Object::Error(Rc::new(LoxError {
+                    token: None,
+                    line: Some(*line),
+                    message: message.clone(),
+                })))
+            }
+            Self::SystemError { message } =>
+            {
+                Some(Object::Error(Rc::new(LoxError {
+                    token: None,
+                    line: None,
+                    message: message.clone(),
+                })))
+            }
+            _ => None,
+        }
+    }
 
     /// Create a `LoxError`
     pub fn new_lex_error(line: usize, message: &str) -> Self
@@ -91,11 +265,9 @@ impl LoxResult
 
     pub fn new_system_error(message: &str) -> Self
     {
-        let e = Self::SystemError {
+        Self::SystemError {
             message: message.to_string(),
-        };
-        eprintln!("{e}");
-        e
+        }
     }
 
     /// Create a `LoxError` at parsing time
@@ -109,11 +281,13 @@ impl LoxResult
         err
     }
 
-    /// Create a `LoxError` at runtime
+    /// Create an error from inside a native function
+    pub fn new_native_error(message: String) -> Self { Self::NativeError { message } }
+
+    /// Create a `LoxError` at runtime. Unlike the other constructors this
+    /// doesn't report the error, as it may still be caught by the script.
     pub fn new_runtime_error(token: Token, message: String) -> Self
     {
-        let err = Self::RuntimeError { token, message };
-        eprintln!("{err}");
-        err
+        Self::RuntimeError { token, message }
     }
 }
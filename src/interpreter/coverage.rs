@@ -0,0 +1,350 @@
+//! Line and branch coverage of Lox scripts, for `--coverage`. Every statement
+//! of a program or module is registered before it runs, so statements that
+//! never run are reported along with the ones that did. Each `if` statement
+//! is a branch with two sides, the else side being taken when the condition
+//! is false even if there's no else branch.
+use super::{module::display_path, Interpreter};
+use crate::stmt::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+/// How often each side of an `if` statement was taken
+#[derive(Debug, Clone, PartialEq)]
+pub struct Branch
+{
+    pub line: usize,
+
+    /// The times the then branch and the else branch were taken
+    pub taken: [u64; 2],
+}
+
+/// The coverage of one file
+#[derive(Debug)]
+pub struct FileCoverage
+{
+    pub path: PathBuf,
+
+    /// The number of statements run on each line with a statement
+    pub lines: BTreeMap<usize, u64>,
+
+    pub branches: Vec<Branch>,
+}
+
+impl FileCoverage
+{
+    fn lines_hit(&self) -> usize { self.lines.values().filter(|&&hits| hits > 0).count() }
+
+    /// The number of sides of branches that were taken
+    fn branches_hit(&self) -> usize
+    {
+        self.branches
+            .iter()
+            .flat_map(|branch| branch.taken)
+            .filter(|&taken| taken > 0)
+            .count()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Coverage
+{
+    files: Vec<FileCoverage>,
+
+    /// The file and line of each registered statement. Statements are
+    /// identified by their address, as they don't move while they can run.
+    statements: HashMap<*const Stmt, (usize, usize)>,
+
+    /// The file and index of each registered `if` statement's branch
+    branches: HashMap<*const IfStmt, (usize, usize)>,
+}
+
+impl Coverage
+{
+    pub fn files(&self) -> &[FileCoverage] { &self.files }
+
+    /// Register the statements of a program or module run from `path`
+    fn register(&mut self, path: &Path, statements: &[Stmt])
+    {
+        let file = match self.files.iter().position(|file| file.path == path)
+        {
+            Some(file) => file,
+            None =>
+            {
+                self.files.push(FileCoverage {
+                    path: path.to_path_buf(),
+                    lines: BTreeMap::new(),
+                    branches: Vec::new(),
+                });
+                self.files.len() - 1
+            }
+        };
+        self.register_all(file, statements);
+    }
+
+    fn register_all(&mut self, file: usize, statements: &[Stmt])
+    {
+        for statement in statements
+        {
+            self.register_statement(file, statement);
+        }
+    }
+
+    fn register_statement(&mut self, file: usize, stmt: &Stmt)
+    {
+        let line = stmt.line();
+        self.statements.insert(stmt, (file, line));
+        self.files[file].lines.entry(line).or_insert(0);
+
+        match stmt
+        {
+            Stmt::Block(stmt) => self.register_all(file, &stmt.statements),
+            Stmt::Function(stmt) => self.register_all(file, &stmt.body),
+            Stmt::If(stmt) =>
+            {
+                let branches = &mut self.files[file].branches;
+                self.branches.insert(stmt, (file, branches.len()));
+                branches.push(Branch {
+                    line,
+                    taken: [0, 0],
+                });
+
+                self.register_statement(file, &stmt.then_branch);
+                if let Some(else_branch) = &stmt.else_branch
+                {
+                    self.register_statement(file, else_branch);
+                }
+            }
+            Stmt::Try(stmt) =>
+            {
+                self.register_all(file, &stmt.body);
+                if let Some(catch_body) = &stmt.catch_body
+                {
+                    self.register_all(file, catch_body);
+                }
+                if let Some(finally_body) = &stmt.finally_body
+                {
+                    self.register_all(file, finally_body);
+                }
+            }
+            Stmt::While(stmt) => self.register_statement(file, &stmt.body),
+            _ => (),
+        }
+    }
+
+    fn hit(&mut self, stmt: &Stmt)
+    {
+        if let Some(&(file, line)) = self.statements.get(&(stmt as *const Stmt))
+        {
+            *self.files[file].lines.entry(line).or_insert(0) += 1;
+        }
+    }
+
+    fn branch(&mut self, stmt: &IfStmt, condition: bool)
+    {
+        if let Some(&(file, branch)) = self.branches.get(&(stmt as *const IfStmt))
+        {
+            let side = if condition { 0 } else { 1 };
+            self.files[file].branches[branch].taken[side] += 1;
+        }
+    }
+
+    /// Each file's source annotated with how often each line ran, like
+    /// `gcov`. Lines whose statements never ran are marked with `#####`.
+    pub fn text_report(&self) -> String
+    {
+        let mut report = String::new();
+        for file in &self.files
+        {
+            let _ = writeln!(
+                report,
+                "{}: {} of {} lines, {} of {} branches",
+                display_path(&file.path),
+                file.lines_hit(),
+                file.lines.len(),
+                file.branches_hit(),
+                file.branches.len() * 2
+            );
+
+            let source = std::fs::read_to_string(&file.path).unwrap_or_default();
+            for (number, text) in source.lines().enumerate().map(|(i, text)| (i + 1, text))
+            {
+                let count = match file.lines.get(&number)
+                {
+                    Some(0) => "#####".to_string(),
+                    Some(hits) => hits.to_string(),
+                    None => String::new(),
+                };
+                let _ = writeln!(report, "{count:>9} | {text}");
+
+                for branch in file.branches.iter().filter(|branch| branch.line == number)
+                {
+                    // A branch whose `if` never ran is already marked
+                    if branch.taken == [0, 0]
+                    {
+                        continue;
+                    }
+                    for (side, name) in ["then", "else"].iter().enumerate()
+                    {
+                        if branch.taken[side] == 0
+                        {
+                            let _ = writeln!(report, "{:>9} | ^ {name} branch never taken", "");
+                        }
+                    }
+                }
+            }
+            report.push('\n');
+        }
+        report
+    }
+
+    /// The coverage in the LCOV tracefile format
+    pub fn lcov(&self) -> String
+    {
+        let mut lcov = String::new();
+        for file in &self.files
+        {
+            let _ = writeln!(lcov, "TN:\nSF:{}", file.path.display());
+            for (index, branch) in file.branches.iter().enumerate()
+            {
+                let ran = branch.taken != [0, 0];
+                for (side, taken) in branch.taken.iter().enumerate()
+                {
+                    // Branches of statements that never ran are marked with `-`
+                    let taken = if ran
+                    {
+                        taken.to_string()
+                    }
+                    else
+                    {
+                        "-".to_string()
+                    };
+                    let _ = writeln!(lcov, "BRDA:{},{index},{side},{taken}", branch.line);
+                }
+            }
+            let _ = writeln!(
+                lcov,
+                "BRF:{}\nBRH:{}",
+                file.branches.len() * 2,
+                file.branches_hit()
+            );
+            for (line, hits) in &file.lines
+            {
+                let _ = writeln!(lcov, "DA:{line},{hits}");
+            }
+            let _ = writeln!(
+                lcov,
+                "LF:{}\nLH:{}\nend_of_record",
+                file.lines.len(),
+                file.lines_hit()
+            );
+        }
+        lcov
+    }
+}
+
+impl Interpreter
+{
+    /// Start recording which statements run, discarding any earlier coverage
+    pub fn enable_coverage(&self) { *self.coverage.borrow_mut() = Some(Coverage::default()); }
+
+    /// Stop recording coverage, returning what was recorded
+    pub fn take_coverage(&self) -> Option<Coverage> { self.coverage.borrow_mut().take() }
+
+    /// Register the statements of the program or module about to run
+    pub(super) fn register_coverage(&self, statements: &[Stmt])
+    {
+        if let Some(coverage) = self.coverage.borrow_mut().as_mut()
+        {
+            coverage.register(&self.current_file(), statements);
+        }
+    }
+
+    pub(super) fn cover_statement(&self, stmt: &Stmt)
+    {
+        if let Some(coverage) = self.coverage.borrow_mut().as_mut()
+        {
+            coverage.hit(stmt);
+        }
+    }
+
+    pub(super) fn cover_branch(&self, stmt: &IfStmt, condition: bool)
+    {
+        if let Some(coverage) = self.coverage.borrow_mut().as_mut()
+        {
+            coverage.branch(stmt, condition);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::run_in;
+
+    fn cover(source: &str) -> Coverage
+    {
+        let i = Interpreter::new();
+        i.enable_coverage();
+        run_in(&i, source).unwrap();
+        i.take_coverage().unwrap()
+    }
+
+    #[test]
+    fn test_lines_and_branches()
+    {
+        let coverage = cover(
+            "var a = 1;
+            if (a > 0) {
+                a = 2;
+            } else {
+                a = 3;
+            }
+            fun never() {
+                print a;
+            }
+            for (var i = 0; i < 3; i = i + 1) a = a + 1;",
+        );
+
+        let file = &coverage.files()[0];
+        let hits: Vec<_> = file
+            .lines
+            .iter()
+            .map(|(&line, &hits)| (line, hits))
+            .collect();
+        assert_eq!(
+            hits,
+            [
+                (1, 1),
+                (2, 2),
+                (3, 1),
+                (4, 0),
+                (5, 0),
+                (7, 1),
+                (8, 0),
+                (10, 6)
+            ]
+        );
+        assert_eq!(
+            file.branches,
+            [Branch {
+                line: 2,
+                taken: [1, 0]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_lcov()
+    {
+        let coverage = cover("if (false) print 1;\nprint 2;");
+
+        let lcov = coverage.lcov();
+        assert!(lcov.starts_with("TN:\nSF:<script>\n"));
+        assert!(lcov.contains("BRDA:1,0,0,0\nBRDA:1,0,1,1\nBRF:2\nBRH:1\n"));
+        assert!(lcov.contains("DA:1,1\nDA:2,1\nLF:2\nLH:2\nend_of_record\n"));
+    }
+}
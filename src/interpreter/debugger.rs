@@ -0,0 +1,622 @@
+//! An interactive debugger for the tree interpreter, for `lox debug`. The
+//! script pauses before its first statement, at breakpoints, and after
+//! stepping, and reads commands while it's paused. Statements are paused on
+//! when they start a new line of the function being run, or of a loop's next
+//! iteration.
+use super::{module::display_path, resolver::*, Interpreter};
+use crate::{
+    error::LoxResult, expr::*, lexer::Scanner, object::Object, parser::Parser, stmt::Stmt,
+    tokens::Token,
+};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{BufRead, Write},
+    path::PathBuf,
+    rc::Rc,
+};
+
+const HELP: &str = "\
+Commands:
+  break [LINE]     Set a breakpoint on a line, or list the breakpoints
+  delete LINE      Remove the breakpoint on a line
+  step             Run until the next line, stepping into calls
+  next             Run until the next line of this function, stepping over calls
+  out              Run until the function returns
+  continue         Run until the next breakpoint
+  backtrace        Show the functions being run
+  vars             Show the variables in scope
+  print EXPR       Evaluate an expression in the paused scope
+  quit             Stop the script";
+
+/// When to pause next
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode
+{
+    /// At a breakpoint
+    Continue,
+
+    /// At the next line
+    Step,
+
+    /// At the next line run with at most this many calls in progress
+    Next(usize),
+
+    /// At the next line run with fewer than this many calls in progress
+    Out(usize),
+}
+
+/// Where a registered statement is
+struct Location
+{
+    file: usize,
+    scope: Scope,
+}
+
+/// A call in progress, or the top level of the script
+struct CallFrame
+{
+    /// The function being called, or `None` for the top level
+    function: Option<Token>,
+
+    /// The line of the last statement run in the call
+    line: usize,
+}
+
+pub struct Debugger
+{
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    mode: Mode,
+    breakpoints: BTreeSet<usize>,
+    files: Vec<PathBuf>,
+
+    /// The lines of each file, read when they're first shown
+    sources: HashMap<usize, Vec<String>>,
+
+    /// The location of each registered statement, by its address
+    statements: HashMap<*const Stmt, Location>,
+    calls: Vec<CallFrame>,
+
+    /// The globals defined before the script ran, which aren't shown
+    natives: HashSet<Rc<str>>,
+}
+
+impl std::fmt::Debug for Debugger
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        write!(f, "<debugger {:?}>", self.mode)
+    }
+}
+
+impl Debugger
+{
+    /// Register the statements of a program or module run from `path`
+    fn register(&mut self, path: PathBuf, scopes: Scopes)
+    {
+        let file = match self.files.iter().position(|file| *file == path)
+        {
+            Some(file) => file,
+            None =>
+            {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        };
+        self.statements.extend(
+            scopes
+                .into_iter()
+                .map(|(stmt, scope)| (stmt, Location { file, scope })),
+        );
+    }
+
+    /// Whether to pause before running `stmt`
+    fn should_pause(&mut self, stmt: &Stmt) -> bool
+    {
+        let line = stmt.line();
+        let depth = self.calls.len();
+        let frame = self.calls.last_mut().unwrap();
+        if frame.line == line
+        {
+            return false;
+        }
+        frame.line = line;
+
+        self.breakpoints.contains(&line)
+            || match self.mode
+            {
+                Mode::Continue => false,
+                Mode::Step => true,
+                Mode::Next(calls) => depth <= calls,
+                Mode::Out(calls) => depth < calls,
+            }
+    }
+
+    fn say(&mut self, text: impl std::fmt::Display) { let _ = writeln!(self.output, "{text}"); }
+
+    /// Show the line a statement is on
+    fn show_location(&mut self, stmt: &Stmt)
+    {
+        let line = stmt.line();
+        let Some(file) = self.statements.get(&(stmt as *const Stmt)).map(|l| l.file)
+        else
+        {
+            self.say(format!("[line {line}]"));
+            return;
+        };
+
+        let path = &self.files[file];
+        let source = self.sources.entry(file).or_insert_with(|| {
+            std::fs::read_to_string(path)
+                .map(|source| source.lines().map(str::to_string).collect())
+                .unwrap_or_default()
+        });
+        let text = source.get(line - 1).map(|text| text.trim()).unwrap_or("");
+        let location = format!("{}:{line}: {text}", display_path(path));
+        self.say(location);
+    }
+
+    fn backtrace(&mut self)
+    {
+        let frames: Vec<String> = self
+            .calls
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(index, frame)| {
+                match &frame.function
+                {
+                    Some(name) =>
+                    {
+                        format!(
+                            "#{index} {} (declared on line {}) at line {}",
+                            name.lexeme, name.line, frame.line
+                        )
+                    }
+                    None => format!("#{index} <script> at line {}", frame.line),
+                }
+            })
+            .collect();
+        self.say(frames.join("\n"));
+    }
+
+    fn breakpoint_command(&mut self, argument: &str, set: bool)
+    {
+        if argument.is_empty() && set
+        {
+            let lines: Vec<String> = self.breakpoints.iter().map(usize::to_string).collect();
+            match lines.is_empty()
+            {
+                true => self.say("No breakpoints"),
+                false => self.say(format!("Breakpoints on lines {}", lines.join(", "))),
+            }
+            return;
+        }
+
+        match argument.parse::<usize>()
+        {
+            Ok(line) if set =>
+            {
+                self.breakpoints.insert(line);
+                self.say(format!("Breakpoint on line {line}"));
+            }
+            Ok(line) if self.breakpoints.remove(&line) =>
+            {
+                self.say(format!("Removed the breakpoint on line {line}"))
+            }
+            Ok(line) => self.say(format!("No breakpoint on line {line}")),
+            Err(_) => self.say(format!("Expected a line number, got '{argument}'")),
+        }
+    }
+}
+
+/// A value as the debugger shows it, with strings quoted
+fn describe(value: &Object) -> String
+{
+    match value
+    {
+        Object::Str(s) => format!("\"{s}\""),
+        other => other.to_string(),
+    }
+}
+
+/// Point the variables of an expression at the variables of `scope`, or at
+/// the globals if it doesn't have them
+fn bind(expr: &Expr, scope: &Scope)
+{
+    let lookup = |name: &Token| {
+        scope
+            .iter()
+            .rev()
+            .find(|(candidate, _)| *candidate == name.lexeme)
+            .map(|(_, variable)| *variable)
+            .unwrap_or_default()
+    };
+
+    match expr
+    {
+        Expr::Assign(expr) =>
+        {
+            bind(&expr.value, scope);
+            expr.variable.set(lookup(&expr.name));
+        }
+        Expr::Binary(expr) =>
+        {
+            bind(&expr.left, scope);
+            bind(&expr.right, scope);
+        }
+        Expr::Call(expr) =>
+        {
+            bind(&expr.callee, scope);
+            expr.arguments
+                .iter()
+                .for_each(|argument| bind(argument, scope));
+        }
+        Expr::Get(expr) => bind(&expr.object, scope),
+        Expr::Grouping(expr) => bind(&expr.expression, scope),
+        Expr::Literal(_) => (),
+        Expr::Logical(expr) =>
+        {
+            bind(&expr.left, scope);
+            bind(&expr.right, scope);
+        }
+        Expr::Unary(expr) => bind(&expr.right, scope),
+        Expr::Variable(expr) => expr.variable.set(lookup(&expr.name)),
+    }
+}
+
+impl Interpreter
+{
+    /// Pause the script before its first statement, reading debugger commands
+    /// from `input` and writing to `output`
+    pub fn enable_debugger(&self, input: Box<dyn BufRead>, output: Box<dyn Write>)
+    {
+        let natives = self
+            .globals
+            .borrow()
+            .variables()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        *self.debugger.borrow_mut() = Some(Debugger {
+            input,
+            output,
+            mode: Mode::Step,
+            breakpoints: BTreeSet::new(),
+            files: Vec::new(),
+            sources: HashMap::new(),
+            statements: HashMap::new(),
+            calls: vec![CallFrame {
+                function: None,
+                line: 0,
+            }],
+            natives,
+        });
+    }
+
+    /// Resolve a program or module about to run, recording the scope of each
+    /// of its statements if the debugger is enabled
+    pub(super) fn resolve(&self, statements: &[Stmt]) -> Result<usize, LoxResult>
+    {
+        if self.debugger.borrow().is_none()
+        {
+            return Resolver::resolve(statements);
+        }
+
+        let (slots, scopes) = Resolver::resolve_with_scopes(statements)?;
+        let file = self.current_file();
+        if let Some(debugger) = self.debugger.borrow_mut().as_mut()
+        {
+            debugger.register(file, scopes);
+        }
+        Ok(slots)
+    }
+
+    pub(crate) fn debug_call(&self, name: &Token)
+    {
+        if let Some(debugger) = self.debugger.borrow_mut().as_mut()
+        {
+            debugger.calls.push(CallFrame {
+                function: Some(name.clone()),
+                line: 0,
+            });
+        }
+    }
+
+    pub(crate) fn debug_return(&self)
+    {
+        if let Some(debugger) = self.debugger.borrow_mut().as_mut()
+        {
+            debugger.calls.pop();
+        }
+    }
+
+    /// Forget the line last run before a loop goes back to its start, so the
+    /// lines of its next iteration are paused on again
+    pub(super) fn debug_loop(&self)
+    {
+        if let Some(debugger) = self.debugger.borrow_mut().as_mut()
+        {
+            debugger.calls.last_mut().unwrap().line = 0;
+        }
+    }
+
+    /// Pause before running `stmt` if the debugger should, reading commands
+    /// until the script is resumed
+    pub(super) fn debug_statement(&self, stmt: &Stmt) -> Result<(), LoxResult>
+    {
+        let pause = match self.debugger.borrow_mut().as_mut()
+        {
+            Some(debugger) => debugger.should_pause(stmt),
+            None => false,
+        };
+        if !pause
+        {
+            return Ok(());
+        }
+
+        // The debugger is taken out while paused, so calls made by evaluated
+        // expressions aren't debugged themselves
+        let mut debugger = self.debugger.take().unwrap();
+        debugger.show_location(stmt);
+        let result = self.debug_commands(&mut debugger, stmt);
+        *self.debugger.borrow_mut() = Some(debugger);
+        result
+    }
+
+    fn debug_commands(&self, debugger: &mut Debugger, stmt: &Stmt) -> Result<(), LoxResult>
+    {
+        loop
+        {
+            let _ = write!(debugger.output, "(debug) ");
+            let _ = debugger.output.flush();
+
+            let mut line = String::new();
+            if debugger.input.read_line(&mut line).unwrap_or(0) == 0
+            {
+                // Without any more commands, run the rest of the script
+                debugger.breakpoints.clear();
+                debugger.mode = Mode::Continue;
+                return Ok(());
+            }
+
+            let line = line.trim();
+            let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+            let argument = argument.trim();
+            let depth = debugger.calls.len();
+            match command
+            {
+                "" => (),
+                "b" | "break" => debugger.breakpoint_command(argument, true),
+                "d" | "delete" => debugger.breakpoint_command(argument, false),
+                "s" | "step" =>
+                {
+                    debugger.mode = Mode::Step;
+                    return Ok(());
+                }
+                "n" | "next" =>
+                {
+                    debugger.mode = Mode::Next(depth);
+                    return Ok(());
+                }
+                "o" | "out" =>
+                {
+                    debugger.mode = Mode::Out(depth);
+                    return Ok(());
+                }
+                "c" | "continue" =>
+                {
+                    debugger.mode = Mode::Continue;
+                    return Ok(());
+                }
+                "bt" | "backtrace" => debugger.backtrace(),
+                "v" | "vars" => self.show_variables(debugger, stmt),
+                "p" | "print" => self.evaluate_command(debugger, stmt, argument),
+                "q" | "quit" => return Err(LoxResult::Exit { code: 0 }),
+                "h" | "help" => debugger.say(HELP),
+                _ => debugger.say(format!("Unknown command '{command}', try 'help'")),
+            }
+        }
+    }
+
+    /// The scope a statement was resolved in
+    fn debug_scope(&self, debugger: &Debugger, stmt: &Stmt) -> Scope
+    {
+        debugger
+            .statements
+            .get(&(stmt as *const Stmt))
+            .map(|location| Rc::clone(&location.scope))
+            .unwrap_or_else(|| Rc::new([]))
+    }
+
+    /// Show the variables the paused statement can see
+    fn show_variables(&self, debugger: &mut Debugger, stmt: &Stmt)
+    {
+        let scope = self.debug_scope(debugger, stmt);
+        let mut shown = HashSet::new();
+        for (name, variable) in scope.iter().rev()
+        {
+            if !shown.insert(Rc::clone(name))
+            {
+                continue;
+            }
+            let value = match *variable
+            {
+                Variable::Local(slot) => self.frame.borrow().get(slot),
+                Variable::Upvalue(index) => self.frame.borrow().upvalue(index).borrow().clone(),
+                Variable::Global => continue,
+            };
+            debugger.say(format!("{name} = {}", describe(&value)));
+        }
+
+        let mut globals: Vec<_> = self
+            .environment
+            .borrow()
+            .borrow()
+            .variables()
+            .into_iter()
+            .filter(|(name, _)| !debugger.natives.contains(name) && !shown.contains(name))
+            .collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, value) in globals
+        {
+            debugger.say(format!("{name} = {} (global)", describe(&value)));
+        }
+    }
+
+    /// Evaluate an expression as if it were part of the paused statement
+    fn evaluate_command(&self, debugger: &mut Debugger, stmt: &Stmt, source: &str)
+    {
+        let mut scanner = Scanner::new(format!("{source};"));
+        let statements = scanner
+            .scan_tokens()
+            .and_then(|tokens| Parser::new(tokens).parse());
+        let expr = match statements.as_deref()
+        {
+            Ok([Stmt::Expression(statement)]) => &statement.expression,
+            Ok(_) => return debugger.say("Expected an expression"),
+            Err(error) => return debugger.say(error),
+        };
+
+        bind(expr, &self.debug_scope(debugger, stmt));
+        match self.evaluate(expr)
+        {
+            Ok(value) => debugger.say(describe(&value)),
+            Err(error) => debugger.say(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::{run_in, SharedOutput};
+    use std::io::Cursor;
+
+    /// Debug a script with the given commands, returning what the debugger
+    /// wrote with the script's path replaced by `script`
+    fn debug(name: &str, script: &str, commands: &str) -> String
+    {
+        let path = std::env::temp_dir().join(format!(
+            "lox-debugger-test-{}-{name}.lox",
+            std::process::id()
+        ));
+        std::fs::write(&path, script).unwrap();
+
+        let output = SharedOutput::default();
+        let i = Interpreter::new();
+        i.set_script_path(&path);
+        i.enable_debugger(
+            Box::new(Cursor::new(commands.to_string())),
+            Box::new(output.clone()),
+        );
+        let result = run_in(&i, script);
+        let path = path.canonicalize().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        match result
+        {
+            Ok(()) | Err(LoxResult::Exit { .. }) => (),
+            Err(error) => panic!("The script failed: {error}"),
+        }
+
+        output
+            .contents()
+            .replace(&path.display().to_string(), "script")
+    }
+
+    /// The locations paused on, in order
+    fn pauses(output: &str) -> Vec<&str>
+    {
+        output
+            .split("(debug) ")
+            .filter(|line| line.starts_with("script:"))
+            .collect()
+    }
+
+    const SCRIPT: &str = "fun add(a, b) {
+    var sum = a + b;
+    return sum;
+}
+var x = add(1, 2);
+print x;";
+
+    const LOOP: &str = "var i = 0;
+while (i < 3)
+    i = i + 1;
+print i;";
+
+    #[test]
+    fn test_breakpoints_and_variables()
+    {
+        let output = debug(
+            "breakpoints",
+            SCRIPT,
+            "break 3\ncontinue\nvars\nprint sum * 10\nbacktrace\n",
+        );
+        assert_eq!(
+            output,
+            "script:1: fun add(a, b) {
+(debug) Breakpoint on line 3
+(debug) script:3: return sum;
+(debug) sum = 3
+b = 2
+a = 1
+add = <fn add> (global)
+(debug) 30
+(debug) #0 add (declared on line 1) at line 3
+#1 <script> at line 5
+(debug) "
+        );
+    }
+
+    #[test]
+    fn test_stepping()
+    {
+        let output = debug("stepping", SCRIPT, "next\nstep\nstep\nout\nquit\n");
+        let lines: Vec<_> = output
+            .split("(debug) ")
+            .filter(|line| !line.is_empty())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "script:1: fun add(a, b) {\n",
+                "script:5: var x = add(1, 2);\n",
+                "script:2: var sum = a + b;\n",
+                "script:3: return sum;\n",
+                "script:6: print x;\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stepping_through_loop()
+    {
+        let output = debug("loop", LOOP, "step\nstep\nstep\nstep\nstep\nstep\n");
+        assert_eq!(
+            pauses(&output),
+            [
+                "script:1: var i = 0;\n",
+                "script:2: while (i < 3)\n",
+                "script:3: i = i + 1;\n",
+                "script:3: i = i + 1;\n",
+                "script:3: i = i + 1;\n",
+                "script:4: print i;\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_breakpoint_in_loop()
+    {
+        let output = debug(
+            "loop-breakpoint",
+            LOOP,
+            "break 3\ncontinue\ncontinue\nprint i\ncontinue\ncontinue\n",
+        );
+        assert_eq!(pauses(&output).len(), 4);
+        assert!(output.contains("(debug) 1\n"));
+    }
+}
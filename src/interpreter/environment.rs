@@ -1,17 +1,28 @@
 use crate::{error::LoxResult, object::Object, tokens::Token};
 use std::{
-    cell::RefCell,
-    collections::{hash_map::Entry, HashMap},
+    cell::{Cell, RefCell},
+    collections::HashMap,
     rc::Rc,
 };
 
 /// An `Environment` contains variable's identifiers and their associated
 /// values.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Default)]
 pub struct Environment
 {
     enclosing: Option<Rc<RefCell<Environment>>>,
-    values: HashMap<String, Object>,
+    values: HashMap<Rc<str>, Object>,
+
+    /// The approximate number of bytes used by the entries of this
+    /// environment and every other one sharing the counter. Environments share
+    /// their enclosing environment's counter.
+    memory: Rc<Cell<usize>>,
+}
+
+/// The approximate number of bytes used by a variable
+pub(crate) fn entry_size(name: &str, value: &Object) -> usize
+{
+    name.len() + std::mem::size_of::<Object>() + value.heap_size()
 }
 
 impl Environment
@@ -22,31 +33,96 @@ impl Environment
         Environment {
             values: HashMap::new(),
             enclosing: None,
+            memory: Rc::new(Cell::new(0)),
         }
     }
 
     pub fn new_with_enclosing(enclosing: Rc<RefCell<Environment>>) -> Environment
     {
+        let memory = Rc::clone(&enclosing.borrow().memory);
         Environment {
             enclosing: Some(enclosing),
             values: HashMap::new(),
+            memory,
         }
     }
 
+    /// The approximate number of bytes used by the variables of every
+    /// environment sharing this one's counter
+    pub fn memory_used(&self) -> usize { self.memory.get() }
+
+    /// The counter behind `memory_used`, for the interpreter's frames to share
+    pub(crate) fn memory_counter(&self) -> Rc<Cell<usize>> { Rc::clone(&self.memory) }
+
     /// Define a new variable in the envrionment
-    pub fn define(&mut self, name: String, value: Object) { self.values.insert(name, value); }
+    pub fn define(&mut self, name: impl Into<Rc<str>>, value: Object)
+    {
+        let name = name.into();
+        self.allocate(entry_size(&name, &value));
+        if let Some(old) = self.values.insert(Rc::clone(&name), value)
+        {
+            self.free(entry_size(&name, &old));
+        }
+    }
 
     /// Get a variable's value from the environment
     pub fn get(&self, name: Token) -> Result<Object, LoxResult>
     {
-        if let Some(o) = self.values.get(&name.lexeme)
+        self.lookup(&name.lexeme).ok_or_else(|| {
+            LoxResult::new_runtime_error(
+                name.clone(),
+                format!("Undefined variable '{}'.", name.lexeme),
+            )
+        })
+    }
+
+    /// Look up a variable by name in this environment or the enclosing ones
+    pub fn lookup(&self, name: &str) -> Option<Object>
+    {
+        if let Some(o) = self.values.get(name)
         {
-            Ok(o.clone())
+            Some(o.clone())
         }
-        else if let Some(enclosing) = &self.enclosing
+        else
         {
             // Check the enclosing scope for the variable
-            enclosing.borrow().get(name)
+            self.enclosing
+                .as_ref()
+                .and_then(|enclosing| enclosing.borrow().lookup(name))
+        }
+    }
+
+    /// Get a variable defined in this environment itself, ignoring the
+    /// enclosing scopes
+    pub fn get_own(&self, name: &str) -> Option<Object> { self.values.get(name).cloned() }
+
+    /// The variables of this environment and the enclosing ones, without
+    /// the ones they shadow
+    pub fn variables(&self) -> Vec<(Rc<str>, Object)>
+    {
+        let mut variables: Vec<_> = self
+            .values
+            .iter()
+            .map(|(name, value)| (Rc::clone(name), value.clone()))
+            .collect();
+        if let Some(enclosing) = &self.enclosing
+        {
+            for (name, value) in enclosing.borrow().variables()
+            {
+                if !self.values.contains_key(&name)
+                {
+                    variables.push((name, value));
+                }
+            }
+        }
+        variables
+    }
+
+    pub fn assign(&mut self, name: &Token, value: Object) -> Result<(), LoxResult>
+    {
+        if self.set(&name.lexeme, value)
+        {
+            Ok(())
         }
         else
         {
@@ -57,26 +133,47 @@ impl Environment
         }
     }
 
-    pub fn assign(&mut self, name: &Token, value: Object) -> Result<(), LoxResult>
+    /// Assign to an existing variable in this environment or the enclosing
+    /// ones, returning false if there isn't one
+    pub fn set(&mut self, name: &str, value: Object) -> bool
     {
-        if let Entry::Occupied(mut object) = self.values.entry(name.lexeme.clone())
+        if let Some(object) = self.values.get_mut(name)
         {
-            object.insert(value);
-            Ok(())
+            let (new, old) = (
+                value.heap_size(),
+                std::mem::replace(object, value).heap_size(),
+            );
+            self.allocate(new);
+            self.free(old);
+            true
         }
         else if let Some(enclosing) = &self.enclosing
         {
             // Check the enclosing scope for the variable
-            enclosing.borrow_mut().assign(name, value)
+            enclosing.borrow_mut().set(name, value)
         }
         else
         {
-            Err(LoxResult::new_runtime_error(
-                name.clone(),
-                format!("Undefined variable '{}'.", name.lexeme),
-            ))
+            false
         }
     }
+
+    fn allocate(&self, bytes: usize) { self.memory.set(self.memory.get() + bytes); }
+
+    fn free(&self, bytes: usize) { self.memory.set(self.memory.get().saturating_sub(bytes)); }
+}
+
+impl Drop for Environment
+{
+    fn drop(&mut self)
+    {
+        let bytes = self
+            .values
+            .iter()
+            .map(|(name, value)| entry_size(name, value))
+            .sum();
+        self.free(bytes);
+    }
 }
 
 #[cfg(test)]
@@ -116,10 +213,7 @@ mod tests
     {
         let mut e = Environment::new();
 
-        e.define(
-            "cool".to_string(),
-            Object::Str("FooBar is cool".to_string()),
-        );
+        e.define("cool".to_string(), Object::Str("FooBar is cool".into()));
 
         assert_eq!(
             e.get(Token::new(
@@ -129,7 +223,7 @@ mod tests
                 0
             ))
             .unwrap(),
-            Object::Str("FooBar is cool".to_string())
+            Object::Str("FooBar is cool".into())
         )
     }
 
@@ -155,15 +249,12 @@ mod tests
         let tok = Token::new(TokenType::Identifier, "cool".to_string(), None, 0);
 
         // Define the variable
-        e.define(
-            "cool".to_string(),
-            Object::Str("FooBar is cool".to_string()),
-        );
+        e.define("cool".to_string(), Object::Str("FooBar is cool".into()));
 
         // Check that the variable's value is defined
         assert_eq!(
             e.get(tok.clone()).unwrap(),
-            Object::Str("FooBar is cool".to_string())
+            Object::Str("FooBar is cool".into())
         );
 
         // Assign a new value to the variable
@@ -190,7 +281,10 @@ mod tests
         let e = Rc::new(RefCell::new(Environment::new()));
         let f = Environment::new_with_enclosing(Rc::clone(&e));
 
-        assert_eq!(f.enclosing.unwrap().borrow().values, e.borrow().values);
+        assert_eq!(
+            f.enclosing.as_ref().unwrap().borrow().values,
+            e.borrow().values
+        );
     }
 
     #[test]
@@ -241,4 +335,25 @@ mod tests
         // Check that the new value has been assigned to the variable
         assert_eq!(f.get(tok.clone()).unwrap(), Object::Bool(true));
     }
+
+    #[test]
+    fn test_memory_accounting()
+    {
+        let e = Rc::new(RefCell::new(Environment::new()));
+        e.borrow_mut().define("a".to_string(), Object::Num(1.0));
+        let base = e.borrow().memory_used();
+
+        let mut f = Environment::new_with_enclosing(Rc::clone(&e));
+        f.define("s".to_string(), Object::Str("x".repeat(100).into()));
+        assert!(e.borrow().memory_used() >= base + 100);
+
+        // Replacing the string frees it
+        let tok = Token::new(TokenType::Identifier, "s".to_string(), None, 0);
+        f.assign(&tok, Object::Nil).unwrap();
+        assert!(e.borrow().memory_used() < base + 100);
+
+        // Dropping the inner environment frees its entries
+        drop(f);
+        assert_eq!(e.borrow().memory_used(), base);
+    }
 }
@@ -0,0 +1,249 @@
+use crate::object::Object;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+/// A variable shared between a frame and the closures that captured it
+pub type Upvalue = Rc<RefCell<Object>>;
+
+/// A slot of a frame
+#[derive(Debug)]
+enum Slot
+{
+    Value(Object),
+
+    /// A variable captured by a closure, which moves into a shared cell so
+    /// later assignments are seen by both
+    Shared(Upvalue),
+
+    /// A variable captured by a closure declared before it. Defining the
+    /// variable fills in the cell, rather than replacing it.
+    Pending(Upvalue),
+}
+
+impl Slot
+{
+    fn heap_size(&self) -> usize
+    {
+        match self
+        {
+            Slot::Value(value) => value.heap_size(),
+            Slot::Shared(cell) | Slot::Pending(cell) => cell.borrow().heap_size(),
+        }
+    }
+}
+
+/// The local variables of a function call, or of the blocks at the top level
+/// of a program, in the slots given to them by the `Resolver`
+#[derive(Debug)]
+pub struct Frame
+{
+    slots: Vec<Slot>,
+
+    /// The variables captured by the function being run
+    upvalues: Rc<Vec<Upvalue>>,
+
+    /// The counter of the memory used by variables, shared with the global
+    /// environment
+    memory: Rc<Cell<usize>>,
+}
+
+impl Frame
+{
+    pub fn new(slots: usize, upvalues: Rc<Vec<Upvalue>>, memory: Rc<Cell<usize>>) -> Self
+    {
+        memory.set(memory.get() + slots * std::mem::size_of::<Object>());
+        Self {
+            slots: (0..slots).map(|_| Slot::Value(Object::Nil)).collect(),
+            upvalues,
+            memory,
+        }
+    }
+
+    /// Create a frame for a call, sharing this frame's memory counter
+    pub fn nested(&self, slots: usize, upvalues: Rc<Vec<Upvalue>>) -> Self
+    {
+        Self::new(slots, upvalues, Rc::clone(&self.memory))
+    }
+
+    /// Define a new variable in a slot, replacing the slot's previous variable
+    /// unless a closure is waiting for this one
+    pub fn define(&mut self, slot: usize, value: Object)
+    {
+        self.allocate(value.heap_size());
+        let old = match &self.slots[slot]
+        {
+            Slot::Pending(cell) =>
+            {
+                let cell = Rc::clone(cell);
+                let old = cell.replace(value);
+                self.slots[slot] = Slot::Shared(cell);
+                old.heap_size()
+            }
+            _ => std::mem::replace(&mut self.slots[slot], Slot::Value(value)).heap_size(),
+        };
+        self.free(old);
+    }
+
+    pub fn get(&self, slot: usize) -> Object
+    {
+        match &self.slots[slot]
+        {
+            Slot::Value(value) => value.clone(),
+            Slot::Shared(cell) | Slot::Pending(cell) => cell.borrow().clone(),
+        }
+    }
+
+    pub fn set(&mut self, slot: usize, value: Object)
+    {
+        self.allocate(value.heap_size());
+        let old = match &mut self.slots[slot]
+        {
+            Slot::Value(old) => std::mem::replace(old, value),
+            Slot::Shared(cell) | Slot::Pending(cell) => cell.replace(value),
+        };
+        self.free(old.heap_size());
+    }
+
+    /// Capture the variable in a slot for a closure
+    pub fn capture(&mut self, slot: usize) -> Upvalue
+    {
+        let slot = &mut self.slots[slot];
+        if let Slot::Value(value) = slot
+        {
+            *slot = Slot::Shared(Rc::new(RefCell::new(std::mem::replace(value, Object::Nil))));
+        }
+
+        match slot
+        {
+            Slot::Shared(cell) | Slot::Pending(cell) => Rc::clone(cell),
+            Slot::Value(_) => unreachable!(),
+        }
+    }
+
+    /// Capture the variable `name` that will be defined in a slot, for a
+    /// closure declared before it. The slot's current variable, if any, is an
+    /// earlier one.
+    pub fn capture_forward(&mut self, slot: usize, name: &Rc<str>) -> Upvalue
+    {
+        if let Slot::Pending(cell) = &self.slots[slot]
+        {
+            return Rc::clone(cell);
+        }
+
+        let cell = Rc::new(RefCell::new(Object::Undefined(Rc::clone(name))));
+        let old = std::mem::replace(&mut self.slots[slot], Slot::Pending(Rc::clone(&cell)));
+        self.free(old.heap_size());
+        cell
+    }
+
+    /// Detach a slot from the closures waiting for its variable, once the
+    /// variable's block has ended without defining it. They keep seeing it
+    /// as undefined, rather than whichever variable uses the slot next.
+    pub fn release(&mut self, slot: usize)
+    {
+        if let Slot::Pending(cell) = &self.slots[slot]
+        {
+            self.free(cell.borrow().heap_size());
+            self.slots[slot] = Slot::Value(Object::Nil);
+        }
+    }
+
+    /// One of the variables captured by the function being run
+    pub fn upvalue(&self, index: usize) -> &Upvalue { &self.upvalues[index] }
+
+    pub fn set_upvalue(&self, index: usize, value: Object)
+    {
+        self.allocate(value.heap_size());
+        let old = self.upvalues[index].replace(value);
+        self.free(old.heap_size());
+    }
+
+    fn allocate(&self, bytes: usize) { self.memory.set(self.memory.get() + bytes); }
+
+    fn free(&self, bytes: usize) { self.memory.set(self.memory.get().saturating_sub(bytes)); }
+}
+
+impl Drop for Frame
+{
+    fn drop(&mut self)
+    {
+        let bytes: usize = self.slots.iter().map(Slot::heap_size).sum();
+        self.free(bytes + self.slots.len() * std::mem::size_of::<Object>());
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_captured_slots_are_shared()
+    {
+        let memory = Rc::new(Cell::new(0));
+        let mut frame = Frame::new(2, Rc::default(), Rc::clone(&memory));
+        frame.define(0, Object::Num(1.0));
+
+        let upvalue = frame.capture(0);
+        frame.set(0, Object::Num(2.0));
+        assert_eq!(*upvalue.borrow(), Object::Num(2.0));
+
+        // A new variable in the slot isn't the captured one
+        frame.define(0, Object::Num(3.0));
+        assert_eq!(*upvalue.borrow(), Object::Num(2.0));
+        assert_eq!(frame.get(0), Object::Num(3.0));
+    }
+
+    #[test]
+    fn test_forward_captures_see_the_definition()
+    {
+        let memory = Rc::new(Cell::new(0));
+        let mut frame = Frame::new(1, Rc::default(), Rc::clone(&memory));
+        frame.define(0, Object::Num(1.0));
+        let earlier = frame.capture(0);
+
+        let name: Rc<str> = "later".into();
+        let upvalue = frame.capture_forward(0, &name);
+        assert_eq!(*upvalue.borrow(), Object::Undefined(Rc::clone(&name)));
+        assert!(Rc::ptr_eq(&frame.capture_forward(0, &name), &upvalue));
+        frame.define(0, Object::Num(2.0));
+        assert_eq!(*upvalue.borrow(), Object::Num(2.0));
+        assert_eq!(*earlier.borrow(), Object::Num(1.0));
+
+        // Once defined, the variable is an ordinary captured one
+        frame.define(0, Object::Num(3.0));
+        assert_eq!(*upvalue.borrow(), Object::Num(2.0));
+    }
+
+    #[test]
+    fn test_released_slots_are_detached()
+    {
+        let memory = Rc::new(Cell::new(0));
+        let mut frame = Frame::new(1, Rc::default(), Rc::clone(&memory));
+        let name: Rc<str> = "later".into();
+        let upvalue = frame.capture_forward(0, &name);
+
+        // The next variable in the slot isn't the one the closure waited for
+        frame.release(0);
+        frame.define(0, Object::Num(1.0));
+        assert_eq!(*upvalue.borrow(), Object::Undefined(name));
+        assert_eq!(frame.get(0), Object::Num(1.0));
+    }
+
+    #[test]
+    fn test_memory_accounting()
+    {
+        let memory = Rc::new(Cell::new(0));
+        let mut frame = Frame::new(1, Rc::default(), Rc::clone(&memory));
+        frame.define(0, Object::Str("x".repeat(100).into()));
+        assert!(memory.get() >= 100);
+
+        frame.set(0, Object::Nil);
+        assert!(memory.get() < 100);
+
+        drop(frame);
+        assert_eq!(memory.get(), 0);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
     error::*,
-    interpreter::{environment::Environment, Interpreter},
+    interpreter::{
+        environment::Environment, frame::Upvalue, resolver::FunctionLayout, Interpreter,
+    },
     object::{callable::LoxCallable, *},
     stmt::{FunctionStmt, Stmt},
     tokens::Token,
@@ -12,18 +14,51 @@ pub struct LoxFunction
     name: Token,
     params: Rc<Vec<Token>>,
     body: Rc<Vec<Stmt>>,
-    closure: Rc<RefCell<Environment>>,
+    layout: Rc<FunctionLayout>,
+
+    /// The variables of enclosing functions used by this one
+    upvalues: Rc<Vec<Upvalue>>,
+
+    /// The environment of the script or module declaring the function, which
+    /// its globals are looked up in
+    globals: Rc<RefCell<Environment>>,
 }
 
 impl LoxFunction
 {
-    pub fn new(declaration: &FunctionStmt, closure: &Rc<RefCell<Environment>>) -> Self
+    /// Create a function declared in the frame being run, capturing the
+    /// variables the resolver found it uses
+    pub fn new(declaration: &FunctionStmt, interpreter: &Interpreter) -> Self
     {
+        let mut frame = interpreter.frame.borrow_mut();
+        let upvalues = declaration
+            .layout
+            .captures
+            .borrow()
+            .iter()
+            .map(|capture| {
+                if capture.forward
+                {
+                    frame.capture_forward(capture.index, &capture.name)
+                }
+                else if capture.is_local
+                {
+                    frame.capture(capture.index)
+                }
+                else
+                {
+                    Rc::clone(frame.upvalue(capture.index))
+                }
+            })
+            .collect();
+
         Self {
             name: declaration.name.clone(),
             body: Rc::clone(&declaration.body),
             params: Rc::clone(&declaration.params),
-            closure: Rc::clone(closure),
+            layout: Rc::clone(&declaration.layout),
+            upvalues: Rc::new(upvalues),
+            globals: Rc::clone(&interpreter.environment.borrow()),
         }
     }
 }
@@ -32,22 +67,28 @@ impl LoxCallable for LoxFunction
 {
     fn call(&self, interpreter: &Interpreter, arguments: Vec<Object>) -> Result<Object, LoxResult>
     {
-        let mut e = Environment::new_with_enclosing(Rc::clone(&self.closure));
+        interpreter.trace_call(&self.name, &arguments);
+        interpreter.debug_call(&self.name);
+        let mut frame = interpreter
+            .frame
+            .borrow()
+            .nested(self.layout.slots.get(), Rc::clone(&self.upvalues));
 
-        for (param, arg) in self.params.iter().zip(arguments.iter())
+        // The parameters take the first slots
+        for (slot, arg) in arguments.into_iter().enumerate()
         {
-            e.define(param.get_identifier(), arg.clone());
+            frame.define(slot, arg);
         }
 
-        match interpreter.execute_block(&self.body, e)
-        {
-            Err(LoxResult::Return { value }) => Ok(value),
-            Err(e) => Err(e),
-            Ok(_) => Ok(Object::Nil),
-        }
+        let result = interpreter.profile_call(&self.name, || {
+            interpreter.execute_function(&self.body, frame, &self.globals)
+        });
+        interpreter.debug_return();
+        interpreter.trace_exit(&self.name, &result);
+        result
     }
 
     fn arity(&self) -> usize { self.params.len() }
 
-    fn to_string(&self) -> String { self.name.get_identifier() }
+    fn to_string(&self) -> String { self.name.lexeme.to_string() }
 }
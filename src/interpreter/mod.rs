@@ -1,48 +1,165 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    path::PathBuf,
+    rc::Rc,
+};
 
+pub mod coverage;
+pub mod debugger;
 pub mod environment;
+pub mod frame;
 pub mod lox_function;
+pub mod module;
 pub mod native_functions;
+pub mod profiler;
+pub mod resolver;
+pub mod sandbox;
+pub mod trace;
 
 use crate::{
-    error::LoxResult,
+    error::{LoxResult, TypeError},
     expr::*,
     object::{callable::Callable, Object},
+    optimizer::Optimizer,
     stmt::*,
-    tokens::TokenType,
+    tokens::{Token, TokenType},
 };
+use coverage::Coverage;
+use debugger::Debugger;
 use environment::Environment;
+use frame::Frame;
 use lox_function::LoxFunction;
+use module::Module;
 use native_functions::*;
+use profiler::Profiler;
+use resolver::{Resolver, Variable};
+use sandbox::{Sandbox, MAX_CALL_DEPTH};
+use trace::Tracer;
+
+/// How a statement finished executing. Anything other than `Normal` unwinds
+/// the enclosing statements until it reaches the loop or function it belongs
+/// to.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Flow
+{
+    /// Execution continues with the next statement
+    Normal,
+
+    /// Exit the innermost loop
+    Break,
+
+    /// Skip to the next iteration of the innermost loop
+    Continue,
+
+    /// Return a value from the innermost function
+    Return(Object),
+}
+
+/// The parts of the host system a script is allowed to use. Embedders running
+/// untrusted code can turn these off, and natives needing them will fail with
+/// a `SystemError`.
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities
+{
+    /// Reading from standard input and writing to standard error
+    pub console: bool,
+
+    /// Reading and writing files
+    pub filesystem: bool,
+
+    /// Reading environment variables and running other programs
+    pub process: bool,
+}
+
+impl Default for Capabilities
+{
+    fn default() -> Self
+    {
+        Self {
+            console: true,
+            filesystem: true,
+            process: true,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Interpreter
 {
     pub globals: Rc<RefCell<Environment>>,
 
-    /// Our variable environment. We use a RefCell for mutability.
+    /// The natives and builtin constants, which enclose the script's globals
+    /// and those of every module
+    builtins: Rc<RefCell<Environment>>,
+
+    /// The environment globals are looked up in: the script's, or that of the
+    /// module being run
     environment: RefCell<Rc<RefCell<Environment>>>,
 
-    loop_nest: RefCell<usize>,
+    /// The local variables of the function being run
+    frame: RefCell<Frame>,
+
+    /// How many function calls deep we are
+    function_nest: RefCell<usize>,
+
+    /// Disables implicit conversions in arithmetic, such as `1 + "a"`
+    strict: RefCell<bool>,
+
+    /// Whether programs are optimized before they run
+    optimize: RefCell<bool>,
+
+    /// The generator behind the `random` natives
+    random: RefCell<Random>,
+
+    capabilities: RefCell<Capabilities>,
+
+    /// The command line arguments passed to the script, as returned by `args()`
+    script_args: RefCell<Vec<String>>,
+
+    /// Every module loaded so far, by canonical path
+    modules: RefCell<HashMap<PathBuf, Rc<Module>>>,
+
+    /// The files being run, from the main script to the module currently
+    /// being loaded
+    module_stack: RefCell<Vec<PathBuf>>,
+
+    /// Directories to search for modules in
+    module_paths: RefCell<Vec<PathBuf>>,
+
+    /// Execution limits and the interrupt flag
+    sandbox: RefCell<Sandbox>,
+
+    /// The calls recorded for `--profile`, if it's enabled
+    profiler: RefCell<Option<Profiler>>,
+
+    /// The statements run, recorded for `--coverage` if it's enabled
+    coverage: RefCell<Option<Coverage>>,
+
+    /// Where `--trace` writes what the interpreter does, if it's enabled
+    tracer: RefCell<Option<Tracer>>,
+
+    /// The debugger of `lox debug`, if it's enabled
+    debugger: RefCell<Option<Debugger>>,
 }
 
-impl StmtVisitor<()> for Interpreter
+impl StmtVisitor<Flow> for Interpreter
 {
-    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<(), LoxResult>
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<Flow, LoxResult>
     {
         self.evaluate(&stmt.expression)?;
-        Ok(())
+        Ok(Flow::Normal)
     }
 
-    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<(), LoxResult>
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<Flow, LoxResult>
     {
         let value = self.evaluate(&stmt.expression)?;
         // Print the expression
         println!("{value}");
-        Ok(())
+        Ok(Flow::Normal)
     }
 
-    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<(), LoxResult>
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<Flow, LoxResult>
     {
         let value: Object = if let Some(expr) = &stmt.initializer
         {
@@ -53,22 +170,21 @@ impl StmtVisitor<()> for Interpreter
             Object::Nil
         };
 
-        self.environment
-            .borrow()
-            .borrow_mut()
-            .define(stmt.name.lexeme.clone(), value);
-        Ok(())
+        self.reserve_variable(&stmt.name, &value)?;
+        self.define(stmt.variable.get(), &stmt.name, value);
+        Ok(Flow::Normal)
     }
 
-    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<(), LoxResult>
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<Flow, LoxResult>
     {
-        let e = Environment::new_with_enclosing(self.environment.borrow().clone());
-        self.execute_block(&stmt.statements, e)
+        self.execute_block(&stmt.statements)
     }
 
-    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<(), LoxResult>
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<Flow, LoxResult>
     {
-        if self.is_truthy(&self.evaluate(&stmt.condition)?)
+        let condition = self.is_truthy(&self.evaluate(&stmt.condition)?);
+        self.cover_branch(stmt, condition);
+        if condition
         {
             self.execute(&stmt.then_branch)
         }
@@ -78,64 +194,122 @@ impl StmtVisitor<()> for Interpreter
         }
         else
         {
-            Ok(())
+            Ok(Flow::Normal)
         }
     }
 
-    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<(), LoxResult>
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<Flow, LoxResult>
     {
-        *self.loop_nest.borrow_mut() += 1;
-        while self.is_truthy(&self.evaluate(&stmt.condition)?)
+        self.execute_loop(stmt)
+    }
+
+    // The resolver has checked that `break` and `continue` are inside a loop
+    fn visit_break_stmt(&self, _stmt: &BreakStmt) -> Result<Flow, LoxResult> { Ok(Flow::Break) }
+
+    fn visit_continue_stmt(&self, _stmt: &ContinueStmt) -> Result<Flow, LoxResult>
+    {
+        Ok(Flow::Continue)
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<Flow, LoxResult>
+    {
+        // A local function is defined before it captures its variables, so it can
+        // capture itself
+        let variable = stmt.variable.get();
+        if let Variable::Local(slot) = variable
         {
-            match self.execute(&stmt.body)
+            self.frame.borrow_mut().define(slot, Object::Nil);
+        }
+
+        let function = Object::Func(Callable {
+            func: Rc::new(LoxFunction::new(stmt, self)),
+        });
+        match variable
+        {
+            Variable::Local(slot) =>
             {
-                Err(LoxResult::Break) => break,
-                Err(e) => return Err(e),
-                Ok(_) => (),
+                self.trace_define(&stmt.name, &function);
+                self.frame.borrow_mut().set(slot, function)
             }
+            _ => self.define(variable, &stmt.name, function),
         }
-        *self.loop_nest.borrow_mut() -= 1;
-
-        Ok(())
+        Ok(Flow::Normal)
     }
 
-    fn visit_break_stmt(&self, stmt: &BreakStmt) -> Result<(), LoxResult>
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<Flow, LoxResult>
     {
-        if *self.loop_nest.borrow() == 0
+        if let Some(value) = &stmt.value
         {
-            Err(LoxResult::new_runtime_error(
-                stmt.token.clone(),
-                "Cannot break outside of loop".to_string(),
-            ))
+            Ok(Flow::Return(self.evaluate(value)?))
         }
         else
         {
-            Err(LoxResult::Break)
+            Ok(Flow::Return(Object::Nil))
         }
     }
 
-    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<(), LoxResult>
+    fn visit_import_stmt(&self, stmt: &ImportStmt) -> Result<Flow, LoxResult>
     {
-        let function = LoxFunction::new(stmt, &self.environment.borrow());
-        self.environment.borrow().borrow_mut().define(
-            stmt.name.get_identifier(),
-            Object::Func(Callable {
-                func: Rc::new(function),
-            }),
-        );
-        Ok(())
+        let module = self.import(stmt)?;
+
+        let mut variables = stmt.variables.iter().map(Cell::get);
+        if let Some(alias) = &stmt.alias
+        {
+            let variable = variables.next().unwrap_or_default();
+            self.define(variable, alias, Object::Module(Rc::clone(&module)));
+        }
+
+        for name in &stmt.names
+        {
+            let value = module.get(&name.lexeme).ok_or_else(|| {
+                LoxResult::new_runtime_error(
+                    name.clone(),
+                    format!(
+                        "Module {} has no definition '{}'.",
+                        stmt.path.lexeme, name.lexeme
+                    ),
+                )
+            })?;
+            self.define(variables.next().unwrap_or_default(), name, value);
+        }
+
+        Ok(Flow::Normal)
     }
 
-    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<(), LoxResult>
+    fn visit_throw_stmt(&self, stmt: &ThrowStmt) -> Result<Flow, LoxResult>
     {
-        if let Some(value) = &stmt.value
+        Err(LoxResult::Throw {
+            token: stmt.keyword.clone(),
+            value: self.evaluate(&stmt.value)?,
+        })
+    }
+
+    fn visit_try_stmt(&self, stmt: &TryStmt) -> Result<Flow, LoxResult>
+    {
+        let mut result = self.execute_block(&stmt.body);
+
+        if let (Err(error), Some(name), Some(catch_body)) =
+            (&result, &stmt.catch_name, &stmt.catch_body)
         {
-            Err(LoxResult::return_value(self.evaluate(value)?))
+            // Errors outside of the script's control pass through
+            if let Some(exception) = error.exception()
+            {
+                self.define(stmt.catch_variable.get(), name, exception);
+                result = self.execute_block(catch_body);
+            }
         }
-        else
+
+        if let Some(finally_body) = &stmt.finally_body
         {
-            Err(LoxResult::return_value(Object::Nil))
+            // An error or jump out of the `finally` block replaces the pending one
+            let flow = self.execute_block(finally_body)?;
+            if flow != Flow::Normal
+            {
+                return Ok(flow);
+            }
         }
+
+        result
     }
 }
 
@@ -151,32 +325,12 @@ impl ExprVisitor<Object> for Interpreter
         let left = self.evaluate(&expr.left)?;
         let right = self.evaluate(&expr.right)?;
 
-        let res = match expr.operator.token_type()
-        {
-            TokenType::Minus => left - right,
-            TokenType::Slash => left / right,
-            TokenType::Star => left * right,
-            TokenType::Plus => left + right,
-            TokenType::Greater => left.greater(right),
-            TokenType::GreaterEqual => left.greater_eq(right),
-            TokenType::Less => left.less(right),
-            TokenType::LessEqual => left.less_eq(right),
-            TokenType::BangEqual => left.neq(right),
-            TokenType::Equal => left.eq(right),
-            _ => todo!(),
-        };
-
-        if res == Object::ArithmeticError || res == Object::ComparisonError
-        {
-            Err(LoxResult::new_runtime_error(
-                expr.operator.clone(),
-                "Illegal expression".to_string(),
-            ))
-        }
-        else
-        {
-            Ok(res)
-        }
+        let res = self
+            .binary(expr.operator.token_type(), left, right)
+            .map_err(|e| LoxResult::new_runtime_error(expr.operator.clone(), e.to_string()))?;
+        self.reserve(res.heap_size())
+            .map_err(|message| LoxResult::new_runtime_error(expr.operator.clone(), message))?;
+        Ok(res)
     }
 
     fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<Object, LoxResult>
@@ -192,11 +346,8 @@ impl ExprVisitor<Object> for Interpreter
         {
             TokenType::Minus =>
             {
-                match right
-                {
-                    Object::Num(n) => Ok(Object::Num(-n)),
-                    _ => Ok(Object::Nil),
-                }
+                (-right)
+                    .map_err(|e| LoxResult::new_runtime_error(expr.operator.clone(), e.to_string()))
             }
             TokenType::Bang => Ok(Object::Bool(!self.is_truthy(&right))),
 
@@ -206,16 +357,55 @@ impl ExprVisitor<Object> for Interpreter
 
     fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<Object, LoxResult>
     {
-        self.environment.borrow().borrow().get(expr.name.clone())
+        match expr.variable.get()
+        {
+            Variable::Global => self.environment.borrow().borrow().get(expr.name.clone()),
+            Variable::Local(slot) => Ok(self.frame.borrow().get(slot)),
+            Variable::Upvalue(index) =>
+            {
+                match self.frame.borrow().upvalue(index).borrow().clone()
+                {
+                    Object::Undefined(_) =>
+                    {
+                        Err(LoxResult::new_runtime_error(
+                            expr.name.clone(),
+                            format!("Undefined variable '{}'.", expr.name.lexeme),
+                        ))
+                    }
+                    value => Ok(value),
+                }
+            }
+        }
     }
 
     fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<Object, LoxResult>
     {
         let value = self.evaluate(&expr.value)?;
-        self.environment
-            .borrow()
-            .borrow_mut()
-            .assign(&expr.name, value.clone())?;
+        self.reserve_variable(&expr.name, &value)?;
+        match expr.variable.get()
+        {
+            Variable::Global =>
+            {
+                self.environment
+                    .borrow()
+                    .borrow_mut()
+                    .assign(&expr.name, value.clone())?
+            }
+            Variable::Local(slot) => self.frame.borrow_mut().set(slot, value.clone()),
+            Variable::Upvalue(index) =>
+            {
+                let frame = self.frame.borrow();
+                if let Object::Undefined(_) = *frame.upvalue(index).borrow()
+                {
+                    return Err(LoxResult::new_runtime_error(
+                        expr.name.clone(),
+                        format!("Undefined variable '{}'.", expr.name.lexeme),
+                    ));
+                }
+                frame.set_upvalue(index, value.clone())
+            }
+        }
+        self.trace_assign(&expr.name, &value);
         Ok(value)
     }
 
@@ -238,6 +428,32 @@ impl ExprVisitor<Object> for Interpreter
         self.evaluate(&expr.right)
     }
 
+    fn visit_get_expr(&self, expr: &GetExpr) -> Result<Object, LoxResult>
+    {
+        let object = self.evaluate(&expr.object)?;
+
+        let value = match &object
+        {
+            Object::Error(error) => error.get(&expr.name.lexeme),
+            Object::Record(record) => record.get(&expr.name.lexeme),
+            Object::Module(module) => module.get(&expr.name.lexeme),
+            _ =>
+            {
+                return Err(LoxResult::new_runtime_error(
+                    expr.name.clone(),
+                    "Only modules, records and error objects have properties.".to_string(),
+                ))
+            }
+        };
+
+        value.ok_or_else(|| {
+            LoxResult::new_runtime_error(
+                expr.name.clone(),
+                format!("Undefined property '{}'.", expr.name.lexeme),
+            )
+        })
+    }
+
     fn visit_call_expr(&self, expr: &CallExpr) -> Result<Object, LoxResult>
     {
         let callee = self.evaluate(&expr.callee)?;
@@ -255,10 +471,22 @@ impl ExprVisitor<Object> for Interpreter
             {
                 return Err(LoxResult::new_runtime_error(
                     expr.paren.clone(),
-                    format!("Expected {arity} arguments but got {len}"),
+                    format!(
+                        "Expected {arity} arguments to '{}' but got {len}",
+                        function.func.to_string()
+                    ),
                 ));
             }
-            function.func.call(self, arguments)
+            function.func.call(self, arguments).map_err(|e| {
+                match e
+                {
+                    LoxResult::NativeError { message } =>
+                    {
+                        LoxResult::new_runtime_error(expr.paren.clone(), message)
+                    }
+                    e => e,
+                }
+            })
         }
         else
         {
@@ -280,72 +508,318 @@ impl Interpreter
 {
     pub fn new() -> Self
     {
-        let globals = Rc::new(RefCell::new(Environment::new()));
+        let builtins = Rc::new(RefCell::new(Environment::new()));
 
-        globals.borrow_mut().define(
+        builtins.borrow_mut().define(
             "clock".to_string(),
             Object::Func(Callable {
                 func: Rc::new(NativeClock),
             }),
         );
+        define_natives(&mut builtins.borrow_mut(), STRING_FUNCTIONS);
+        define_natives(&mut builtins.borrow_mut(), MATH_FUNCTIONS);
+        define_natives(&mut builtins.borrow_mut(), CONVERSION_FUNCTIONS);
+        define_natives(&mut builtins.borrow_mut(), IO_FUNCTIONS);
+        define_natives(&mut builtins.borrow_mut(), PROCESS_FUNCTIONS);
+        for (name, value) in MATH_CONSTANTS
+        {
+            builtins
+                .borrow_mut()
+                .define(name.to_string(), Object::Num(*value));
+        }
+        let globals = Rc::new(RefCell::new(Environment::new_with_enclosing(Rc::clone(
+            &builtins,
+        ))));
 
+        let memory = globals.borrow().memory_counter();
         Self {
             environment: RefCell::new(Rc::clone(&globals)),
-            loop_nest: RefCell::new(0),
+            frame: RefCell::new(Frame::new(0, Rc::default(), memory)),
+            function_nest: RefCell::new(0),
+            strict: RefCell::new(false),
+            optimize: RefCell::new(false),
+            random: RefCell::new(Random::default()),
+            capabilities: RefCell::new(Capabilities::default()),
+            script_args: RefCell::new(Vec::new()),
+            modules: RefCell::new(HashMap::new()),
+            module_stack: RefCell::new(Vec::new()),
+            module_paths: RefCell::new(Vec::new()),
+            sandbox: RefCell::new(Sandbox::default()),
+            profiler: RefCell::new(None),
+            coverage: RefCell::new(None),
+            tracer: RefCell::new(None),
+            debugger: RefCell::new(None),
             globals,
+            builtins,
         }
     }
-    fn evaluate(&self, expr: &Expr) -> Result<Object, LoxResult> { expr.accept(self) }
 
-    fn is_truthy(&self, object: &Object) -> bool
+    /// Enable or disable strict arithmetic. In strict mode `+` only accepts two
+    /// numbers or two strings.
+    pub fn set_strict(&self, strict: bool) { *self.strict.borrow_mut() = strict; }
+
+    /// Whether strict arithmetic is enabled
+    pub(crate) fn is_strict(&self) -> bool { *self.strict.borrow() }
+
+    /// Enable or disable the optimizer pass, which runs on every program
+    /// before it's interpreted or compiled
+    pub fn set_optimize(&self, optimize: bool) { *self.optimize.borrow_mut() = optimize; }
+
+    /// Run the optimizer pass over a program, if it's enabled. The program is
+    /// checked first, so removing dead code can't hide its errors.
+    pub(crate) fn optimize(&self, statements: Vec<Stmt>) -> Result<Vec<Stmt>, LoxResult>
+    {
+        match *self.optimize.borrow()
+        {
+            true =>
+            {
+                Resolver::resolve(&statements)?;
+                Optimizer::new(self).optimize(&statements)
+            }
+            false => Ok(statements),
+        }
+    }
+
+    /// Restrict (or grant) the script's access to the host system
+    pub fn set_capabilities(&self, capabilities: Capabilities)
+    {
+        *self.capabilities.borrow_mut() = capabilities;
+    }
+
+    /// Set the command line arguments returned by `args()`
+    pub fn set_args(&self, args: Vec<String>) { *self.script_args.borrow_mut() = args; }
+
+    fn evaluate(&self, expr: &Expr) -> Result<Object, LoxResult>
+    {
+        self.step()?;
+        expr.accept(self)
+    }
+
+    /// Apply a binary operator. The optimizer uses this too, so that folded
+    /// operations behave exactly like the ones the interpreter evaluates.
+    pub(crate) fn binary(
+        &self,
+        operator: TokenType,
+        left: Object,
+        right: Object,
+    ) -> Result<Object, TypeError>
+    {
+        match operator
+        {
+            TokenType::Minus => left - right,
+            TokenType::Slash => left / right,
+            TokenType::Star => left * right,
+            TokenType::Plus if *self.strict.borrow() => left.add_strict(right),
+            TokenType::Plus => left + right,
+            TokenType::Greater => left.greater(right),
+            TokenType::GreaterEqual => left.greater_eq(right),
+            TokenType::Less => left.less(right),
+            TokenType::LessEqual => left.less_eq(right),
+            TokenType::BangEqual => Ok(left.neq(right)),
+            TokenType::Equal => Ok(left.eq(right)),
+            _ => todo!(),
+        }
+    }
+
+    pub(crate) fn is_truthy(&self, object: &Object) -> bool
     {
         // `Nil` and `False` values are false, everything else is true
         !matches!(object, Object::Nil | Object::Bool(false))
     }
 
-    pub fn interpret(&self, statements: &[Stmt]) -> bool
+    /// Execute a program, stopping at the first runtime error and returning
+    /// it. Reporting the error is left to the caller.
+    pub fn interpret(&self, statements: &[Stmt]) -> Result<(), LoxResult>
     {
-        *self.loop_nest.borrow_mut() = 0;
-        for statement in statements
+        let slots = self.resolve(statements)?;
+        let frame = self.frame.borrow().nested(slots, Rc::default());
+        *self.frame.borrow_mut() = frame;
+        self.register_coverage(statements);
+
+        *self.function_nest.borrow_mut() = 0;
+        self.sandboxed(|| {
+            statements
+                .iter()
+                .try_for_each(|statement| self.execute(statement).map(|_| ()))
+        })
+    }
+
+
+    fn execute(&self, stmt: &Stmt) -> Result<Flow, LoxResult>
+    {
+        self.step()?;
+        self.debug_statement(stmt)?;
+        self.cover_statement(stmt);
+        self.trace_statement(stmt);
+        stmt.accept(self)
+    }
+
+    /// Define a variable declared by the resolver as `variable`
+    fn define(&self, variable: Variable, name: &Token, value: Object)
+    {
+        self.trace_define(name, &value);
+        match variable
         {
-            if self.execute(statement).is_err()
+            Variable::Local(slot) => self.frame.borrow_mut().define(slot, value),
+            _ =>
             {
-                return false;
+                self.environment
+                    .borrow()
+                    .borrow_mut()
+                    .define(name.get_identifier(), value)
             }
         }
-        true
     }
 
+    /// Execute `statements`, stopping early if one of them jumps out of the
+    /// block.
+    pub fn execute_block(&self, statements: &[Stmt]) -> Result<Flow, LoxResult>
+    {
+        let mut result = Ok(Flow::Normal);
+        for statement in statements
+        {
+            result = self.execute(statement);
+            if !matches!(result, Ok(Flow::Normal))
+            {
+                break;
+            }
+        }
 
-    fn execute(&self, stmt: &Stmt) -> Result<(), LoxResult> { stmt.accept(self) }
+        // The block's slots are reused by later blocks, whose variables
+        // mustn't fill in the ones closures here are still waiting for
+        for statement in statements
+        {
+            let variable = match statement
+            {
+                Stmt::Var(stmt) => stmt.variable.get(),
+                Stmt::Function(stmt) => stmt.variable.get(),
+                _ => continue,
+            };
+            if let Variable::Local(slot) = variable
+            {
+                self.frame.borrow_mut().release(slot);
+            }
+        }
+        result
+    }
 
-    pub fn execute_block(
+    /// Execute the body of a function in its own frame, with globals looked
+    /// up in `globals`, returning the value it returns.
+    pub fn execute_function(
         &self,
-        statements: &[Stmt],
-        environment: Environment,
-    ) -> Result<(), LoxResult>
-    {
-        let previous = self.environment.replace(Rc::new(RefCell::new(environment)));
-        let result = statements
-            .iter()
-            .try_for_each(|statement| self.execute(statement));
-        self.environment.replace(previous);
-        result
+        body: &[Stmt],
+        frame: Frame,
+        globals: &Rc<RefCell<Environment>>,
+    ) -> Result<Object, LoxResult>
+    {
+        if *self.function_nest.borrow() == MAX_CALL_DEPTH
+        {
+            return Err(LoxResult::new_native_error("Stack overflow.".to_string()));
+        }
+
+        *self.function_nest.borrow_mut() += 1;
+        let frame = self.frame.replace(frame);
+        let environment = self.environment.replace(Rc::clone(globals));
+        let result = self.execute_block(body);
+        self.environment.replace(environment);
+        self.frame.replace(frame);
+        *self.function_nest.borrow_mut() -= 1;
+
+        match result?
+        {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Object::Nil),
+        }
+    }
+
+    fn execute_loop(&self, stmt: &WhileStmt) -> Result<Flow, LoxResult>
+    {
+        while self.is_truthy(&self.evaluate(&stmt.condition)?)
+        {
+            match self.execute(&stmt.body)?
+            {
+                Flow::Break => break,
+                Flow::Return(value) => return Ok(Flow::Return(value)),
+                Flow::Normal | Flow::Continue => (),
+            }
+
+            if let Some(increment) = &stmt.increment
+            {
+                self.evaluate(increment)?;
+            }
+            self.debug_loop();
+        }
+
+        Ok(Flow::Normal)
     }
 }
 
 
 #[cfg(test)]
 // TODO: Test every possible case
-mod tests
+pub(crate) mod tests
 {
     use super::*;
-    use crate::{stmt::VarStmt, tokens::*};
+    use crate::{lexer::Scanner, parser::Parser, stmt::VarStmt, tokens::*};
+    use std::io::{self, Write};
+
     fn make_literal(o: Object) -> Box<Expr>
     {
         Box::new(Expr::Literal(LiteralExpr { value: Some(o) }))
     }
 
+    /// Parse `source`, which has to be free of syntax errors
+    pub(crate) fn parse(source: &str) -> Vec<Stmt>
+    {
+        let mut scanner = Scanner::new(source.to_string());
+        let tokens = scanner.scan_tokens().unwrap();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    /// Run `source` through `i`
+    pub(crate) fn run_in(i: &Interpreter, source: &str) -> Result<(), LoxResult>
+    {
+        i.interpret(&parse(source))
+    }
+
+    /// Run `source` through a fresh interpreter
+    pub(crate) fn run(source: &str) -> Interpreter
+    {
+        let i = Interpreter::new();
+        let _ = run_in(&i, source);
+        i
+    }
+
+    /// Read a global variable's value
+    pub(crate) fn global(i: &Interpreter, name: &str) -> Object
+    {
+        i.globals.borrow().lookup(name).unwrap()
+    }
+
+    /// An output that can be read after the interpreter is done with it
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedOutput
+    {
+        /// Everything written so far
+        pub(crate) fn contents(&self) -> String
+        {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedOutput
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
     #[test]
     /// Tests unary minus (-15) or (-value)
     fn test_unary_minus()
@@ -361,6 +835,27 @@ mod tests
         assert_eq!(res, Object::Num(-123.5));
     }
 
+    #[test]
+    /// Tests unary minus on a non-number (-"abc")
+    fn test_unary_minus_type_error()
+    {
+        let i = Interpreter::new();
+        let unary_expr = UnaryExpr {
+            operator: Token::new(TokenType::Minus, "-".to_string(), None, 1),
+            right: make_literal(Object::Str("abc".into())),
+        };
+
+        match i.visit_unary_expr(&unary_expr)
+        {
+            Err(LoxResult::RuntimeError { token, message }) =>
+            {
+                assert!(token.is(TokenType::Minus));
+                assert_eq!(message, "Operand to '-' must be a number, got string");
+            }
+            res => panic!("Expected a runtime error, got {res:?}"),
+        }
+    }
+
     #[test]
     /// Tests unary not (!true)
     fn test_unary_bang()
@@ -452,14 +947,36 @@ mod tests
         let i = Interpreter::new();
 
         let binary_expr = BinaryExpr {
-            left: make_literal(Object::Str("Hello, ".to_string())),
+            left: make_literal(Object::Str("Hello, ".into())),
             operator: Token::new(TokenType::Plus, "+".to_string(), None, 0),
-            right: make_literal(Object::Str("World!".to_string())),
+            right: make_literal(Object::Str("World!".into())),
         };
 
         let res = i.visit_binary_expr(&binary_expr).unwrap();
 
-        assert_eq!(res, Object::Str("Hello, World!".to_string()));
+        assert_eq!(res, Object::Str("Hello, World!".into()));
+    }
+
+    #[test]
+    /// Test that strict mode refuses to concatenate numbers and strings (1 +
+    /// "a")
+    fn test_binary_plus_strict()
+    {
+        let i = Interpreter::new();
+
+        let binary_expr = BinaryExpr {
+            left: make_literal(Object::Num(1.0)),
+            operator: Token::new(TokenType::Plus, "+".to_string(), None, 0),
+            right: make_literal(Object::Str("a".into())),
+        };
+
+        assert_eq!(
+            i.visit_binary_expr(&binary_expr).unwrap(),
+            Object::Str("1a".into())
+        );
+
+        i.set_strict(true);
+        assert!(i.visit_binary_expr(&binary_expr).is_err());
     }
 
     #[test]
@@ -480,6 +997,31 @@ mod tests
         assert!(res.is_err());
     }
 
+    #[test]
+    /// Test that the type error names the operator and both operand types
+    fn test_arithmetic_error_message()
+    {
+        let i = Interpreter::new();
+
+        let binary_expr = BinaryExpr {
+            left: make_literal(Object::Str("a".into())),
+            operator: Token::new(TokenType::Minus, "-".to_string(), None, 0),
+            right: make_literal(Object::Bool(true)),
+        };
+
+        match i.visit_binary_expr(&binary_expr)
+        {
+            Err(LoxResult::RuntimeError { message, .. }) =>
+            {
+                assert_eq!(
+                    message,
+                    "Operands to '-' must be numbers, got string and bool"
+                )
+            }
+            res => panic!("Expected a runtime error, got {res:?}"),
+        }
+    }
+
     #[test]
     /// Test that an comparison error is thrown when trying to compare differing
     /// types
@@ -688,9 +1230,9 @@ mod tests
         let i = Interpreter::new();
 
         let binary_expr = BinaryExpr {
-            left: make_literal(Object::Str("Hello".to_string())),
+            left: make_literal(Object::Str("Hello".into())),
             operator: Token::new(TokenType::Equal, "==".to_string(), None, 0),
-            right: make_literal(Object::Str("Hello".to_string())),
+            right: make_literal(Object::Str("Hello".into())),
         };
 
         let res = i.visit_binary_expr(&binary_expr).unwrap();
@@ -705,9 +1247,9 @@ mod tests
         let i = Interpreter::new();
 
         let binary_expr = BinaryExpr {
-            left: make_literal(Object::Str("Hello".to_string())),
+            left: make_literal(Object::Str("Hello".into())),
             operator: Token::new(TokenType::BangEqual, "!=".to_string(), None, 0),
-            right: make_literal(Object::Str("Hello World".to_string())),
+            right: make_literal(Object::Str("Hello World".into())),
         };
 
         let res = i.visit_binary_expr(&binary_expr).unwrap();
@@ -758,6 +1300,7 @@ mod tests
         let var_stmt = VarStmt {
             name: name.clone(),
             initializer: Some(*make_literal(Object::Num(23.0))),
+            variable: Cell::default(),
         };
         i.visit_var_stmt(&var_stmt).unwrap();
 
@@ -775,6 +1318,7 @@ mod tests
         let var_stmt = VarStmt {
             name: name.clone(),
             initializer: None,
+            variable: Cell::default(),
         };
         i.visit_var_stmt(&var_stmt).unwrap();
 
@@ -792,10 +1336,14 @@ mod tests
         let var_stmt = VarStmt {
             name: name.clone(),
             initializer: Some(*make_literal(Object::Num(23.0))),
+            variable: Cell::default(),
         };
         i.visit_var_stmt(&var_stmt).unwrap();
 
-        let var_expr = VariableExpr { name };
+        let var_expr = VariableExpr {
+            name,
+            variable: Cell::default(),
+        };
 
         assert_eq!(i.visit_variable_expr(&var_expr).unwrap(), Object::Num(23.0))
     }
@@ -805,8 +1353,123 @@ mod tests
     {
         let i = Interpreter::new();
         let name = Token::new(TokenType::Identifier, "foo".to_string(), None, 0);
-        let var_expr = VariableExpr { name };
+        let var_expr = VariableExpr {
+            name,
+            variable: Cell::default(),
+        };
 
         assert!(i.visit_variable_expr(&var_expr).is_err())
     }
+
+    #[test]
+    fn test_catch_thrown_value()
+    {
+        let i = run("var caught; try { throw \"boom\"; } catch (e) { caught = e; }");
+        assert_eq!(global(&i, "caught"), Object::Str("boom".into()));
+    }
+
+    #[test]
+    fn test_catch_runtime_error()
+    {
+        let i = run(
+            "var message; var line;\ntry { 1 - \"x\"; } catch (e) { message = e.message; line = \
+             e.line; }",
+        );
+        assert!(matches!(global(&i, "message"), Object::Str(_)));
+        assert_eq!(global(&i, "line"), Object::Num(2.0));
+    }
+
+    #[test]
+    fn test_finally_runs_after_return()
+    {
+        let i = run(
+            "var cleaned = false; fun f() { try { return 1; } finally { cleaned = true; } } var r \
+             = f();",
+        );
+        assert_eq!(global(&i, "cleaned"), Object::Bool(true));
+        assert_eq!(global(&i, "r"), Object::Num(1.0));
+    }
+
+    #[test]
+    fn test_uncaught_throw_propagates()
+    {
+        let i = run("var after = false; try { throw 1; } finally { } after = true;");
+        assert_eq!(global(&i, "after"), Object::Bool(false));
+    }
+
+    #[test]
+    fn test_interpret_returns_first_error()
+    {
+        let statements = parse("var a = 1;\nprint a - nil;\nprint b;");
+
+        match Interpreter::new().interpret(&statements)
+        {
+            Err(LoxResult::RuntimeError { token, .. }) => assert_eq!(token.line, 2),
+            res => panic!("Expected a runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_continue_runs_for_increment()
+    {
+        let i = run(
+            "var sum = 0; for (var n = 0; n < 5; n = n + 1) { if (n == 2) continue; sum = sum + \
+             n; }",
+        );
+        assert_eq!(global(&i, "sum"), Object::Num(8.0));
+    }
+
+    #[test]
+    fn test_break_exits_loop()
+    {
+        let i = run("var n = 0; while (true) { n = n + 1; if (n == 3) break; }");
+        assert_eq!(global(&i, "n"), Object::Num(3.0));
+    }
+
+    #[test]
+    fn test_break_in_function_inside_loop_is_an_error()
+    {
+        let statements = parse("while (true) { fun f() { break; } }");
+
+        let res = Interpreter::new().interpret(&statements);
+        assert!(matches!(res, Err(LoxResult::ParseError { .. })));
+    }
+
+    #[test]
+    fn test_top_level_return_is_an_error()
+    {
+        let statements = parse("return 1;");
+
+        let res = Interpreter::new().interpret(&statements);
+        assert!(matches!(res, Err(LoxResult::ParseError { .. })));
+    }
+
+    #[test]
+    fn test_top_level_return_is_not_caught()
+    {
+        let i = run("var caught = false; try { return; } catch (e) { caught = true; }");
+        // The script is rejected before it runs
+        assert!(i.globals.borrow().lookup("caught").is_none());
+    }
+
+    #[test]
+    fn test_function_identity()
+    {
+        let i = run(
+            "fun f() {} fun g() {} var f2 = f; var same = f == f2; var different = f == g; var \
+             mixed = f == 1;",
+        );
+        assert_eq!(global(&i, "same"), Object::Bool(true));
+        assert_eq!(global(&i, "different"), Object::Bool(false));
+        assert_eq!(global(&i, "mixed"), Object::Bool(false));
+    }
+
+    #[test]
+    fn test_equality_of_differing_types()
+    {
+        let i = run("var a = 1 == \"1\"; var b = true != nil; var c = clock == clock;");
+        assert_eq!(global(&i, "a"), Object::Bool(false));
+        assert_eq!(global(&i, "b"), Object::Bool(true));
+        assert_eq!(global(&i, "c"), Object::Bool(true));
+    }
 }
@@ -0,0 +1,345 @@
+use super::{environment::Environment, Interpreter};
+use crate::{error::LoxResult, lexer::Scanner, object::Object, parser::Parser, stmt::ImportStmt};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// A loaded module. Its exports are everything defined at the top level of
+/// its file.
+#[derive(Debug)]
+pub struct Module
+{
+    /// The module's canonical path
+    path: PathBuf,
+
+    environment: Rc<RefCell<Environment>>,
+}
+
+impl Module
+{
+    pub(crate) fn new(path: PathBuf, environment: Rc<RefCell<Environment>>) -> Self
+    {
+        Self { path, environment }
+    }
+
+    /// Look up an exported definition
+    pub fn get(&self, name: &str) -> Option<Object> { self.environment.borrow().get_own(name) }
+}
+
+/// Modules are only equal to themselves
+impl PartialEq for Module
+{
+    fn eq(&self, other: &Self) -> bool { std::ptr::eq(self, other) }
+}
+
+impl std::fmt::Display for Module
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "<module {}>", self.path.display())
+    }
+}
+
+/// A path relative to the working directory, if it's inside it
+pub(super) fn display_path(path: &Path) -> String
+{
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+impl Interpreter
+{
+    /// Set the path of the script being run, so its imports can be resolved
+    /// relative to it
+    pub fn set_script_path(&self, path: &Path)
+    {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        *self.module_stack.borrow_mut() = vec![path];
+    }
+
+    /// The file of the script or module being loaded
+    pub(super) fn current_file(&self) -> PathBuf
+    {
+        match self.module_stack.borrow().last()
+        {
+            Some(path) => path.clone(),
+            None => PathBuf::from("<script>"),
+        }
+    }
+
+    /// Add a directory to search for modules that aren't found relative to
+    /// the importing file
+    pub fn add_module_path(&self, path: PathBuf) { self.module_paths.borrow_mut().push(path); }
+
+    /// A new environment for a module's globals. It only encloses the
+    /// builtins, so a module can't see or assign the importer's globals.
+    pub(crate) fn module_environment(&self) -> Rc<RefCell<Environment>>
+    {
+        Rc::new(RefCell::new(Environment::new_with_enclosing(Rc::clone(
+            &self.builtins,
+        ))))
+    }
+
+    /// Load the module imported by `stmt`, running it if it hasn't been
+    /// loaded yet
+    pub(super) fn import(&self, stmt: &ImportStmt) -> Result<Rc<Module>, LoxResult>
+    {
+        let name = match &stmt.path.literal
+        {
+            Some(Object::Str(name)) => name.clone(),
+            _ => unreachable!("Import paths are string literals"),
+        };
+
+        self.load_module(
+            &name,
+            |message| LoxResult::new_runtime_error(stmt.path.clone(), message),
+            |source| self.run_module(source, stmt),
+        )
+    }
+
+    /// Load the module `name`, using `run` to run its source in a new
+    /// environment if it hasn't been loaded yet. `error` creates the error
+    /// for a module that can't be loaded.
+    pub(crate) fn load_module(
+        &self,
+        name: &str,
+        error: impl Fn(String) -> LoxResult,
+        run: impl FnOnce(String) -> Result<Rc<RefCell<Environment>>, LoxResult>,
+    ) -> Result<Rc<Module>, LoxResult>
+    {
+        // Finding and reading the module's file needs the filesystem, like the
+        // file natives
+        if !self.capabilities.borrow().filesystem
+        {
+            return Err(LoxResult::new_system_error(
+                "import is unavailable, filesystem access is disabled",
+            ));
+        }
+
+        let path = self
+            .resolve_module(name)
+            .ok_or_else(|| error(format!("Couldn't find module \"{name}\".")))?;
+
+        if let Some(module) = self.modules.borrow().get(&path)
+        {
+            return Ok(Rc::clone(module));
+        }
+
+        if let Some(start) = self.module_stack.borrow().iter().position(|p| *p == path)
+        {
+            let cycle: Vec<String> = self.module_stack.borrow()[start..]
+                .iter()
+                .chain([&path])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(error(format!(
+                "Import cycle detected: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| error(format!("Couldn't read module \"{name}\": {e}")))?;
+
+        self.module_stack.borrow_mut().push(path.clone());
+        let result = run(source);
+        self.module_stack.borrow_mut().pop();
+
+        let module = Rc::new(Module::new(path.clone(), result?));
+        self.modules.borrow_mut().insert(path, Rc::clone(&module));
+        Ok(module)
+    }
+
+    /// Find the file for the module `name`. Relative paths are looked up next
+    /// to the importing file first, then in each of the module paths.
+    fn resolve_module(&self, name: &str) -> Option<PathBuf>
+    {
+        let importer_dir = match self.module_stack.borrow().last()
+        {
+            Some(importer) => importer.parent().map(Path::to_path_buf),
+            None => std::env::current_dir().ok(),
+        };
+
+        importer_dir
+            .iter()
+            .chain(self.module_paths.borrow().iter())
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .and_then(|path| path.canonicalize().ok())
+    }
+
+    /// Run a module's source in a fresh environment, returning that
+    /// environment
+    fn run_module(
+        &self,
+        source: String,
+        stmt: &ImportStmt,
+    ) -> Result<Rc<RefCell<Environment>>, LoxResult>
+    {
+        // The errors themselves have already been reported by the scanner and parser
+        let invalid = || {
+            LoxResult::new_runtime_error(
+                stmt.path.clone(),
+                format!("Module {} has errors.", stmt.path.lexeme),
+            )
+        };
+
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().map_err(|_| invalid())?;
+        let mut parser = Parser::new(tokens);
+        let statements = parser.parse().map_err(|_| invalid())?;
+
+        if !parser.success()
+        {
+            return Err(invalid());
+        }
+        let statements = self.optimize(statements)?;
+        let slots = self.resolve(&statements)?;
+        self.register_coverage(&statements);
+
+        let environment = self.module_environment();
+        let previous = self.environment.replace(Rc::clone(&environment));
+        let frame = self.frame.borrow().nested(slots, Rc::default());
+        let frame = self.frame.replace(frame);
+
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement).map(|_| ()));
+        self.frame.replace(frame);
+        self.environment.replace(previous);
+
+        result.map(|_| environment)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::{
+        tests::{global, run_in},
+        Capabilities,
+    };
+    use std::fs;
+
+    /// Create an empty directory for a test's module files
+    fn temp_dir(name: &str) -> PathBuf
+    {
+        let dir =
+            std::env::temp_dir().join(format!("lox-module-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Run `main.lox` in `dir`
+    fn run_main(dir: &Path) -> (Interpreter, Result<(), LoxResult>)
+    {
+        run_main_with(dir, Capabilities::default())
+    }
+
+    /// Run `main.lox` in `dir` with only the given access to the host system
+    fn run_main_with(dir: &Path, capabilities: Capabilities)
+        -> (Interpreter, Result<(), LoxResult>)
+    {
+        let path = dir.join("main.lox");
+        let i = Interpreter::new();
+        i.set_capabilities(capabilities);
+        i.set_script_path(&path);
+        let res = run_in(&i, &fs::read_to_string(&path).unwrap());
+        (i, res)
+    }
+
+    #[test]
+    fn test_import_runs_module_once()
+    {
+        let dir = temp_dir("once");
+        fs::create_dir(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib/counter.lox"),
+            "var loads = 0; loads = loads + 1; fun double(x) { return x * 2; }",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.lox"),
+            "import \"lib/counter.lox\" as c; from \"lib/counter.lox\" import double, loads; var \
+             a = c.double(2); var b = double(3);",
+        )
+        .unwrap();
+
+        let (i, res) = run_main(&dir);
+        res.unwrap();
+        assert_eq!(global(&i, "a"), Object::Num(4.0));
+        assert_eq!(global(&i, "b"), Object::Num(6.0));
+        assert_eq!(global(&i, "loads"), Object::Num(1.0));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_cycle()
+    {
+        let dir = temp_dir("cycle");
+        fs::write(dir.join("main.lox"), "import \"a.lox\" as a;").unwrap();
+        fs::write(dir.join("a.lox"), "import \"b.lox\" as b;").unwrap();
+        fs::write(dir.join("b.lox"), "import \"a.lox\" as a;").unwrap();
+
+        match run_main(&dir).1
+        {
+            Err(LoxResult::RuntimeError { message, .. }) =>
+            {
+                assert!(message.starts_with("Import cycle detected"))
+            }
+            res => panic!("Expected an import cycle error, got {res:?}"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_export()
+    {
+        let dir = temp_dir("missing");
+        fs::write(dir.join("main.lox"), "from \"m.lox\" import nope;").unwrap();
+        fs::write(dir.join("m.lox"), "var yes = 1;").unwrap();
+
+        assert!(matches!(
+            run_main(&dir).1,
+            Err(LoxResult::RuntimeError { .. })
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_needs_filesystem()
+    {
+        let dir = temp_dir("sandbox");
+        fs::write(dir.join("main.lox"), "import \"m.lox\" as m;").unwrap();
+        fs::write(dir.join("m.lox"), "print \"module ran\";").unwrap();
+
+        let capabilities = Capabilities {
+            filesystem: false,
+            ..Capabilities::default()
+        };
+        match run_main_with(&dir, capabilities).1
+        {
+            Err(LoxResult::SystemError { message }) =>
+            {
+                assert_eq!(
+                    message,
+                    "import is unavailable, filesystem access is disabled"
+                )
+            }
+            res => panic!("Expected a system error, got {res:?}"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,24 @@
-use super::Interpreter;
+use super::{environment::Environment, Interpreter};
 use crate::{
     error::LoxResult,
-    object::{callable::LoxCallable, Object},
+    object::{
+        callable::{Callable, LoxCallable},
+        Object,
+    },
 };
-use std::time::SystemTime;
+use std::{cell::RefCell, rc::Rc, time::SystemTime};
+
+mod conversions;
+mod io;
+mod math;
+mod process;
+mod strings;
+
+pub use conversions::CONVERSION_FUNCTIONS;
+pub use io::IO_FUNCTIONS;
+pub use math::{Random, MATH_CONSTANTS, MATH_FUNCTIONS};
+pub use process::PROCESS_FUNCTIONS;
+pub use strings::STRING_FUNCTIONS;
 
 pub struct NativeClock;
 impl LoxCallable for NativeClock
@@ -26,3 +41,126 @@ impl LoxCallable for NativeClock
 
     fn to_string(&self) -> String { "clock".to_string() }
 }
+
+/// The signature of a Rust function backing a `NativeFunction`. The arguments
+/// have already been checked against the function's arity.
+pub type NativeFn = fn(&Interpreter, &[Object]) -> Result<Object, LoxResult>;
+
+/// A native function implemented by a plain Rust function
+#[derive(Clone, Copy)]
+pub struct NativeFunction
+{
+    name: &'static str,
+    arity: usize,
+    function: NativeFn,
+}
+
+impl NativeFunction
+{
+    pub const fn new(name: &'static str, arity: usize, function: NativeFn) -> Self
+    {
+        Self {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+impl LoxCallable for NativeFunction
+{
+    fn call(&self, interpreter: &Interpreter, arguments: Vec<Object>) -> Result<Object, LoxResult>
+    {
+        (self.function)(interpreter, &arguments)
+    }
+
+    fn arity(&self) -> usize { self.arity }
+
+    fn to_string(&self) -> String { self.name.to_string() }
+}
+
+/// Define each of `functions` as a global in `environment`
+pub fn define_natives(environment: &mut Environment, functions: &[NativeFunction])
+{
+    for function in functions
+    {
+        environment.define(
+            function.name.to_string(),
+            Object::Func(Callable {
+                func: Rc::new(*function),
+            }),
+        );
+    }
+}
+
+/// The error for an argument of the wrong type. `index` is zero based.
+fn argument_error(name: &str, index: usize, expected: &str, got: &Object) -> LoxResult
+{
+    LoxResult::new_native_error(format!(
+        "{name}() expects {expected} as argument {}, got {}",
+        index + 1,
+        got.type_name()
+    ))
+}
+
+/// Check that a result of `bytes` can be built without exceeding the memory
+/// limit. Natives check the size of what they build up front, as it's only
+/// counted once it's stored in a variable.
+fn reserve(interpreter: &Interpreter, bytes: usize) -> Result<(), LoxResult>
+{
+    interpreter
+        .reserve(bytes)
+        .map_err(LoxResult::new_native_error)
+}
+
+fn string_arg<'a>(name: &str, args: &'a [Object], index: usize) -> Result<&'a str, LoxResult>
+{
+    match &args[index]
+    {
+        Object::Str(s) => Ok(s),
+        other => Err(argument_error(name, index, "a string", other)),
+    }
+}
+
+fn number_arg(name: &str, args: &[Object], index: usize) -> Result<f64, LoxResult>
+{
+    match &args[index]
+    {
+        Object::Num(n) => Ok(*n),
+        other => Err(argument_error(name, index, "a number", other)),
+    }
+}
+
+/// A number argument that must be a whole number, such as a count or an index
+fn integer_arg(name: &str, args: &[Object], index: usize) -> Result<i64, LoxResult>
+{
+    match &args[index]
+    {
+        Object::Num(n) if n.fract() == 0.0 && n.is_finite() => Ok(*n as i64),
+        Object::Num(n) =>
+        {
+            Err(LoxResult::new_native_error(format!(
+                "{name}() expects an integer as argument {}, got {n}",
+                index + 1
+            )))
+        }
+        other => Err(argument_error(name, index, "an integer", other)),
+    }
+}
+
+fn list_arg<'a>(
+    name: &str,
+    args: &'a [Object],
+    index: usize,
+) -> Result<&'a Rc<RefCell<Vec<Object>>>, LoxResult>
+{
+    match &args[index]
+    {
+        Object::List(list) => Ok(list),
+        other => Err(argument_error(name, index, "a list", other)),
+    }
+}
+
+/// A string value, for the natives' tests
+#[cfg(test)]
+fn string(s: &str) -> Object { Object::Str(s.into()) }
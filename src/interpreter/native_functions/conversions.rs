@@ -0,0 +1,90 @@
+//! Type conversion and introspection natives
+use super::*;
+
+pub const CONVERSION_FUNCTIONS: &[NativeFunction] = &[
+    NativeFunction::new("type", 1, type_of),
+    NativeFunction::new("str", 1, str),
+    NativeFunction::new("num", 1, num),
+    NativeFunction::new("bool", 1, bool),
+];
+
+/// `type(value)`: the name of `value`'s type, such as "number" or "string"
+fn type_of(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    Ok(Object::Str(args[0].type_name().into()))
+}
+
+/// `str(value)`: `value` as it would be printed
+fn str(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    Ok(Object::Str(args[0].to_string().into()))
+}
+
+/// `num(value)`: parse a string as a number. Numbers are returned unchanged.
+/// Strings such as "nan" and "inf", which aren't numbers Lox can write, are
+/// rejected, as are numbers too large to represent.
+fn num(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    match &args[0]
+    {
+        Object::Num(n) => Ok(Object::Num(*n)),
+        Object::Str(s) =>
+        {
+            s.trim()
+                .parse()
+                .ok()
+                .filter(|n: &f64| n.is_finite())
+                .map(Object::Num)
+                .ok_or_else(|| {
+                    LoxResult::new_native_error(format!("num() can't convert \"{s}\" to a number"))
+                })
+        }
+        other => Err(argument_error("num", 0, "a string or number", other)),
+    }
+}
+
+/// `bool(value)`: whether `value` is truthy
+fn bool(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    Ok(Object::Bool(interpreter.is_truthy(&args[0])))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_type_names()
+    {
+        let i = Interpreter::new();
+        assert_eq!(type_of(&i, &[Object::Num(1.0)]).unwrap(), string("number"));
+        assert_eq!(type_of(&i, &[string("")]).unwrap(), string("string"));
+        assert_eq!(type_of(&i, &[Object::Nil]).unwrap(), string("nil"));
+        assert_eq!(
+            type_of(&i, &[Object::list(Vec::new())]).unwrap(),
+            string("list")
+        );
+    }
+
+    #[test]
+    fn test_num()
+    {
+        let i = Interpreter::new();
+        assert_eq!(num(&i, &[string(" 12.5 ")]).unwrap(), Object::Num(12.5));
+        assert!(num(&i, &[string("twelve")]).is_err());
+        assert!(num(&i, &[string("nan")]).is_err());
+        assert!(num(&i, &[string("-inf")]).is_err());
+        assert!(num(&i, &[string("1e999")]).is_err());
+        assert!(num(&i, &[Object::Nil]).is_err());
+    }
+
+    #[test]
+    fn test_str_and_bool()
+    {
+        let i = Interpreter::new();
+        assert_eq!(str(&i, &[Object::Num(3.0)]).unwrap(), string("3"));
+        assert_eq!(bool(&i, &[Object::Num(0.0)]).unwrap(), Object::Bool(true));
+        assert_eq!(bool(&i, &[Object::Nil]).unwrap(), Object::Bool(false));
+    }
+}
@@ -0,0 +1,221 @@
+//! Console and file natives. These are only usable when the interpreter's
+//! `Capabilities` allow it.
+use super::*;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+pub const IO_FUNCTIONS: &[NativeFunction] = &[
+    NativeFunction::new("input", 1, input),
+    NativeFunction::new("eprint", 1, eprint),
+    NativeFunction::new("read_file", 1, read_file),
+    NativeFunction::new("write_file", 2, write_file),
+    NativeFunction::new("append_file", 2, append_file),
+    NativeFunction::new("file_exists", 1, file_exists),
+    NativeFunction::new("read_lines", 1, read_lines),
+];
+
+fn require_console(interpreter: &Interpreter, name: &str) -> Result<(), LoxResult>
+{
+    if interpreter.capabilities.borrow().console
+    {
+        Ok(())
+    }
+    else
+    {
+        Err(LoxResult::new_system_error(&format!(
+            "{name}() is unavailable, console access is disabled"
+        )))
+    }
+}
+
+fn require_filesystem(interpreter: &Interpreter, name: &str) -> Result<(), LoxResult>
+{
+    if interpreter.capabilities.borrow().filesystem
+    {
+        Ok(())
+    }
+    else
+    {
+        Err(LoxResult::new_system_error(&format!(
+            "{name}() is unavailable, filesystem access is disabled"
+        )))
+    }
+}
+
+fn io_error(name: &str, path: &str, e: io::Error) -> LoxResult
+{
+    LoxResult::new_system_error(&format!("{name}() failed for \"{path}\": {e}"))
+}
+
+/// `input(prompt)`: print `prompt`, then read a line from standard input.
+/// Returns `nil` at the end of input.
+fn input(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_console(interpreter, "input")?;
+    let prompt = string_arg("input", args, 0)?;
+
+    print!("{prompt}");
+    let mut line = String::new();
+    let read = io::stdout()
+        .flush()
+        .and_then(|_| io::stdin().lock().read_line(&mut line))
+        .map_err(|e| LoxResult::new_system_error(&format!("input() failed: {e}")))?;
+
+    if read == 0
+    {
+        return Ok(Object::Nil);
+    }
+
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    Ok(Object::Str(line.into()))
+}
+
+/// `eprint(value)`: print `value` to standard error
+fn eprint(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_console(interpreter, "eprint")?;
+    eprintln!("{}", args[0]);
+    Ok(Object::Nil)
+}
+
+/// `read_file(path)`: the contents of the file at `path`
+fn read_file(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_filesystem(interpreter, "read_file")?;
+    let path = string_arg("read_file", args, 0)?;
+
+    let size = fs::metadata(path)
+        .map_err(|e| io_error("read_file", path, e))?
+        .len();
+    reserve(interpreter, size as usize)?;
+
+    fs::read_to_string(path)
+        .map(|contents| Object::Str(contents.into()))
+        .map_err(|e| io_error("read_file", path, e))
+}
+
+/// `write_file(path, contents)`: replace the contents of the file at `path`,
+/// creating it if needed
+fn write_file(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_filesystem(interpreter, "write_file")?;
+    let path = string_arg("write_file", args, 0)?;
+    let contents = string_arg("write_file", args, 1)?;
+
+    fs::write(path, contents).map_err(|e| io_error("write_file", path, e))?;
+    Ok(Object::Nil)
+}
+
+/// `append_file(path, contents)`: add `contents` to the end of the file at
+/// `path`, creating it if needed
+fn append_file(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_filesystem(interpreter, "append_file")?;
+    let path = string_arg("append_file", args, 0)?;
+    let contents = string_arg("append_file", args, 1)?;
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| io_error("append_file", path, e))?;
+    Ok(Object::Nil)
+}
+
+/// `file_exists(path)`
+fn file_exists(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_filesystem(interpreter, "file_exists")?;
+    let path = string_arg("file_exists", args, 0)?;
+    Ok(Object::Bool(Path::new(path).is_file()))
+}
+
+/// `read_lines(path)`: a list of the lines in the file at `path`, without
+/// their line endings
+fn read_lines(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_filesystem(interpreter, "read_lines")?;
+    let path = string_arg("read_lines", args, 0)?;
+
+    let size = fs::metadata(path)
+        .map_err(|e| io_error("read_lines", path, e))?
+        .len();
+    reserve(interpreter, size as usize)?;
+
+    let contents = fs::read_to_string(path).map_err(|e| io_error("read_lines", path, e))?;
+    Ok(Object::list(
+        contents
+            .lines()
+            .map(|line| Object::Str(line.into()))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::Capabilities;
+
+    /// A path in the temporary directory that's unique to this test
+    fn temp_path(name: &str) -> String
+    {
+        std::env::temp_dir()
+            .join(format!("lox-io-test-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn test_write_append_and_read()
+    {
+        let i = Interpreter::new();
+        let path = temp_path("write");
+
+        write_file(&i, &[string(&path), string("one\n")]).unwrap();
+        append_file(&i, &[string(&path), string("two\n")]).unwrap();
+
+        assert_eq!(
+            file_exists(&i, &[string(&path)]).unwrap(),
+            Object::Bool(true)
+        );
+        assert_eq!(
+            read_file(&i, &[string(&path)]).unwrap(),
+            string("one\ntwo\n")
+        );
+        assert_eq!(
+            read_lines(&i, &[string(&path)]).unwrap().to_string(),
+            "[one, two]"
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file_is_a_system_error()
+    {
+        let i = Interpreter::new();
+        let res = read_file(&i, &[string(&temp_path("missing"))]);
+        assert!(matches!(res, Err(LoxResult::SystemError { .. })));
+    }
+
+    #[test]
+    fn test_filesystem_capability()
+    {
+        let i = Interpreter::new();
+        i.set_capabilities(Capabilities {
+            filesystem: false,
+            ..Capabilities::default()
+        });
+
+        let path = temp_path("disabled");
+        let res = write_file(&i, &[string(&path), string("")]);
+        assert!(matches!(res, Err(LoxResult::SystemError { .. })));
+        assert!(!Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,176 @@
+//! Math natives and constants
+use super::*;
+
+pub const MATH_CONSTANTS: &[(&str, f64)] =
+    &[("PI", std::f64::consts::PI), ("E", std::f64::consts::E)];
+
+pub const MATH_FUNCTIONS: &[NativeFunction] = &[
+    NativeFunction::new("sqrt", 1, sqrt),
+    NativeFunction::new("pow", 2, pow),
+    NativeFunction::new("abs", 1, abs),
+    NativeFunction::new("floor", 1, floor),
+    NativeFunction::new("ceil", 1, ceil),
+    NativeFunction::new("round", 1, round),
+    NativeFunction::new("min", 2, min),
+    NativeFunction::new("max", 2, max),
+    NativeFunction::new("sin", 1, sin),
+    NativeFunction::new("cos", 1, cos),
+    NativeFunction::new("tan", 1, tan),
+    NativeFunction::new("atan2", 2, atan2),
+    NativeFunction::new("log", 1, log),
+    NativeFunction::new("exp", 1, exp),
+    NativeFunction::new("random", 0, random),
+    NativeFunction::new("random_int", 2, random_int),
+    NativeFunction::new("seed", 1, seed),
+];
+
+/// Define a native that applies an `f64` method to its only argument
+macro_rules! unary_math {
+    ($name:ident, $method:ident) => {
+        fn $name(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+        {
+            Ok(Object::Num(
+                number_arg(stringify!($name), args, 0)?.$method(),
+            ))
+        }
+    };
+}
+
+/// Define a native that applies an `f64` method to its two arguments
+macro_rules! binary_math {
+    ($name:ident, $method:ident) => {
+        fn $name(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+        {
+            let left = number_arg(stringify!($name), args, 0)?;
+            let right = number_arg(stringify!($name), args, 1)?;
+            Ok(Object::Num(left.$method(right)))
+        }
+    };
+}
+
+unary_math!(sqrt, sqrt);
+unary_math!(abs, abs);
+unary_math!(floor, floor);
+unary_math!(ceil, ceil);
+unary_math!(round, round);
+unary_math!(sin, sin);
+unary_math!(cos, cos);
+unary_math!(tan, tan);
+unary_math!(log, ln);
+unary_math!(exp, exp);
+binary_math!(pow, powf);
+binary_math!(min, min);
+binary_math!(max, max);
+binary_math!(atan2, atan2);
+
+/// `random()`: a number in the range [0, 1)
+fn random(interpreter: &Interpreter, _: &[Object]) -> Result<Object, LoxResult>
+{
+    Ok(Object::Num(interpreter.random.borrow_mut().next_f64()))
+}
+
+/// `random_int(low, high)`: an integer in the range [low, high]
+fn random_int(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let low = integer_arg("random_int", args, 0)?;
+    let high = integer_arg("random_int", args, 1)?;
+
+    if low > high
+    {
+        return Err(LoxResult::new_native_error(format!(
+            "random_int() range is empty, {low} is greater than {high}"
+        )));
+    }
+
+    let range = high.abs_diff(low).wrapping_add(1);
+    let offset = match range
+    {
+        // The range covers every i64
+        0 => interpreter.random.borrow_mut().next_u64(),
+        range => interpreter.random.borrow_mut().next_u64() % range,
+    };
+    Ok(Object::Num(low.wrapping_add(offset as i64) as f64))
+}
+
+/// `seed(n)`: restart the random number generator from seed `n`. The same
+/// seed always produces the same sequence of random numbers.
+fn seed(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let seed = integer_arg("seed", args, 0)?;
+    *interpreter.random.borrow_mut() = Random::new(seed as u64);
+    Ok(Object::Nil)
+}
+
+/// A small, deterministic pseudo-random number generator (SplitMix64). It's
+/// not suitable for cryptography. Interpreters start from seed 0, so
+/// unseeded scripts are reproducible too.
+#[derive(Debug, Clone, Default)]
+pub struct Random
+{
+    state: u64,
+}
+
+impl Random
+{
+    pub fn new(seed: u64) -> Self { Self { state: seed } }
+
+    pub fn next_u64(&mut self) -> u64
+    {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in the range [0, 1)
+    pub fn next_f64(&mut self) -> f64 { (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_min_max()
+    {
+        let i = Interpreter::new();
+        let args = [Object::Num(3.0), Object::Num(-2.0)];
+        assert_eq!(min(&i, &args).unwrap(), Object::Num(-2.0));
+        assert_eq!(max(&i, &args).unwrap(), Object::Num(3.0));
+        assert!(sqrt(&i, &[Object::Bool(true)]).is_err());
+    }
+
+    #[test]
+    fn test_seeded_random_is_reproducible()
+    {
+        let i = Interpreter::new();
+        seed(&i, &[Object::Num(42.0)]).unwrap();
+        let first: Vec<Object> = (0..5).map(|_| random(&i, &[]).unwrap()).collect();
+        seed(&i, &[Object::Num(42.0)]).unwrap();
+        let second: Vec<Object> = (0..5).map(|_| random(&i, &[]).unwrap()).collect();
+
+        assert_eq!(first, second);
+        assert!(first
+            .iter()
+            .all(|n| matches!(n, Object::Num(n) if (0.0..1.0).contains(n))));
+    }
+
+    #[test]
+    fn test_random_int_range()
+    {
+        let i = Interpreter::new();
+        let args = [Object::Num(-3.0), Object::Num(3.0)];
+        for _ in 0..100
+        {
+            match random_int(&i, &args).unwrap()
+            {
+                Object::Num(n) => assert!((-3.0..=3.0).contains(&n) && n.fract() == 0.0),
+                other => panic!("Expected a number, got {other:?}"),
+            }
+        }
+
+        assert!(random_int(&i, &[Object::Num(2.0), Object::Num(1.0)]).is_err());
+    }
+}
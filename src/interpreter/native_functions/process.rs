@@ -0,0 +1,196 @@
+//! Natives for scripts that interact with their process: command line
+//! arguments, environment variables, exit codes and running other programs.
+use super::*;
+use crate::object::Record;
+use std::process::Command;
+
+pub const PROCESS_FUNCTIONS: &[NativeFunction] = &[
+    NativeFunction::new("args", 0, args),
+    NativeFunction::new("env", 1, env),
+    NativeFunction::new("exit", 1, exit),
+    NativeFunction::new("exec", 2, exec),
+];
+
+fn require_process(interpreter: &Interpreter, name: &str) -> Result<(), LoxResult>
+{
+    if interpreter.capabilities.borrow().process
+    {
+        Ok(())
+    }
+    else
+    {
+        Err(LoxResult::new_system_error(&format!(
+            "{name}() is unavailable, process access is disabled"
+        )))
+    }
+}
+
+/// `args()`: a list of the command line arguments following the script's path
+fn args(interpreter: &Interpreter, _: &[Object]) -> Result<Object, LoxResult>
+{
+    Ok(Object::list(
+        interpreter
+            .script_args
+            .borrow()
+            .iter()
+            .map(|arg| Object::Str(arg.as_str().into()))
+            .collect(),
+    ))
+}
+
+/// `env(name)`: the value of the environment variable `name`, or `nil` if it
+/// isn't set
+fn env(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_process(interpreter, "env")?;
+    let name = string_arg("env", args, 0)?;
+
+    Ok(std::env::var(name)
+        .map(|value| Object::Str(value.into()))
+        .unwrap_or(Object::Nil))
+}
+
+/// `exit(code)`: stop the script, exiting with `code`
+fn exit(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let code = integer_arg("exit", args, 0)?;
+
+    match i32::try_from(code)
+    {
+        Ok(code) => Err(LoxResult::Exit { code }),
+        Err(_) =>
+        {
+            Err(LoxResult::new_native_error(format!(
+                "exit() code {code} is out of range"
+            )))
+        }
+    }
+}
+
+/// `exec(command, arguments)`: run `command` with the list of string
+/// `arguments`, and wait for it to finish. Returns a record of its `status`
+/// (`nil` if it was killed by a signal), `stdout` and `stderr`.
+fn exec(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    require_process(interpreter, "exec")?;
+    let command = string_arg("exec", args, 0)?;
+    let arguments = list_arg("exec", args, 1)?
+        .borrow()
+        .iter()
+        .map(|arg| {
+            match arg
+            {
+                Object::Str(arg) => Ok(arg.to_string()),
+                other =>
+                {
+                    Err(LoxResult::new_native_error(format!(
+                        "exec() arguments must be strings, got {}",
+                        other.type_name()
+                    )))
+                }
+            }
+        })
+        .collect::<Result<Vec<String>, LoxResult>>()?;
+
+    let output = Command::new(command)
+        .args(&arguments)
+        .output()
+        .map_err(|e| {
+            LoxResult::new_system_error(&format!("exec() couldn't run \"{command}\": {e}"))
+        })?;
+    reserve(interpreter, output.stdout.len() + output.stderr.len())?;
+
+    let status = match output.status.code()
+    {
+        Some(code) => Object::Num(code as f64),
+        None => Object::Nil,
+    };
+
+    Ok(Object::Record(Rc::new(Record::new(vec![
+        ("status", status),
+        (
+            "stdout",
+            Object::Str(String::from_utf8_lossy(&output.stdout).into()),
+        ),
+        (
+            "stderr",
+            Object::Str(String::from_utf8_lossy(&output.stderr).into()),
+        ),
+    ]))))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::Capabilities;
+
+    #[test]
+    fn test_args()
+    {
+        let i = Interpreter::new();
+        i.set_args(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(args(&i, &[]).unwrap().to_string(), "[a, b]");
+    }
+
+    #[test]
+    fn test_exit()
+    {
+        let i = Interpreter::new();
+        let res = exit(&i, &[Object::Num(3.0)]);
+        assert!(matches!(res, Err(LoxResult::Exit { code: 3 })));
+    }
+
+    #[test]
+    fn test_exec()
+    {
+        let i = Interpreter::new();
+        let res = exec(&i, &[string("echo"), Object::list(vec![string("hi")])]).unwrap();
+
+        match res
+        {
+            Object::Record(record) =>
+            {
+                assert_eq!(record.get("status"), Some(Object::Num(0.0)));
+                assert_eq!(record.get("stdout"), Some(string("hi\n")));
+            }
+            other => panic!("Expected a record, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_exec_output_counts_against_memory_limit()
+    {
+        let i = Interpreter::new();
+        i.set_limits(crate::interpreter::sandbox::Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 100),
+            ..Default::default()
+        });
+
+        let args = [
+            string("printf"),
+            Object::list(vec![string("%0200d"), string("0")]),
+        ];
+        assert!(matches!(
+            exec(&i, &args),
+            Err(LoxResult::NativeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_process_capability()
+    {
+        let i = Interpreter::new();
+        i.set_capabilities(Capabilities {
+            process: false,
+            ..Capabilities::default()
+        });
+
+        let res = exec(&i, &[string("echo"), Object::list(Vec::new())]);
+        assert!(matches!(res, Err(LoxResult::SystemError { .. })));
+        assert!(matches!(
+            env(&i, &[string("PATH")]),
+            Err(LoxResult::SystemError { .. })
+        ));
+    }
+}
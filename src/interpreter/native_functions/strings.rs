@@ -0,0 +1,328 @@
+//! String natives. Strings are indexed and measured in Unicode scalar values
+//! (`char`s), not bytes.
+use super::*;
+
+pub const STRING_FUNCTIONS: &[NativeFunction] = &[
+    NativeFunction::new("len", 1, len),
+    NativeFunction::new("substr", 3, substr),
+    NativeFunction::new("upper", 1, upper),
+    NativeFunction::new("lower", 1, lower),
+    NativeFunction::new("trim", 1, trim),
+    NativeFunction::new("split", 2, split),
+    NativeFunction::new("join", 2, join),
+    NativeFunction::new("replace", 3, replace),
+    NativeFunction::new("find", 2, find),
+    NativeFunction::new("starts_with", 2, starts_with),
+    NativeFunction::new("ends_with", 2, ends_with),
+    NativeFunction::new("chr", 1, chr),
+    NativeFunction::new("ord", 1, ord),
+    NativeFunction::new("repeat", 2, repeat),
+];
+
+/// `len(value)`: the number of characters in a string, or items in a list
+fn len(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    match &args[0]
+    {
+        Object::Str(s) => Ok(Object::Num(s.chars().count() as f64)),
+        Object::List(list) => Ok(Object::Num(list.borrow().len() as f64)),
+        other => Err(argument_error("len", 0, "a string or list", other)),
+    }
+}
+
+/// `substr(s, start, end)`: the characters of `s` from `start` up to, but not
+/// including, `end`. Indices past the end of the string are clamped to it.
+fn substr(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("substr", args, 0)?;
+    let start = integer_arg("substr", args, 1)?;
+    let end = integer_arg("substr", args, 2)?;
+
+    if start < 0 || end < 0
+    {
+        return Err(LoxResult::new_native_error(
+            "substr() indices can't be negative".to_string(),
+        ));
+    }
+
+    // The byte offset of the character at `index`, clamped to the end
+    let offset = |index: i64| {
+        s.char_indices()
+            .nth(index as usize)
+            .map_or(s.len(), |(offset, _)| offset)
+    };
+    let (start, end) = (offset(start), offset(end));
+    let part = s.get(start..end).unwrap_or_default();
+
+    reserve(interpreter, part.len())?;
+    Ok(Object::Str(part.into()))
+}
+
+/// `upper(s)`: `s` in upper case
+fn upper(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("upper", args, 0)?;
+    reserve(interpreter, s.len())?;
+    Ok(Object::Str(s.to_uppercase().into()))
+}
+
+/// `lower(s)`: `s` in lower case
+fn lower(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("lower", args, 0)?;
+    reserve(interpreter, s.len())?;
+    Ok(Object::Str(s.to_lowercase().into()))
+}
+
+/// `trim(s)`: `s` without leading and trailing whitespace
+fn trim(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    Ok(Object::Str(string_arg("trim", args, 0)?.trim().into()))
+}
+
+/// `split(s, separator)`: a list of the parts of `s` between each
+/// `separator`. An empty separator splits `s` into its characters.
+fn split(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("split", args, 0)?;
+    let separator = string_arg("split", args, 1)?;
+
+    let count = if separator.is_empty()
+    {
+        s.chars().count()
+    }
+    else
+    {
+        s.matches(separator).count() + 1
+    };
+    reserve(interpreter, count * std::mem::size_of::<Object>() + s.len())?;
+
+    let parts = if separator.is_empty()
+    {
+        s.chars()
+            .map(|c| Object::Str(c.to_string().into()))
+            .collect()
+    }
+    else
+    {
+        s.split(separator)
+            .map(|part| Object::Str(part.into()))
+            .collect()
+    };
+
+    Ok(Object::list(parts))
+}
+
+/// `join(list, separator)`: the items of `list` converted to strings, with
+/// `separator` between each of them
+fn join(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let list = list_arg("join", args, 0)?;
+    let separator = string_arg("join", args, 1)?;
+
+    let parts: Vec<String> = list.borrow().iter().map(|o| o.to_string()).collect();
+    let length = parts.iter().map(String::len).sum::<usize>()
+        + separator.len() * parts.len().saturating_sub(1);
+    reserve(interpreter, length)?;
+
+    Ok(Object::Str(parts.join(separator).into()))
+}
+
+/// `replace(s, from, to)`: `s` with every occurrence of `from` replaced by
+/// `to`
+fn replace(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("replace", args, 0)?;
+    let from = string_arg("replace", args, 1)?;
+    let to = string_arg("replace", args, 2)?;
+
+    if from.is_empty()
+    {
+        return Err(LoxResult::new_native_error(
+            "replace() can't replace an empty string".to_string(),
+        ));
+    }
+
+    let count = s.matches(from).count();
+    reserve(interpreter, s.len() - count * from.len() + count * to.len())?;
+
+    Ok(Object::Str(s.replace(from, to).into()))
+}
+
+/// `find(s, needle)`: the character index of the first occurrence of `needle`
+/// in `s`, or -1 if there is none
+fn find(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("find", args, 0)?;
+    let needle = string_arg("find", args, 1)?;
+
+    Ok(Object::Num(match s.find(needle)
+    {
+        Some(byte_index) => s[..byte_index].chars().count() as f64,
+        None => -1.0,
+    }))
+}
+
+/// `starts_with(s, prefix)`
+fn starts_with(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("starts_with", args, 0)?;
+    let prefix = string_arg("starts_with", args, 1)?;
+    Ok(Object::Bool(s.starts_with(prefix)))
+}
+
+/// `ends_with(s, suffix)`
+fn ends_with(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("ends_with", args, 0)?;
+    let suffix = string_arg("ends_with", args, 1)?;
+    Ok(Object::Bool(s.ends_with(suffix)))
+}
+
+/// `chr(code)`: the one character string for the Unicode code point `code`
+fn chr(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let code = integer_arg("chr", args, 0)?;
+
+    u32::try_from(code)
+        .ok()
+        .and_then(char::from_u32)
+        .map(|c| Object::Str(c.to_string().into()))
+        .ok_or_else(|| {
+            LoxResult::new_native_error(format!("chr() got an invalid code point {code}"))
+        })
+}
+
+/// `ord(c)`: the Unicode code point of the one character string `c`
+fn ord(_: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("ord", args, 0)?;
+    let mut chars = s.chars();
+
+    match (chars.next(), chars.next())
+    {
+        (Some(c), None) => Ok(Object::Num(c as u32 as f64)),
+        _ =>
+        {
+            Err(LoxResult::new_native_error(format!(
+                "ord() expects a single character, got a string of length {}",
+                s.chars().count()
+            )))
+        }
+    }
+}
+
+/// The longest string `repeat` builds, in bytes, whether or not there's a
+/// memory limit
+const MAX_REPEAT_LENGTH: usize = 1 << 30;
+
+/// `repeat(s, count)`: `s` repeated `count` times
+fn repeat(interpreter: &Interpreter, args: &[Object]) -> Result<Object, LoxResult>
+{
+    let s = string_arg("repeat", args, 0)?;
+    let count = integer_arg("repeat", args, 1)?;
+
+    if count < 0
+    {
+        return Err(LoxResult::new_native_error(
+            "repeat() count can't be negative".to_string(),
+        ));
+    }
+
+    let length = s
+        .len()
+        .checked_mul(count as usize)
+        .filter(|&length| length <= MAX_REPEAT_LENGTH)
+        .ok_or_else(|| {
+            LoxResult::new_native_error(format!(
+                "repeat() result would be longer than {MAX_REPEAT_LENGTH} bytes"
+            ))
+        })?;
+
+    reserve(interpreter, length)?;
+
+    Ok(Object::Str(s.repeat(count as usize).into()))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_len_counts_chars()
+    {
+        let i = Interpreter::new();
+        assert_eq!(len(&i, &[string("héllo")]).unwrap(), Object::Num(5.0));
+        assert!(len(&i, &[Object::Num(1.0)]).is_err());
+    }
+
+    #[test]
+    fn test_substr()
+    {
+        let i = Interpreter::new();
+        let args = [string("añbcd"), Object::Num(1.0), Object::Num(3.0)];
+        assert_eq!(substr(&i, &args).unwrap(), string("ñb"));
+
+        let args = [string("abc"), Object::Num(1.0), Object::Num(10.0)];
+        assert_eq!(substr(&i, &args).unwrap(), string("bc"));
+
+        let args = [string("abc"), Object::Num(1.5), Object::Num(2.0)];
+        assert!(substr(&i, &args).is_err());
+    }
+
+    #[test]
+    fn test_split_and_join()
+    {
+        let i = Interpreter::new();
+        let parts = split(&i, &[string("a,b,c"), string(",")]).unwrap();
+        assert_eq!(
+            len(&i, std::slice::from_ref(&parts)).unwrap(),
+            Object::Num(3.0)
+        );
+        assert_eq!(join(&i, &[parts, string("-")]).unwrap(), string("a-b-c"));
+    }
+
+    #[test]
+    fn test_find()
+    {
+        let i = Interpreter::new();
+        assert_eq!(
+            find(&i, &[string("ñaña"), string("ña")]).unwrap(),
+            Object::Num(0.0)
+        );
+        assert_eq!(
+            find(&i, &[string("aña"), string("a")]).unwrap(),
+            Object::Num(0.0)
+        );
+        assert_eq!(
+            find(&i, &[string("ñb"), string("b")]).unwrap(),
+            Object::Num(1.0)
+        );
+        assert_eq!(
+            find(&i, &[string("abc"), string("z")]).unwrap(),
+            Object::Num(-1.0)
+        );
+    }
+
+    #[test]
+    fn test_chr_and_ord()
+    {
+        let i = Interpreter::new();
+        assert_eq!(chr(&i, &[Object::Num(955.0)]).unwrap(), string("λ"));
+        assert_eq!(ord(&i, &[string("λ")]).unwrap(), Object::Num(955.0));
+        assert!(chr(&i, &[Object::Num(-1.0)]).is_err());
+        assert!(ord(&i, &[string("ab")]).is_err());
+    }
+
+    #[test]
+    fn test_repeat_length_is_capped()
+    {
+        let i = Interpreter::new();
+        assert_eq!(
+            repeat(&i, &[string("ab"), Object::Num(3.0)]).unwrap(),
+            string("ababab")
+        );
+        assert!(repeat(&i, &[string("ab"), Object::Num(1e18)]).is_err());
+    }
+}
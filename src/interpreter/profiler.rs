@@ -0,0 +1,252 @@
+//! Records how often each Lox function is called and how long its calls take.
+//! Functions are told apart by their name and the line they're declared on.
+//! The results can be printed as a table, or as folded stacks for flamegraph
+//! tools.
+use super::Interpreter;
+use crate::tokens::Token;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// A function, by name and declaration line
+type FunctionKey = (Rc<str>, usize);
+
+/// The totals of one function's calls
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FunctionStats
+{
+    pub calls: u64,
+
+    /// The time spent in the function and the functions it called. Time spent
+    /// in recursive calls is only counted once.
+    pub inclusive: Duration,
+
+    /// The time spent in the function itself
+    pub exclusive: Duration,
+}
+
+/// A distinct stack of calls, as a node of the call tree
+#[derive(Debug)]
+struct StackNode
+{
+    function: FunctionKey,
+    parent: Option<usize>,
+    exclusive: Duration,
+}
+
+/// A call in progress
+#[derive(Debug)]
+struct ActiveCall
+{
+    node: usize,
+    started: Instant,
+
+    /// The time spent in the calls this one made
+    children: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler
+{
+    functions: HashMap<FunctionKey, FunctionStats>,
+    nodes: Vec<StackNode>,
+    node_ids: HashMap<(Option<usize>, FunctionKey), usize>,
+    calls: Vec<ActiveCall>,
+}
+
+impl Profiler
+{
+    fn enter(&mut self, name: &Token)
+    {
+        let function = (Rc::clone(&name.lexeme), name.line);
+        let parent = self.calls.last().map(|call| call.node);
+        let next_id = self.nodes.len();
+        let node = *self
+            .node_ids
+            .entry((parent, function.clone()))
+            .or_insert(next_id);
+        if node == next_id
+        {
+            self.nodes.push(StackNode {
+                function,
+                parent,
+                exclusive: Duration::ZERO,
+            });
+        }
+
+        self.calls.push(ActiveCall {
+            node,
+            started: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    fn exit(&mut self)
+    {
+        let call = self.calls.pop().expect("Exited a call that wasn't entered");
+        let elapsed = call.started.elapsed();
+        let exclusive = elapsed.saturating_sub(call.children);
+        if let Some(caller) = self.calls.last_mut()
+        {
+            caller.children += elapsed;
+        }
+
+        self.nodes[call.node].exclusive += exclusive;
+        let function = &self.nodes[call.node].function;
+        let recursive = self
+            .calls
+            .iter()
+            .any(|outer| self.nodes[outer.node].function == *function);
+
+        let stats = self.functions.entry(function.clone()).or_default();
+        stats.calls += 1;
+        stats.exclusive += exclusive;
+        if !recursive
+        {
+            stats.inclusive += elapsed;
+        }
+    }
+
+    /// The totals of a function's calls, if it was called
+    pub fn stats(&self, name: &str, line: usize) -> Option<FunctionStats>
+    {
+        self.functions.get(&(name.into(), line)).copied()
+    }
+
+    /// A table of the functions called, the ones taking the most time first
+    pub fn table(&self) -> String
+    {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|(a_key, a), (b_key, b)| {
+            b.exclusive.cmp(&a.exclusive).then_with(|| a_key.cmp(b_key))
+        });
+
+        let mut table = format!(
+            "{:>10} {:>12} {:>12}  function\n",
+            "calls", "total ms", "self ms"
+        );
+        for ((name, line), stats) in functions
+        {
+            let _ = writeln!(
+                table,
+                "{:>10} {:>12.3} {:>12.3}  {name} (line {line})",
+                stats.calls,
+                stats.inclusive.as_secs_f64() * 1000.0,
+                stats.exclusive.as_secs_f64() * 1000.0
+            );
+        }
+        table
+    }
+
+    /// The time spent in each stack of calls, in microseconds, in the folded
+    /// format read by `flamegraph.pl` and `inferno`
+    pub fn folded_stacks(&self) -> String
+    {
+        let mut lines: Vec<_> = (0..self.nodes.len())
+            .filter(|&id| !self.nodes[id].exclusive.is_zero())
+            .map(|id| {
+                let mut frames = Vec::new();
+                let mut node = Some(id);
+                while let Some(id) = node
+                {
+                    let (name, line) = &self.nodes[id].function;
+                    frames.push(format!("{name}:{line}"));
+                    node = self.nodes[id].parent;
+                }
+                frames.reverse();
+                format!(
+                    "{} {}\n",
+                    frames.join(";"),
+                    self.nodes[id].exclusive.as_micros()
+                )
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+impl Interpreter
+{
+    /// Start recording the calls of Lox functions, discarding any earlier
+    /// profile
+    pub fn enable_profiler(&self) { *self.profiler.borrow_mut() = Some(Profiler::default()); }
+
+    /// Stop recording calls, returning what was recorded
+    pub fn take_profile(&self) -> Option<Profiler> { self.profiler.borrow_mut().take() }
+
+    /// Run a call of the function `name`, recording it if the profiler is
+    /// enabled
+    pub(crate) fn profile_call<T>(&self, name: &Token, call: impl FnOnce() -> T) -> T
+    {
+        if self.profiler.borrow().is_none()
+        {
+            return call();
+        }
+
+        if let Some(profiler) = self.profiler.borrow_mut().as_mut()
+        {
+            profiler.enter(name);
+        }
+        let result = call();
+        if let Some(profiler) = self.profiler.borrow_mut().as_mut()
+        {
+            profiler.exit();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::run_in;
+
+    fn profile(source: &str) -> Profiler
+    {
+        let i = Interpreter::new();
+        i.enable_profiler();
+        run_in(&i, source).unwrap();
+        i.take_profile().unwrap()
+    }
+
+    #[test]
+    fn test_calls_are_counted_per_function()
+    {
+        let profiler = profile(
+            "fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            fun twice() { return fib(5) + fib(5); }
+            twice();",
+        );
+
+        let fib = profiler.stats("fib", 1).unwrap();
+        assert_eq!(fib.calls, 30);
+        assert!(fib.inclusive >= fib.exclusive);
+        assert_eq!(profiler.stats("twice", 5).unwrap().calls, 1);
+        assert_eq!(profiler.stats("fib", 2), None);
+        assert!(profiler.table().contains("fib (line 1)"));
+    }
+
+    #[test]
+    fn test_folded_stacks()
+    {
+        let profiler = profile(
+            "fun inner() { var s = 0; for (var i = 0; i < 1000; i = i + 1) s = s + i; }
+            fun outer() { inner(); }
+            outer();",
+        );
+
+        let folded = profiler.folded_stacks();
+        assert!(folded
+            .lines()
+            .any(|line| line.starts_with("outer:2;inner:1 ")));
+        assert!(folded.lines().all(|line| line.starts_with("outer:2")));
+    }
+}
@@ -0,0 +1,616 @@
+//! Resolves a program's variables before the tree-walking interpreter runs
+//! it. Variables declared inside blocks and functions are given a slot in
+//! their function's frame, and variables of enclosing functions are captured
+//! by closures through shared cells, so only globals are looked up by name at
+//! runtime.
+use crate::{error::LoxResult, expr::*, stmt::*, tokens::Token};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+/// Where a variable lives, as decided by the resolver. Declarations are
+/// either `Global` or `Local`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Variable
+{
+    /// Looked up by name in the environment of the script or module. This is
+    /// also where variables are looked for before the program is resolved.
+    #[default]
+    Global,
+
+    /// A slot in the current frame
+    Local(usize),
+
+    /// One of the variables captured by the function being run
+    Upvalue(usize),
+}
+
+/// A variable captured by a function when it's declared, either from the
+/// declaring frame's slots or from the declaring function's own upvalues
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture
+{
+    pub name: Rc<str>,
+    pub index: usize,
+    pub is_local: bool,
+
+    /// Whether the variable is declared after the capturing function, so its
+    /// slot doesn't hold it yet
+    pub forward: bool,
+}
+
+/// How a function's frame is laid out. Its parameters take the first slots.
+#[derive(Debug, Default)]
+pub struct FunctionLayout
+{
+    /// The number of slots in the function's frame
+    pub slots: Cell<usize>,
+
+    /// The variables captured by closures of the function, in upvalue order
+    pub captures: RefCell<Vec<Capture>>,
+}
+
+/// The variables a statement can see besides the globals, by name. Later
+/// entries shadow earlier ones.
+pub type Scope = Rc<[(Rc<str>, Variable)]>;
+
+/// The scope of each statement of a program, by the statement's address
+pub type Scopes = HashMap<*const Stmt, Scope>;
+
+/// A local variable, in scope while its block is being resolved
+struct Local
+{
+    name: Rc<str>,
+    depth: usize,
+
+    /// Variables and functions get their slot when their block starts, but
+    /// are only declared once their declaration is reached. Until then only
+    /// the functions declared before them can refer to them.
+    declared: bool,
+}
+
+/// A function being resolved. The top level of a program is one too, with
+/// globals rather than locals at depth 0.
+#[derive(Default)]
+struct FunctionScope
+{
+    /// The locals in scope, each taking the slot at its index
+    locals: Vec<Local>,
+    captures: Vec<Capture>,
+    depth: usize,
+
+    /// The number of loops enclosing the statement being resolved
+    loops: usize,
+
+    /// The most slots in use at once
+    slots: usize,
+}
+
+pub struct Resolver
+{
+    /// The function being resolved, and the functions enclosing it
+    functions: RefCell<Vec<FunctionScope>>,
+
+    /// The scope of each statement, if they're being recorded
+    scopes: Option<RefCell<Scopes>>,
+}
+
+impl Resolver
+{
+    /// Resolve a program, returning the number of slots its top level needs
+    /// for variables declared inside blocks
+    pub fn resolve(statements: &[Stmt]) -> Result<usize, LoxResult>
+    {
+        Self::resolve_program(statements, None).map(|(slots, _)| slots)
+    }
+
+    /// Resolve a program like `resolve`, also returning the scope of each of
+    /// its statements for the debugger
+    pub fn resolve_with_scopes(statements: &[Stmt]) -> Result<(usize, Scopes), LoxResult>
+    {
+        Self::resolve_program(statements, Some(RefCell::default()))
+    }
+
+    fn resolve_program(
+        statements: &[Stmt],
+        scopes: Option<RefCell<Scopes>>,
+    ) -> Result<(usize, Scopes), LoxResult>
+    {
+        let resolver = Self {
+            functions: RefCell::new(vec![FunctionScope::default()]),
+            scopes,
+        };
+        resolver.statements(statements)?;
+
+        let slots = resolver.functions.into_inner().pop().unwrap().slots;
+        Ok((
+            slots,
+            resolver.scopes.map(RefCell::into_inner).unwrap_or_default(),
+        ))
+    }
+
+    fn statements(&self, statements: &[Stmt]) -> Result<(), LoxResult>
+    {
+        statements
+            .iter()
+            .try_for_each(|statement| self.statement(statement))
+    }
+
+    fn statement(&self, stmt: &Stmt) -> Result<(), LoxResult>
+    {
+        if let Some(scopes) = &self.scopes
+        {
+            let scope = self.current(|f| {
+                let captured =
+                    f.captures.iter().enumerate().map(|(index, capture)| {
+                        (Rc::clone(&capture.name), Variable::Upvalue(index))
+                    });
+                let locals = f
+                    .locals
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, local)| local.declared)
+                    .map(|(slot, local)| (Rc::clone(&local.name), Variable::Local(slot)));
+                captured.chain(locals).collect()
+            });
+            scopes.borrow_mut().insert(stmt, scope);
+        }
+        stmt.accept(self)
+    }
+
+    /// Run `f` on the function being resolved
+    fn current<T>(&self, f: impl FnOnce(&mut FunctionScope) -> T) -> T
+    {
+        f(self.functions.borrow_mut().last_mut().unwrap())
+    }
+
+    /// Resolve statements in a new scope
+    fn block(
+        &self,
+        statements: &[Stmt],
+        declare: Option<(&Token, &Cell<Variable>)>,
+    ) -> Result<(), LoxResult>
+    {
+        self.current(|f| f.depth += 1);
+        if let Some((name, variable)) = declare
+        {
+            self.declare(name, variable);
+        }
+        self.hoist(statements);
+        self.statements(statements)?;
+        self.current(|f| {
+            f.depth -= 1;
+            let depth = f.depth;
+            f.locals.retain(|local| local.depth <= depth);
+        });
+        Ok(())
+    }
+
+    /// Give each variable and function declared directly in `statements` a
+    /// slot, so the functions declared before them can refer to them
+    fn hoist(&self, statements: &[Stmt])
+    {
+        self.current(|f| {
+            for statement in statements
+            {
+                let name = match statement
+                {
+                    Stmt::Var(stmt) => &stmt.name,
+                    Stmt::Function(stmt) => &stmt.name,
+                    _ => continue,
+                };
+                f.locals.push(Local {
+                    name: name.lexeme.clone(),
+                    depth: f.depth,
+                    declared: false,
+                });
+            }
+            f.slots = f.slots.max(f.locals.len());
+        });
+    }
+
+    /// Declare a variable in the current scope, in the slot it was given when
+    /// its block started if it has one
+    fn declare(&self, name: &Token, variable: &Cell<Variable>)
+    {
+        variable.set(self.current(|f| {
+            if f.depth == 0
+            {
+                return Variable::Global;
+            }
+
+            let depth = f.depth;
+            if let Some(slot) = f.locals.iter().position(|local| {
+                !local.declared && local.depth == depth && local.name == name.lexeme
+            })
+            {
+                f.locals[slot].declared = true;
+                return Variable::Local(slot);
+            }
+
+            f.locals.push(Local {
+                name: name.lexeme.clone(),
+                depth,
+                declared: true,
+            });
+            f.slots = f.slots.max(f.locals.len());
+            Variable::Local(f.locals.len() - 1)
+        }));
+    }
+
+    /// Fail with `message` unless `token` is inside a loop of the function
+    /// being resolved
+    fn require_loop(&self, token: &Token, message: &str) -> Result<(), LoxResult>
+    {
+        if self.current(|f| f.loops) == 0
+        {
+            return Err(LoxResult::parse_error(token, message));
+        }
+        Ok(())
+    }
+
+    /// Resolve a reference to the variable `name`
+    fn reference(&self, name: &Token, variable: &Cell<Variable>)
+    {
+        let function = self.functions.borrow().len() - 1;
+        variable.set(self.resolve_variable(function, &name.lexeme, false));
+    }
+
+    /// Find `name` in the function at `function`, capturing it from the
+    /// enclosing functions if needed. Locals that aren't declared yet are
+    /// only found when `captured`, as the functions declared before them can
+    /// refer to them.
+    fn resolve_variable(&self, function: usize, name: &str, captured: bool) -> Variable
+    {
+        if let Some(slot) = self.functions.borrow()[function]
+            .locals
+            .iter()
+            .rposition(|local| *local.name == *name && (local.declared || captured))
+        {
+            return Variable::Local(slot);
+        }
+        if function == 0
+        {
+            return Variable::Global;
+        }
+
+        let capture = match self.resolve_variable(function - 1, name, true)
+        {
+            Variable::Global => return Variable::Global,
+            Variable::Local(index) =>
+            {
+                Capture {
+                    name: name.into(),
+                    index,
+                    is_local: true,
+                    forward: !self.functions.borrow()[function - 1].locals[index].declared,
+                }
+            }
+            Variable::Upvalue(index) =>
+            {
+                Capture {
+                    name: name.into(),
+                    index,
+                    is_local: false,
+                    forward: false,
+                }
+            }
+        };
+
+        let scope = &mut self.functions.borrow_mut()[function];
+        let index = scope
+            .captures
+            .iter()
+            .position(|existing| *existing == capture)
+            .unwrap_or_else(|| {
+                scope.captures.push(capture);
+                scope.captures.len() - 1
+            });
+        Variable::Upvalue(index)
+    }
+}
+
+impl StmtVisitor<()> for Resolver
+{
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<(), LoxResult>
+    {
+        self.block(&stmt.statements, None)
+    }
+
+    fn visit_break_stmt(&self, stmt: &BreakStmt) -> Result<(), LoxResult>
+    {
+        self.require_loop(&stmt.token, "Cannot break outside of loop")
+    }
+
+    fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<(), LoxResult>
+    {
+        self.require_loop(&stmt.token, "Cannot continue outside of loop")
+    }
+
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<(), LoxResult>
+    {
+        stmt.expression.accept(self)
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<(), LoxResult>
+    {
+        // Declared first, so the function can call itself
+        self.declare(&stmt.name, &stmt.variable);
+
+        self.functions.borrow_mut().push(FunctionScope {
+            locals: stmt
+                .params
+                .iter()
+                .map(|param| {
+                    Local {
+                        name: param.lexeme.clone(),
+                        depth: 1,
+                        declared: true,
+                    }
+                })
+                .collect(),
+            depth: 1,
+            slots: stmt.params.len(),
+            ..FunctionScope::default()
+        });
+        self.hoist(&stmt.body);
+        let result = self.statements(&stmt.body);
+        let function = self.functions.borrow_mut().pop().unwrap();
+
+        stmt.layout.slots.set(function.slots);
+        *stmt.layout.captures.borrow_mut() = function.captures;
+        result
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<(), LoxResult>
+    {
+        stmt.condition.accept(self)?;
+        self.statement(&stmt.then_branch)?;
+        if let Some(else_branch) = &stmt.else_branch
+        {
+            self.statement(else_branch)?;
+        }
+        Ok(())
+    }
+
+    fn visit_import_stmt(&self, stmt: &ImportStmt) -> Result<(), LoxResult>
+    {
+        for (name, variable) in stmt.alias.iter().chain(&stmt.names).zip(&stmt.variables)
+        {
+            self.declare(name, variable);
+        }
+        Ok(())
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<(), LoxResult>
+    {
+        stmt.expression.accept(self)
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<(), LoxResult>
+    {
+        // Rejected here, so a `try` statement can't catch it
+        if self.functions.borrow().len() == 1
+        {
+            return Err(LoxResult::parse_error(
+                &stmt.keyword,
+                "Can't return from top-level code.",
+            ));
+        }
+
+        match &stmt.value
+        {
+            Some(value) => value.accept(self),
+            None => Ok(()),
+        }
+    }
+
+    fn visit_throw_stmt(&self, stmt: &ThrowStmt) -> Result<(), LoxResult>
+    {
+        stmt.value.accept(self)
+    }
+
+    fn visit_try_stmt(&self, stmt: &TryStmt) -> Result<(), LoxResult>
+    {
+        self.block(&stmt.body, None)?;
+        if let (Some(name), Some(body)) = (&stmt.catch_name, &stmt.catch_body)
+        {
+            self.block(body, Some((name, &stmt.catch_variable)))?;
+        }
+        if let Some(body) = &stmt.finally_body
+        {
+            self.block(body, None)?;
+        }
+        Ok(())
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<(), LoxResult>
+    {
+        // The initializer can't see the variable it initializes
+        if let Some(initializer) = &stmt.initializer
+        {
+            initializer.accept(self)?;
+        }
+        self.declare(&stmt.name, &stmt.variable);
+        Ok(())
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<(), LoxResult>
+    {
+        stmt.condition.accept(self)?;
+        self.current(|f| f.loops += 1);
+        let result = self.statement(&stmt.body);
+        self.current(|f| f.loops -= 1);
+        result?;
+        match &stmt.increment
+        {
+            Some(increment) => increment.accept(self),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ExprVisitor<()> for Resolver
+{
+    fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<(), LoxResult>
+    {
+        expr.value.accept(self)?;
+        self.reference(&expr.name, &expr.variable);
+        Ok(())
+    }
+
+    fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<(), LoxResult>
+    {
+        expr.left.accept(self)?;
+        expr.right.accept(self)
+    }
+
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<(), LoxResult>
+    {
+        expr.callee.accept(self)?;
+        expr.arguments
+            .iter()
+            .try_for_each(|argument| argument.accept(self))
+    }
+
+    fn visit_get_expr(&self, expr: &GetExpr) -> Result<(), LoxResult> { expr.object.accept(self) }
+
+    fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<(), LoxResult>
+    {
+        expr.expression.accept(self)
+    }
+
+    fn visit_literal_expr(&self, _expr: &LiteralExpr) -> Result<(), LoxResult> { Ok(()) }
+
+    fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<(), LoxResult>
+    {
+        expr.left.accept(self)?;
+        expr.right.accept(self)
+    }
+
+    fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<(), LoxResult>
+    {
+        expr.right.accept(self)
+    }
+
+    fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<(), LoxResult>
+    {
+        self.reference(&expr.name, &expr.variable);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::parse;
+
+    /// The variable printed by a `print` statement
+    fn printed(stmt: &Stmt) -> Variable
+    {
+        match stmt
+        {
+            Stmt::Print(PrintStmt {
+                expression: Expr::Variable(variable),
+                ..
+            }) => variable.variable.get(),
+            _ => panic!("Expected a print statement"),
+        }
+    }
+
+    #[test]
+    fn test_slots_are_reused_by_sibling_blocks()
+    {
+        let statements =
+            parse("var g; { var a; { var b; print b; } { var c; print g; } print a; }");
+        assert_eq!(Resolver::resolve(&statements).unwrap(), 2);
+
+        let Stmt::Block(block) = &statements[1]
+        else
+        {
+            panic!("Expected a block");
+        };
+        let (Stmt::Block(first), Stmt::Block(second)) =
+            (&block.statements[1], &block.statements[2])
+        else
+        {
+            panic!("Expected blocks");
+        };
+        assert_eq!(printed(&first.statements[1]), Variable::Local(1));
+        assert_eq!(printed(&second.statements[1]), Variable::Global);
+        assert_eq!(printed(&block.statements[3]), Variable::Local(0));
+    }
+
+    #[test]
+    fn test_captures()
+    {
+        let statements =
+            parse("fun outer(x) { var y; fun inner() { print y; print x; print y; } }");
+        Resolver::resolve(&statements).unwrap();
+
+        let Stmt::Function(outer) = &statements[0]
+        else
+        {
+            panic!("Expected a function");
+        };
+        let Stmt::Function(inner) = &outer.body[1]
+        else
+        {
+            panic!("Expected a function");
+        };
+
+        assert_eq!(outer.layout.slots.get(), 3);
+        assert_eq!(
+            *inner.layout.captures.borrow(),
+            [
+                Capture {
+                    name: "y".into(),
+                    index: 1,
+                    is_local: true,
+                    forward: false
+                },
+                Capture {
+                    name: "x".into(),
+                    index: 0,
+                    is_local: true,
+                    forward: false
+                }
+            ]
+        );
+        assert_eq!(printed(&inner.body[0]), Variable::Upvalue(0));
+        assert_eq!(printed(&inner.body[2]), Variable::Upvalue(0));
+    }
+
+    #[test]
+    fn test_forward_captures()
+    {
+        let statements =
+            parse("fun outer() { fun inner() { print later; } var later; print later; }");
+        Resolver::resolve(&statements).unwrap();
+
+        let Stmt::Function(outer) = &statements[0]
+        else
+        {
+            panic!("Expected a function");
+        };
+        let Stmt::Function(inner) = &outer.body[0]
+        else
+        {
+            panic!("Expected a function");
+        };
+
+        assert_eq!(
+            *inner.layout.captures.borrow(),
+            [Capture {
+                name: "later".into(),
+                index: 1,
+                is_local: true,
+                forward: true
+            }]
+        );
+        assert_eq!(printed(&outer.body[2]), Variable::Local(1));
+    }
+}
@@ -0,0 +1,353 @@
+//! Limits on how long a script may run and how much memory it may use, for
+//! embedders running untrusted code. Scripts running for too long are aborted
+//! with `LoxResult::Aborted`, which can't be caught by the script. Running out
+//! of memory is a `RuntimeError`, so scripts can recover from it.
+use super::Interpreter;
+use crate::{
+    error::{AbortReason, LoxResult},
+    object::Object,
+    tokens::Token,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often, in steps, the deadline is checked. Reading the clock on every
+/// step would be a significant part of the interpreter's run time.
+const DEADLINE_INTERVAL: u64 = 1024;
+
+/// The deepest calls of Lox functions can be nested, so runaway recursion is
+/// a runtime error rather than overflowing the native stack, like the VM's
+/// limit on its frames. Each call can take tens of kilobytes of native stack,
+/// depending on how deeply its statements are nested, so the `lox` binary runs
+/// scripts on a thread with a large stack.
+pub const MAX_CALL_DEPTH: usize = 4096;
+
+/// Limits applied to each call to `Interpreter::interpret`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits
+{
+    /// The maximum number of statements and expressions to execute
+    pub max_steps: Option<u64>,
+
+    /// How long the script may run for
+    pub timeout: Option<Duration>,
+
+    /// The approximate number of bytes the script's variables and strings may
+    /// use
+    pub max_memory: Option<usize>,
+}
+
+/// Cancels a running script from another thread. The interpreter aborts at
+/// its next step. The handle is reset when each `interpret` call starts and
+/// returns, so an interrupt that arrives between runs doesn't cancel the next.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle
+{
+    pub fn interrupt(&self) { self.0.store(true, Ordering::Relaxed); }
+}
+
+/// The interpreter's progress against its limits
+#[derive(Debug, Default)]
+pub(super) struct Sandbox
+{
+    pub(super) limits: Limits,
+    steps: u64,
+    deadline: Option<Instant>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Sandbox
+{
+    /// Start counting against the limits afresh
+    pub(super) fn start(&mut self)
+    {
+        self.interrupted.store(false, Ordering::Relaxed);
+        self.steps = 0;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Count a step, failing if any limit has been exceeded
+    fn step(&mut self) -> Result<(), AbortReason>
+    {
+        self.steps += 1;
+
+        if self.interrupted.load(Ordering::Relaxed)
+        {
+            return Err(AbortReason::Interrupted);
+        }
+
+        if let Some(max_steps) = self.limits.max_steps
+        {
+            if self.steps > max_steps
+            {
+                return Err(AbortReason::StepLimit(max_steps));
+            }
+        }
+
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.limits.timeout)
+        {
+            if self.steps.is_multiple_of(DEADLINE_INTERVAL) && Instant::now() >= deadline
+            {
+                return Err(AbortReason::Timeout(timeout));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clear any interrupt, whether or not the run was aborted by it
+    pub(super) fn finish(&mut self) { self.interrupted.store(false, Ordering::Relaxed); }
+}
+
+impl Interpreter
+{
+    /// Run a script with fresh limits
+    pub(crate) fn sandboxed(
+        &self,
+        run: impl FnOnce() -> Result<(), LoxResult>,
+    ) -> Result<(), LoxResult>
+    {
+        self.sandbox.borrow_mut().start();
+        let result = run();
+        self.sandbox.borrow_mut().finish();
+        result
+    }
+
+    /// Limit how much the script may execute on each run
+    pub fn set_limits(&self, limits: Limits) { self.sandbox.borrow_mut().limits = limits; }
+
+    /// A handle for cancelling the script from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle
+    {
+        InterruptHandle(Arc::clone(&self.sandbox.borrow().interrupted))
+    }
+
+    /// Check that `bytes` more can be allocated without exceeding the memory
+    /// limit, failing with a message for the error if they can't
+    pub(crate) fn reserve(&self, bytes: usize) -> Result<(), String>
+    {
+        let used = self.globals.borrow().memory_used();
+        match self.sandbox.borrow().limits.max_memory
+        {
+            Some(limit) if used.saturating_add(bytes) > limit =>
+            {
+                Err(format!(
+                    "Out of memory, the script is limited to {limit} bytes"
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `value` can be stored in a variable without exceeding the
+    /// memory limit
+    pub(super) fn reserve_variable(&self, name: &Token, value: &Object) -> Result<(), LoxResult>
+    {
+        self.reserve(name.lexeme.len() + std::mem::size_of::<Object>() + value.heap_size())
+            .map_err(|message| LoxResult::new_runtime_error(name.clone(), message))
+    }
+
+    /// Count a statement or expression against the limits
+    pub(crate) fn step(&self) -> Result<(), LoxResult>
+    {
+        self.sandbox
+            .borrow_mut()
+            .step()
+            .map_err(|reason| LoxResult::Aborted { reason })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::{global, run_in};
+    use std::thread;
+
+    #[test]
+    fn test_step_limit()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        });
+
+        let res = run_in(&i, "while (true) {}");
+        assert!(matches!(
+            res,
+            Err(LoxResult::Aborted {
+                reason: AbortReason::StepLimit(1000)
+            })
+        ));
+
+        // The budget is per run
+        run_in(&i, "var a = 1;").unwrap();
+    }
+
+    #[test]
+    fn test_abort_is_not_caught()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            timeout: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        });
+
+        let res = run_in(&i, "try { while (true) {} } catch (e) {}");
+        assert!(matches!(
+            res,
+            Err(LoxResult::Aborted {
+                reason: AbortReason::Timeout(_)
+            })
+        ));
+    }
+
+    #[test]
+    fn test_memory_limit()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 10_000),
+            ..Limits::default()
+        });
+
+        // Doubling a string quickly runs into the limit, and the error can be
+        // caught
+        run_in(
+            &i,
+            "var s = \"x\"; var caught = false; try { while (true) s = s + s; } catch (e) { \
+             caught = e.message; }",
+        )
+        .unwrap();
+        match global(&i, "caught")
+        {
+            Object::Str(message) => assert!(message.starts_with("Out of memory")),
+            other => panic!("Expected an error message, got {other}"),
+        }
+
+        assert!(matches!(
+            run_in(&i, "var t = repeat(\"x\", 20000);"),
+            Err(LoxResult::RuntimeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_memory_limit_counts_locals()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 200_000),
+            ..Limits::default()
+        });
+
+        assert!(matches!(
+            run_in(
+                &i,
+                "fun f() { var a = repeat(\"x\", 60000); var b = repeat(\"x\", 60000); var c = \
+                 repeat(\"x\", 60000); var d = repeat(\"x\", 60000); var e = repeat(\"x\", \
+                 60000); } f();"
+            ),
+            Err(LoxResult::RuntimeError { .. })
+        ));
+
+        // The locals are freed once the function returns
+        run_in(
+            &i,
+            "fun f() { var a = repeat(\"x\", 60000); } f(); f(); f(); f();",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_call_depth_limit()
+    {
+        // Reaching the limit takes more stack than test threads have
+        let caught = thread::Builder::new()
+            .stack_size(256 * 1024 * 1024)
+            .spawn(|| {
+                let i = Interpreter::new();
+                run_in(
+                    &i,
+                    "var caught; fun f(n) { return f(n + 1); } try { f(0); } catch (e) { caught = \
+                     e.message; }",
+                )
+                .unwrap();
+                let caught = i.globals.borrow().lookup("caught");
+                caught.map(|message| message.to_string())
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(caught.as_deref(), Some("Stack overflow."));
+    }
+
+    #[test]
+    fn test_memory_limit_counts_lists()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 100_000),
+            ..Limits::default()
+        });
+
+        // 30,000 strings take more memory than the text they were split from
+        assert!(matches!(
+            run_in(&i, "var parts = split(repeat(\"x,\", 30000), \",\");"),
+            Err(LoxResult::RuntimeError { .. })
+        ));
+
+        // A list that fits can still be joined into a string that doesn't
+        run_in(
+            &i,
+            "var parts = split(repeat(\"x\", 30000) + \",\" + repeat(\"y\", 30000), \",\");",
+        )
+        .unwrap();
+        assert!(matches!(
+            run_in(&i, "print join(parts, repeat(\"-\", 50000));"),
+            Err(LoxResult::RuntimeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_interrupt()
+    {
+        let i = Interpreter::new();
+        let handle = i.interrupt_handle();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            handle.interrupt();
+        });
+
+        let res = run_in(&i, "while (true) {}");
+        interrupter.join().unwrap();
+        assert!(matches!(
+            res,
+            Err(LoxResult::Aborted {
+                reason: AbortReason::Interrupted
+            })
+        ));
+
+        // The interrupt only cancels the run it happened in
+        run_in(&i, "var a = 1;").unwrap();
+    }
+
+    #[test]
+    fn test_interrupt_after_run()
+    {
+        let i = Interpreter::new();
+        let handle = i.interrupt_handle();
+        run_in(&i, "var a = 1;").unwrap();
+
+        // An interrupt that arrives between runs doesn't cancel the next one
+        handle.interrupt();
+        run_in(&i, "var b = 2;").unwrap();
+    }
+}
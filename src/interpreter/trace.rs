@@ -0,0 +1,307 @@
+//! Logs what the tree interpreter does, for `--trace`: each statement it
+//! executes, each call of a Lox function with its arguments and result, and
+//! each variable defined or assigned. Lines are indented by the depth of the
+//! calls, or written as JSON objects, one per line, for tools to read.
+use super::Interpreter;
+use crate::{error::LoxResult, object::Object, stmt::Stmt, tokens::Token};
+use std::io::{self, Write};
+
+/// How trace events are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat
+{
+    /// Lines of text, indented by the depth of the calls
+    Text,
+
+    /// A JSON object on each line
+    Json,
+}
+
+pub struct Tracer
+{
+    sink: Box<dyn Write>,
+    format: TraceFormat,
+
+    /// The number of Lox function calls in progress
+    depth: usize,
+}
+
+impl std::fmt::Debug for Tracer
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        write!(f, "<tracer {:?}>", self.format)
+    }
+}
+
+impl Tracer
+{
+    pub fn new(sink: Box<dyn Write>, format: TraceFormat) -> Self
+    {
+        Self {
+            sink,
+            format,
+            depth: 0,
+        }
+    }
+
+    /// Write an event, given as its text and as the fields of its JSON object.
+    /// Failing to write the trace doesn't stop the script.
+    fn event(&mut self, text: String, fields: &[(&str, String)])
+    {
+        let _ = match self.format
+        {
+            TraceFormat::Text => writeln!(self.sink, "{:1$}{text}", "", self.depth * 2),
+            TraceFormat::Json =>
+            {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .chain([&("depth", self.depth.to_string())])
+                    .map(|(name, value)| format!("\"{name}\":{value}"))
+                    .collect();
+                writeln!(self.sink, "{{{}}}", fields.join(","))
+            }
+        };
+    }
+
+    fn statement(&mut self, stmt: &Stmt)
+    {
+        let (kind, line) = (statement_kind(stmt), stmt.line());
+        self.event(
+            format!("[line {line}] {kind}"),
+            &[
+                ("event", json_string("statement")),
+                ("kind", json_string(kind)),
+                ("line", line.to_string()),
+            ],
+        );
+    }
+
+    fn call(&mut self, name: &Token, arguments: &[Object])
+    {
+        let text: Vec<String> = arguments.iter().map(Object::to_string).collect();
+        let json: Vec<String> = arguments.iter().map(json_value).collect();
+        self.event(
+            format!(
+                "[line {}] call {}({})",
+                name.line,
+                name.lexeme,
+                text.join(", ")
+            ),
+            &[
+                ("event", json_string("call")),
+                ("function", json_string(&name.lexeme)),
+                ("line", name.line.to_string()),
+                ("arguments", format!("[{}]", json.join(","))),
+            ],
+        );
+        self.depth += 1;
+    }
+
+    fn exit(&mut self, name: &Token, result: &Result<Object, LoxResult>)
+    {
+        self.depth = self.depth.saturating_sub(1);
+        match result
+        {
+            Ok(value) =>
+            {
+                self.event(
+                    format!("return {} -> {value}", name.lexeme),
+                    &[
+                        ("event", json_string("return")),
+                        ("function", json_string(&name.lexeme)),
+                        ("value", json_value(value)),
+                    ],
+                )
+            }
+            Err(error) =>
+            {
+                self.event(
+                    format!("unwind {}: {error}", name.lexeme),
+                    &[
+                        ("event", json_string("unwind")),
+                        ("function", json_string(&name.lexeme)),
+                        ("error", json_string(&error.to_string())),
+                    ],
+                )
+            }
+        }
+    }
+
+    fn variable(&mut self, event: &str, name: &Token, value: &Object)
+    {
+        self.event(
+            format!("[line {}] {event} {} = {value}", name.line, name.lexeme),
+            &[
+                ("event", json_string(event)),
+                ("name", json_string(&name.lexeme)),
+                ("line", name.line.to_string()),
+                ("value", json_value(value)),
+            ],
+        );
+    }
+}
+
+fn statement_kind(stmt: &Stmt) -> &'static str
+{
+    match stmt
+    {
+        Stmt::Block(_) => "block",
+        Stmt::Break(_) => "break",
+        Stmt::Continue(_) => "continue",
+        Stmt::Expression(_) => "expression",
+        Stmt::Function(_) => "function",
+        Stmt::If(_) => "if",
+        Stmt::Import(_) => "import",
+        Stmt::Print(_) => "print",
+        Stmt::Return(_) => "return",
+        Stmt::Throw(_) => "throw",
+        Stmt::Try(_) => "try",
+        Stmt::Var(_) => "var",
+        Stmt::While(_) => "while",
+    }
+}
+
+fn json_string(s: &str) -> String
+{
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars()
+    {
+        match c
+        {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A Lox value as JSON. Values without a JSON equivalent are written as the
+/// string Lox prints for them.
+fn json_value(value: &Object) -> String
+{
+    match value
+    {
+        Object::Num(n) if n.is_finite() => n.to_string(),
+        Object::Bool(b) => b.to_string(),
+        Object::Nil => "null".to_string(),
+        other => json_string(&other.to_string()),
+    }
+}
+
+impl Interpreter
+{
+    /// Start writing a trace of the script to `sink`
+    pub fn enable_trace(&self, sink: Box<dyn Write>, format: TraceFormat)
+    {
+        *self.tracer.borrow_mut() = Some(Tracer::new(sink, format));
+    }
+
+    /// Stop tracing, flushing what was written
+    pub fn finish_trace(&self) -> io::Result<()>
+    {
+        match self.tracer.borrow_mut().take()
+        {
+            Some(mut tracer) => tracer.sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn trace_statement(&self, stmt: &Stmt)
+    {
+        if let Some(tracer) = self.tracer.borrow_mut().as_mut()
+        {
+            tracer.statement(stmt);
+        }
+    }
+
+    pub(crate) fn trace_call(&self, name: &Token, arguments: &[Object])
+    {
+        if let Some(tracer) = self.tracer.borrow_mut().as_mut()
+        {
+            tracer.call(name, arguments);
+        }
+    }
+
+    pub(crate) fn trace_exit(&self, name: &Token, result: &Result<Object, LoxResult>)
+    {
+        if let Some(tracer) = self.tracer.borrow_mut().as_mut()
+        {
+            tracer.exit(name, result);
+        }
+    }
+
+    pub(super) fn trace_define(&self, name: &Token, value: &Object)
+    {
+        if let Some(tracer) = self.tracer.borrow_mut().as_mut()
+        {
+            tracer.variable("define", name, value);
+        }
+    }
+
+    pub(super) fn trace_assign(&self, name: &Token, value: &Object)
+    {
+        if let Some(tracer) = self.tracer.borrow_mut().as_mut()
+        {
+            tracer.variable("assign", name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::{run_in, SharedOutput};
+
+    fn trace(source: &str, format: TraceFormat) -> String
+    {
+        let sink = SharedOutput::default();
+        let i = Interpreter::new();
+        i.enable_trace(Box::new(sink.clone()), format);
+        run_in(&i, source).unwrap();
+        i.finish_trace().unwrap();
+        sink.contents()
+    }
+
+    #[test]
+    fn test_text_trace()
+    {
+        let output = trace(
+            "fun add(a, b) { return a + b; }\nvar x = add(1, 2);\nx = \"done\";",
+            TraceFormat::Text,
+        );
+        assert_eq!(
+            output,
+            "[line 1] function
+[line 1] define add = <fn add>
+[line 2] var
+[line 1] call add(1, 2)
+  [line 1] return
+return add -> 3
+[line 2] define x = 3
+[line 3] expression
+[line 3] assign x = done
+"
+        );
+    }
+
+    #[test]
+    fn test_json_trace()
+    {
+        let output = trace("var s = \"a\nb\";", TraceFormat::Json);
+        assert_eq!(
+            output,
+            r#"{"event":"statement","kind":"var","line":1,"depth":0}
+{"event":"define","name":"s","line":1,"value":"a\nb","depth":0}
+"#
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use crate::object::Object;
 use crate::{error::LoxResult, tokens::*};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 pub struct Scanner
 {
     /// The source document as a vector of chars as to allow for easy iteration
@@ -20,6 +23,10 @@ pub struct Scanner
 
     /// All the reserved keywords
     keywords: HashMap<String, TokenType>,
+
+    /// The lexemes and string literals scanned so far, so that repeated
+    /// identifiers and strings share a single allocation
+    strings: HashSet<Rc<str>>,
 }
 
 impl Scanner
@@ -45,6 +52,14 @@ impl Scanner
             ("var".to_string(), TokenType::Var),
             ("while".to_string(), TokenType::While),
             ("break".to_string(), TokenType::Break),
+            ("continue".to_string(), TokenType::Continue),
+            ("throw".to_string(), TokenType::Throw),
+            ("try".to_string(), TokenType::Try),
+            ("catch".to_string(), TokenType::Catch),
+            ("finally".to_string(), TokenType::Finally),
+            ("import".to_string(), TokenType::Import),
+            ("from".to_string(), TokenType::From),
+            ("as".to_string(), TokenType::As),
         ]);
         Self {
             source: source.chars().collect(),
@@ -53,6 +68,7 @@ impl Scanner
             current: 0,
             line: 1,
             keywords,
+            strings: HashSet::new(),
         }
     }
 
@@ -354,6 +370,7 @@ impl Scanner
         let value: String = self.source[self.start + 1..self.current - 1]
             .iter()
             .collect();
+        let value = self.intern(value);
         self.add_token_object(TokenType::String, Some(Object::Str(value)));
         Ok(())
     }
@@ -377,10 +394,23 @@ impl Scanner
         // Get a char slice from the source, then turn it to an iterator. After this,
         // collect into a string
         let lexeme: String = self.source[self.start..self.current].iter().collect();
+        let lexeme = self.intern(lexeme);
         self.tokens
             .push(Token::new(ttype, lexeme, literal, self.line))
     }
 
+    /// Get the shared copy of a string, adding it if it's new.
+    fn intern(&mut self, text: String) -> Rc<str>
+    {
+        if let Some(interned) = self.strings.get(text.as_str())
+        {
+            return Rc::clone(interned);
+        }
+        let interned: Rc<str> = text.into();
+        self.strings.insert(Rc::clone(&interned));
+        interned
+    }
+
     /// Returns true if we're at the end of the string.
     fn is_at_end(&self) -> bool { self.current >= self.source.len() }
 
@@ -399,3 +429,29 @@ impl Scanner
         }
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_repeated_strings_are_interned()
+    {
+        let mut scanner = Scanner::new("var a = \"a\"; a = a + \"a\";".to_string());
+        let tokens = scanner.scan_tokens().unwrap();
+
+        // The identifier `a` and the string "a" share one allocation
+        let (declared, used) = (&tokens[1].lexeme, &tokens[5].lexeme);
+        assert!(Rc::ptr_eq(declared, used));
+        match (&tokens[3].literal, &tokens[9].literal)
+        {
+            (Some(Object::Str(first)), Some(Object::Str(second))) =>
+            {
+                assert!(Rc::ptr_eq(first, second));
+                assert!(Rc::ptr_eq(first, declared));
+            }
+            other => panic!("Expected string literals, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! A Lox interpreter, with a tree-walking backend and a bytecode VM. `Lox`
+//! runs scripts the way the `lox` binary does.
+pub mod error;
+mod expr;
+pub mod interpreter;
+mod lexer;
+pub mod lox;
+pub mod object;
+mod optimizer;
+mod parser;
+mod stmt;
+mod tokens;
+mod vm;
+
+pub use crate::lox::{Backend, Lox};
@@ -1,13 +1,91 @@
 // use crate::_ast_printer::AstPrinter;
 use crate::error::*;
-use crate::interpreter::*;
+use crate::interpreter::{
+    sandbox::{InterruptHandle, Limits},
+    trace::TraceFormat,
+    *,
+};
 use crate::lexer::*;
 use crate::parser::Parser;
-use std::io::{self, stdout, BufRead, Write};
+use crate::vm::{chunk::Prototype, compiler::Compiler, disassemble, serialize, Vm};
+use std::{
+    cell::RefCell,
+    io::{self, stdout, BufRead, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// How scripts are run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend
+{
+    /// Walk the syntax tree
+    Tree,
+
+    /// Compile to bytecode and run it on the VM
+    Vm,
+}
+
+/// Standard input, read a line at a time for the debugger. Stdin is only
+/// locked while a line is read, so the script being debugged can read it too.
+#[derive(Default)]
+struct StdinLines
+{
+    line: String,
+
+    /// How much of `line` has been consumed
+    consumed: usize,
+}
+
+impl Read for StdinLines
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for StdinLines
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]>
+    {
+        if self.consumed == self.line.len()
+        {
+            self.line.clear();
+            self.consumed = 0;
+            io::stdin().read_line(&mut self.line)?;
+        }
+        Ok(&self.line.as_bytes()[self.consumed..])
+    }
+
+    fn consume(&mut self, amount: usize) { self.consumed += amount; }
+}
+
+/// Where `--profile` writes the profile of a script
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProfileOutput
+{
+    /// A table on standard error
+    Table,
+
+    /// Folded stacks for flamegraph tools, in a file
+    Folded(PathBuf),
+}
 
 pub struct Lox
 {
     interpreter: Interpreter,
+    backend: RefCell<Backend>,
+    profile: RefCell<Option<ProfileOutput>>,
+
+    /// Where `--coverage` writes its LCOV file, if anywhere
+    lcov_path: RefCell<Option<PathBuf>>,
+}
+
+impl Default for Lox
+{
+    fn default() -> Self { Self::new() }
 }
 
 impl Lox
@@ -16,21 +94,188 @@ impl Lox
     {
         Self {
             interpreter: Interpreter::new(),
+            backend: RefCell::new(Backend::Tree),
+            profile: RefCell::new(None),
+            lcov_path: RefCell::new(None),
         }
     }
 
-    /// Open a file and interpret its contents.
+    /// Choose how scripts are run
+    pub fn set_backend(&self, backend: Backend) { *self.backend.borrow_mut() = backend; }
+
+    /// Enable or disable strict arithmetic in the interpreter
+    pub fn set_strict(&self, strict: bool) { self.interpreter.set_strict(strict); }
+
+    /// Optimize programs before running them
+    pub fn set_optimize(&self, optimize: bool) { self.interpreter.set_optimize(optimize); }
+
+    /// Restrict (or grant) the script's access to the host system
+    pub fn set_capabilities(&self, capabilities: Capabilities)
+    {
+        self.interpreter.set_capabilities(capabilities);
+    }
+
+    /// Set the command line arguments returned by `args()`
+    pub fn set_args(&self, args: Vec<String>) { self.interpreter.set_args(args); }
+
+    /// Limit how much each run of the interpreter may execute
+    pub fn set_limits(&self, limits: Limits) { self.interpreter.set_limits(limits); }
+
+    /// Add a directory to search for imported modules in
+    pub fn add_module_path(&self, path: PathBuf) { self.interpreter.add_module_path(path); }
+
+    /// Record the calls of the script's functions, and write the profile once
+    /// `run_file` finishes. Only the tree interpreter is profiled.
+    pub fn set_profile(&self, output: ProfileOutput)
+    {
+        self.interpreter.enable_profiler();
+        *self.profile.borrow_mut() = Some(output);
+    }
+
+    /// Record which statements run, and report the coverage once `run_file`
+    /// finishes, also writing it to `lcov_path` in the LCOV format. Only the
+    /// tree interpreter records coverage.
+    pub fn set_coverage(&self, lcov_path: Option<PathBuf>)
+    {
+        self.interpreter.enable_coverage();
+        *self.lcov_path.borrow_mut() = lcov_path;
+    }
+
+    /// Write a trace of what the tree interpreter does to `sink`
+    pub fn set_trace(&self, sink: Box<dyn Write>, format: TraceFormat)
+    {
+        self.interpreter.enable_trace(sink, format);
+    }
+
+    /// A handle for cancelling the running script from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle { self.interpreter.interrupt_handle() }
+
+    /// Open a file and interpret its contents. If the script fails, the
+    /// process exits with the error's exit code.
     pub fn run_file(&self, path: &String) -> io::Result<()>
     {
         let buf = std::fs::read_to_string(path)?;
-        if self.run(buf).is_err()
+        self.interpreter.set_script_path(Path::new(path));
+        let result = self.run(buf);
+        self.write_profile()?;
+        self.write_coverage()?;
+        self.interpreter.finish_trace()?;
+        if let Err(e) = result
+        {
+            std::process::exit(e.exit_code());
+        }
+
+        Ok(())
+    }
+
+    /// Run a script under the interactive debugger, which reads commands from
+    /// standard input. Debugging always walks the syntax tree.
+    pub fn debug_file(&self, path: &String) -> io::Result<()>
+    {
+        let buf = std::fs::read_to_string(path)?;
+        self.set_backend(Backend::Tree);
+        self.interpreter.set_script_path(Path::new(path));
+        self.interpreter
+            .enable_debugger(Box::new(StdinLines::default()), Box::new(stdout()));
+        let result = self.run(buf);
+        if let Err(e) = result
+        {
+            std::process::exit(e.exit_code());
+        }
+        println!("Program finished.");
+
+        Ok(())
+    }
+
+    /// Report the coverage of the script, if it was recorded
+    fn write_coverage(&self) -> io::Result<()>
+    {
+        let Some(coverage) = self.interpreter.take_coverage()
+        else
+        {
+            return Ok(());
+        };
+
+        eprint!("{}", coverage.text_report());
+        match &*self.lcov_path.borrow()
+        {
+            Some(path) => std::fs::write(path, coverage.lcov()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the profile of the script, if it was profiled
+    fn write_profile(&self) -> io::Result<()>
+    {
+        let Some(profiler) = self.interpreter.take_profile()
+        else
+        {
+            return Ok(());
+        };
+
+        match &*self.profile.borrow()
+        {
+            Some(ProfileOutput::Folded(path)) => std::fs::write(path, profiler.folded_stacks()),
+            _ =>
+            {
+                eprint!("{}", profiler.table());
+                Ok(())
+            }
+        }
+    }
+
+    /// Compile a script to bytecode, and write it to `output` for
+    /// `run_compiled`
+    pub fn compile_file(&self, path: &String, output: &Path) -> io::Result<()>
+    {
+        let buf = std::fs::read_to_string(path)?;
+        match self.compile(buf)
         {
-            std::process::exit(65);
+            Ok(script) => std::fs::write(output, serialize::serialize(&script)),
+            Err(e) => std::process::exit(e.exit_code()),
+        }
+    }
+
+    /// Run a script compiled by `compile_file` on the VM
+    pub fn run_compiled(&self, path: &String) -> io::Result<()>
+    {
+        let buf = std::fs::read(path)?;
+        self.interpreter.set_script_path(Path::new(path));
+        let result = serialize::deserialize(&buf)
+            .and_then(|script| Vm::interpret(&self.interpreter, script));
+
+        if let Err(e) = result
+        {
+            if !matches!(e, LoxResult::Exit { .. })
+            {
+                e.report();
+            }
+            std::process::exit(e.exit_code());
         }
 
         Ok(())
     }
 
+    /// Print the bytecode of a script, or of a file compiled by `compile_file`
+    pub fn disassemble_file(&self, path: &String) -> io::Result<()>
+    {
+        let script = if Path::new(path).extension().is_some_and(|e| e == "loxc")
+        {
+            serialize::deserialize(&std::fs::read(path)?).inspect_err(LoxResult::report)
+        }
+        else
+        {
+            self.compile(std::fs::read_to_string(path)?)
+        };
+
+        match script
+        {
+            Ok(script) => print!("{}", disassemble::disassemble(&script)),
+            Err(e) => std::process::exit(e.exit_code()),
+        }
+        Ok(())
+    }
+
     /// Open a REPL (Read-Eval-Print loop) interactive programming environment.
     pub fn run_prompt(&self)
     {
@@ -45,7 +290,10 @@ impl Lox
                 {
                     break;
                 }
-                let _ = self.run(line);
+                if let Err(LoxResult::Exit { code }) = self.run(line)
+                {
+                    std::process::exit(code);
+                }
             }
             else
             {
@@ -57,7 +305,28 @@ impl Lox
         }
     }
 
-    fn run(&self, source: String) -> Result<(), LoxResult>
+    /// Compile a script for the VM. Errors have already been reported.
+    fn compile(&self, source: String) -> Result<std::rc::Rc<Prototype>, LoxResult>
+    {
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens()?;
+        let mut parser = Parser::new(tokens);
+        let statements = parser.parse()?;
+        if !parser.success()
+        {
+            // The parse errors were reported as they were found
+            return Err(LoxResult::LoxError {
+                line: 0,
+                message: "The script has errors.".to_string(),
+            });
+        }
+
+        let statements = self.interpreter.optimize(statements)?;
+        Compiler::compile(&statements)
+    }
+
+    /// Run a script's source. Errors are reported before being returned.
+    pub fn run(&self, source: String) -> Result<(), LoxResult>
     {
         let mut scanner = Scanner::new(source);
         let tokens = scanner.scan_tokens()?;
@@ -68,7 +337,27 @@ impl Lox
 
         if parser.success()
         {
-            self.interpreter.interpret(&statements);
+            let statements = self.interpreter.optimize(statements)?;
+            let result = match *self.backend.borrow()
+            {
+                Backend::Tree => self.interpreter.interpret(&statements),
+                Backend::Vm =>
+                {
+                    // Compile errors have already been reported
+                    let script = Compiler::compile(&statements)?;
+                    Vm::interpret(&self.interpreter, script)
+                }
+            };
+
+            if let Err(e) = result
+            {
+                // Errors found before the program runs were reported when they were found
+                if !matches!(e, LoxResult::Exit { .. } | LoxResult::ParseError { .. })
+                {
+                    e.report();
+                }
+                return Err(e);
+            }
         }
 
         Ok(())
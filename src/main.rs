@@ -1,29 +1,217 @@
-mod error;
-mod expr;
-mod interpreter;
-mod lexer;
-mod lox;
-mod object;
-mod parser;
-mod stmt;
-mod tokens;
+use lox::{
+    interpreter::{sandbox::Limits, trace::TraceFormat, Capabilities},
+    lox::ProfileOutput,
+    Backend, Lox,
+};
+use std::{
+    env::args,
+    fs::File,
+    io::{stderr, BufWriter, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
 
-use lox::Lox;
-use std::env::args;
+/// The stack size of the thread running scripts. The tree interpreter recurses
+/// on the native stack, so it needs enough for its deepest calls.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
 
+/// The exit code for a file that can't be created, as in `sysexits.h`
+const EX_IOERR: i32 = 74;
 
 pub fn main()
 {
-    let args: Vec<String> = args().collect();
+    let runner = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("Couldn't start the interpreter thread");
+    if runner.join().is_err()
+    {
+        std::process::exit(70);
+    }
+}
+
+fn run()
+{
     let lox = Lox::new();
-    match args.len()
+    let mut args = args().skip(1);
+    let mut script = None;
+    let mut limits = Limits::default();
+    let mut backend = Backend::Tree;
+    let mut profile = None;
+    let mut coverage = None;
+    let mut trace = None;
+    let mut trace_format = TraceFormat::Text;
+
+    // Options come before the script, everything after it is passed to the script
+    for arg in args.by_ref()
+    {
+        match arg.as_str()
+        {
+            "--strict" => lox.set_strict(true),
+            "-O" => lox.set_optimize(true),
+            "--backend=tree" => backend = Backend::Tree,
+            "--backend=vm" => backend = Backend::Vm,
+            // Print a table of the time spent in each function
+            "--profile" => profile = Some(ProfileOutput::Table),
+            // Write folded stacks for flamegraph tools to a file
+            _ if arg.starts_with("--profile=") =>
+            {
+                profile = Some(ProfileOutput::Folded(arg["--profile=".len()..].into()));
+            }
+            // Report the lines run, and write them to an LCOV file if one is given
+            "--coverage" => coverage = Some(None),
+            _ if arg.starts_with("--coverage=") =>
+            {
+                coverage = Some(Some(arg["--coverage=".len()..].into()));
+            }
+            // Log what the interpreter does to standard error, or to a file
+            "--trace" => trace = Some(None),
+            _ if arg.starts_with("--trace=") =>
+            {
+                trace = Some(Some(arg["--trace=".len()..].to_string()))
+            }
+            "--trace-format=text" => trace_format = TraceFormat::Text,
+            "--trace-format=json" => trace_format = TraceFormat::Json,
+            // Deny the script access to the host system
+            "--sandbox" =>
+            {
+                lox.set_capabilities(Capabilities {
+                    console: false,
+                    filesystem: false,
+                    process: false,
+                })
+            }
+            _ if arg.starts_with("--module-path=") =>
+            {
+                lox.add_module_path(arg["--module-path=".len()..].into())
+            }
+            _ if arg.starts_with("--max-steps=") =>
+            {
+                limits.max_steps = Some(option_value(&arg, "--max-steps="));
+            }
+            // The timeout is given in milliseconds
+            _ if arg.starts_with("--timeout=") =>
+            {
+                limits.timeout = Some(Duration::from_millis(option_value(&arg, "--timeout=")));
+            }
+            // The memory limit is given in bytes
+            _ if arg.starts_with("--max-memory=") =>
+            {
+                limits.max_memory = Some(option_value(&arg, "--max-memory=") as usize);
+            }
+            _ if arg.starts_with("--") => usage(),
+            _ =>
+            {
+                script = Some(arg);
+                break;
+            }
+        }
+    }
+
+    lox.set_limits(limits);
+    lox.set_backend(backend);
+    // The VM doesn't record calls or coverage, or trace what it does
+    if backend == Backend::Vm && (profile.is_some() || coverage.is_some() || trace.is_some())
+    {
+        usage();
+    }
+    if let Some(profile) = profile
+    {
+        lox.set_profile(profile);
+    }
+    if let Some(lcov_path) = coverage
+    {
+        lox.set_coverage(lcov_path);
+    }
+    if let Some(path) = trace
     {
-        1 => lox.run_prompt(),
-        2 => lox.run_file(&args[1]).expect("Couldn't run file"),
-        _ =>
+        let sink: Box<dyn Write> = match path
         {
-            println!("Usage: lox-ast [script]");
-            std::process::exit(64);
+            Some(path) =>
+            {
+                match File::create(&path)
+                {
+                    Ok(file) => Box::new(BufWriter::new(file)),
+                    Err(e) =>
+                    {
+                        eprintln!("Couldn't create the trace file {path}: {e}");
+                        std::process::exit(EX_IOERR);
+                    }
+                }
+            }
+            None => Box::new(stderr()),
+        };
+        lox.set_trace(sink, trace_format);
+    }
+    match script.as_deref()
+    {
+        None => lox.run_prompt(),
+        // `compile script [-o output]`
+        Some("compile") =>
+        {
+            let script = args.next().unwrap_or_else(|| usage());
+            let output = match (args.next().as_deref(), args.next())
+            {
+                (None, _) => Path::new(&script).with_extension("loxc"),
+                (Some("-o"), Some(output)) if args.next().is_none() => output.into(),
+                _ => usage(),
+            };
+            lox.compile_file(&script, &output)
+                .expect("Couldn't compile file");
+        }
+        // `disasm script`
+        Some("disasm") =>
+        {
+            let script = args.next().unwrap_or_else(|| usage());
+            lox.disassemble_file(&script)
+                .expect("Couldn't disassemble file");
+        }
+        // `run compiled [arguments...]`
+        Some("run") =>
+        {
+            let compiled = args.next().unwrap_or_else(|| usage());
+            lox.set_args(args.collect());
+            lox.run_compiled(&compiled).expect("Couldn't run file");
+        }
+        // `debug script [arguments...]`
+        Some("debug") =>
+        {
+            // Only the tree interpreter can be debugged
+            if backend == Backend::Vm
+            {
+                usage();
+            }
+            let script = args.next().unwrap_or_else(|| usage());
+            lox.set_args(args.collect());
+            lox.debug_file(&script).expect("Couldn't debug file");
+        }
+        Some(script) =>
+        {
+            lox.set_args(args.collect());
+            lox.run_file(&script.to_string())
+                .expect("Couldn't run file");
         }
     }
 }
+
+/// Parse the numeric value of an `--option=value` argument
+fn option_value(arg: &str, option: &str) -> u64
+{
+    arg[option.len()..].parse().unwrap_or_else(|_| usage())
+}
+
+fn usage() -> !
+{
+    println!(
+        "Usage: lox-ast [options] [script [arguments...]]
+       lox-ast [options] run compiled [arguments...]
+       lox-ast [options] compile script [-o output]
+       lox-ast [options] disasm script
+       lox-ast [options] debug script [arguments...]
+Options: [--backend=tree|vm] [-O] [--strict] [--sandbox] [--module-path=DIR] [--max-steps=N] \
+         [--timeout=MS] [--max-memory=BYTES] [--profile[=FOLDED_FILE]] [--coverage[=LCOV_FILE]] \
+         [--trace[=FILE]] [--trace-format=text|json]"
+    );
+    std::process::exit(64);
+}
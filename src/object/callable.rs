@@ -8,12 +8,11 @@ pub struct Callable
     pub func: Rc<dyn LoxCallable>,
 }
 
+/// Functions are equal only if they're the same function. Copies of a
+/// `Callable` share the function, so they compare equal.
 impl PartialEq for Callable
 {
-    fn eq(&self, other: &Self) -> bool
-    {
-        std::ptr::eq(&self.func, &other.func) && self.arity() == other.arity()
-    }
+    fn eq(&self, other: &Self) -> bool { Rc::ptr_eq(&self.func, &other.func) }
 }
 
 
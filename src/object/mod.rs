@@ -1,19 +1,26 @@
 pub mod callable;
+use crate::{
+    error::{LoxError, TypeError},
+    interpreter::module::Module,
+    vm::Closure,
+};
 use callable::*;
+use std::{cell::RefCell, rc::Rc};
 
 
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, Div, Mul, Neg, Sub};
 
 #[derive(Debug, PartialEq, Clone)]
-/// `Object` represents an object type in lox. There are four object variants
-/// (Number, String, Boolean, and Nil (NULL)) accompanied by two error types.
+/// `Object` represents an object type in lox. There are four primitive
+/// variants (Number, String, Boolean, and Nil (NULL)) accompanied by functions
+/// and error objects.
 pub enum Object
 {
     /// A number
     Num(f64),
 
     /// A string
-    Str(String),
+    Str(Rc<str>),
 
     /// A boolean value
     Bool(bool),
@@ -23,73 +30,161 @@ pub enum Object
 
     Func(Callable),
 
-    /// Tried to do an operation on incompatable types
-    ArithmeticError,
+    /// A function compiled for the bytecode VM
+    Closure(Rc<Closure>),
 
-    /// Tried to compare incomparable types
-    ComparisonError,
+    /// A list of values, as returned by natives such as `split`
+    List(Rc<RefCell<Vec<Object>>>),
+
+    /// A fixed set of named values
+    Record(Rc<Record>),
+
+    /// An imported module, whose definitions are read as properties
+    Module(Rc<Module>),
+
+    /// An error object, as bound by a `catch` clause
+    Error(Rc<LoxError>),
+
+    /// What a closure sees of a local variable declared after it, until the
+    /// declaration runs. Using it is an error, which names the variable.
+    Undefined(Rc<str>),
+}
+
+/// A fixed set of named values, for natives that return more than one value.
+/// The values are read as properties, e.g. `result.status`.
+#[derive(Debug, PartialEq)]
+pub struct Record
+{
+    fields: Vec<(String, Object)>,
+}
+
+impl Record
+{
+    pub fn new(fields: Vec<(&str, Object)>) -> Self
+    {
+        Self {
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        }
+    }
+
+    /// Look up the value of the field `name`
+    pub fn get(&self, name: &str) -> Option<Object>
+    {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+impl std::fmt::Display for Record
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"))
+            .collect();
+        write!(f, "{{{}}}", fields.join(", "))
+    }
 }
 
 
 impl Sub for Object
 {
-    type Output = Self;
+    type Output = Result<Self, TypeError>;
 
-    fn sub(self, other: Self) -> Self
+    fn sub(self, other: Self) -> Self::Output
     {
         match (self, other)
         {
-            (Object::Num(left), Object::Num(right)) => Self::Num(left - right),
+            (Object::Num(left), Object::Num(right)) => Ok(Self::Num(left - right)),
 
-            _ => Object::ArithmeticError,
+            (left, right) => Err(TypeError::operands("-", "numbers", &left, &right)),
         }
     }
 }
 
 impl Div for Object
 {
-    type Output = Self;
+    type Output = Result<Self, TypeError>;
 
-    fn div(self, other: Self) -> Self
+    fn div(self, other: Self) -> Self::Output
     {
         match (self, other)
         {
-            (Object::Num(left), Object::Num(right)) => Self::Num(left / right),
+            (Object::Num(left), Object::Num(right)) => Ok(Self::Num(left / right)),
 
-            _ => Object::ArithmeticError,
+            (left, right) => Err(TypeError::operands("/", "numbers", &left, &right)),
         }
     }
 }
 
 impl Mul for Object
 {
-    type Output = Self;
+    type Output = Result<Self, TypeError>;
 
-    fn mul(self, other: Self) -> Self
+    fn mul(self, other: Self) -> Self::Output
     {
         match (self, other)
         {
-            (Object::Num(left), Object::Num(right)) => Self::Num(left * right),
+            (Object::Num(left), Object::Num(right)) => Ok(Self::Num(left * right)),
 
-            _ => Object::ArithmeticError,
+            (left, right) => Err(TypeError::operands("*", "numbers", &left, &right)),
         }
     }
 }
 
 impl Add for Object
 {
-    type Output = Self;
+    type Output = Result<Self, TypeError>;
 
-    fn add(self, other: Self) -> Self
+    fn add(self, other: Self) -> Self::Output
     {
         match (self, other)
         {
-            (Object::Num(left), Object::Num(right)) => Self::Num(left + right),
-            (Object::Str(left), Object::Str(right)) => Self::Str(format!("{left}{right}")),
-            (Object::Num(left), Object::Str(right)) => Self::Str(format!("{left}{right}")),
-            (Object::Str(left), Object::Num(right)) => Self::Str(format!("{left}{right}")),
+            (Object::Num(left), Object::Num(right)) => Ok(Self::Num(left + right)),
+            (Object::Str(left), Object::Str(right)) =>
+            {
+                Ok(Self::Str(format!("{left}{right}").into()))
+            }
+            (Object::Num(left), Object::Str(right)) =>
+            {
+                Ok(Self::Str(format!("{left}{right}").into()))
+            }
+            (Object::Str(left), Object::Num(right)) =>
+            {
+                Ok(Self::Str(format!("{left}{right}").into()))
+            }
+
+            (left, right) =>
+            {
+                Err(TypeError::operands(
+                    "+",
+                    "two numbers or two strings",
+                    &left,
+                    &right,
+                ))
+            }
+        }
+    }
+}
+
+impl Neg for Object
+{
+    type Output = Result<Self, TypeError>;
+
+    fn neg(self) -> Self::Output
+    {
+        match self
+        {
+            Object::Num(right) => Ok(Self::Num(-right)),
 
-            _ => Object::ArithmeticError,
+            right => Err(TypeError::operand("-", "a number", &right)),
         }
     }
 }
@@ -121,83 +216,199 @@ impl std::fmt::Display for Object
             Self::Num(x) => write!(f, "{x}"),
             Self::Bool(x) => write!(f, "{x}"),
             Self::Str(x) => write!(f, "{x}"),
-            Self::ArithmeticError | Self::ComparisonError =>
-            {
-                panic!("Shouldn't be trying to print erronious Objects")
-            }
             Self::Func(x) => write!(f, "{x}"),
+            Self::List(x) => write_list(f, x, &mut Vec::new()),
+            Self::Record(x) => write!(f, "{x}"),
+            Self::Closure(x) => write!(f, "{x}"),
+            Self::Module(x) => write!(f, "{x}"),
+            Self::Error(x) => write!(f, "{x}"),
+            Self::Undefined(name) => write!(f, "<undefined {name}>"),
         }
     }
 }
 
+/// Write `list`, given the lists it's nested in. A list inside itself is
+/// written as `[...]`.
+fn write_list(
+    f: &mut std::fmt::Formatter,
+    list: &Rc<RefCell<Vec<Object>>>,
+    enclosing: &mut Vec<*const RefCell<Vec<Object>>>,
+) -> std::fmt::Result
+{
+    if enclosing.contains(&Rc::as_ptr(list))
+    {
+        return write!(f, "[...]");
+    }
+
+    enclosing.push(Rc::as_ptr(list));
+    write!(f, "[")?;
+    for (index, item) in list.borrow().iter().enumerate()
+    {
+        if index > 0
+        {
+            write!(f, ", ")?;
+        }
+        match item
+        {
+            Object::List(inner) => write_list(f, inner, enclosing)?,
+            other => write!(f, "{other}")?,
+        }
+    }
+    enclosing.pop();
+    write!(f, "]")
+}
+
 impl Object
 {
+    /// The name of this value's type, as shown to Lox programs
+    pub fn type_name(&self) -> &'static str
+    {
+        match self
+        {
+            Self::Num(_) => "number",
+            Self::Str(_) => "string",
+            Self::Bool(_) => "bool",
+            Self::Nil => "nil",
+            Self::Func(_) | Self::Closure(_) => "function",
+            Self::List(_) => "list",
+            Self::Record(_) => "record",
+            Self::Module(_) => "module",
+            Self::Error(_) => "error",
+            Self::Undefined(_) => "undefined",
+        }
+    }
+
+    /// The approximate number of bytes this value owns outside of the
+    /// `Object` itself, as counted against a script's memory limit. Only
+    /// strings and lists, with their items, are counted.
+    pub fn heap_size(&self) -> usize
+    {
+        match self
+        {
+            Self::Str(s) => s.len(),
+            Self::List(list) =>
+            {
+                let items = list.borrow();
+                items.len() * std::mem::size_of::<Object>()
+                    + items.iter().map(Object::heap_size).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Create a list holding `items`
+    pub fn list(items: Vec<Object>) -> Self { Self::List(Rc::new(RefCell::new(items))) }
+
+    /// Addition without the implicit number to string conversions done by `+`.
+    /// Only two numbers or two strings can be added.
+    pub fn add_strict(self, other: Object) -> Result<Self, TypeError>
+    {
+        match (self, other)
+        {
+            (Object::Num(left), Object::Num(right)) => Ok(Self::Num(left + right)),
+            (Object::Str(left), Object::Str(right)) =>
+            {
+                Ok(Self::Str(format!("{left}{right}").into()))
+            }
+
+            (left, right) =>
+            {
+                Err(TypeError::operands(
+                    "+",
+                    "two numbers or two strings",
+                    &left,
+                    &right,
+                ))
+            }
+        }
+    }
+
     /// Test if `self` is greater-than `right`
-    pub fn greater(&self, right: Object) -> Self
+    pub fn greater(&self, right: Object) -> Result<Self, TypeError>
     {
         match (self, right)
         {
-            (Self::Num(left), Self::Num(right)) => Self::Bool(*left > right),
-            _ => Self::ComparisonError,
+            (Self::Num(left), Self::Num(right)) => Ok(Self::Bool(*left > right)),
+            (left, right) => Err(TypeError::operands(">", "numbers", left, &right)),
         }
     }
 
     /// Test if `self` is greater-than, or equal-to `right`
-    pub fn greater_eq(&self, right: Object) -> Self
+    pub fn greater_eq(&self, right: Object) -> Result<Self, TypeError>
     {
         match (self, right)
         {
-            (Self::Num(left), Self::Num(right)) => Self::Bool(*left >= right),
-            _ => Self::ComparisonError,
+            (Self::Num(left), Self::Num(right)) => Ok(Self::Bool(*left >= right)),
+            (left, right) => Err(TypeError::operands(">=", "numbers", left, &right)),
         }
     }
 
     /// Test if `self` is less-than `right`
-    pub fn less(&self, right: Object) -> Self
+    pub fn less(&self, right: Object) -> Result<Self, TypeError>
     {
         match (self, right)
         {
-            (Self::Num(left), Self::Num(right)) => Self::Bool(*left < right),
-            _ => Self::ComparisonError,
+            (Self::Num(left), Self::Num(right)) => Ok(Self::Bool(*left < right)),
+            (left, right) => Err(TypeError::operands("<", "numbers", left, &right)),
         }
     }
 
     /// Test if `self` is less-than, or equal-to `right`
-    pub fn less_eq(&self, right: Object) -> Self
+    pub fn less_eq(&self, right: Object) -> Result<Self, TypeError>
     {
         match (self, right)
         {
-            (Self::Num(left), Self::Num(right)) => Self::Bool(*left <= right),
-            _ => Self::ComparisonError,
+            (Self::Num(left), Self::Num(right)) => Ok(Self::Bool(*left <= right)),
+            (left, right) => Err(TypeError::operands("<=", "numbers", left, &right)),
         }
     }
 
     /// Test if `self` and `right` are equal
-    pub fn eq(&self, right: Object) -> Self
+    pub fn eq(&self, right: Object) -> Self { Self::Bool(self.equals(&right)) }
+
+    /// Test if `self` and `right` aren't equal
+    pub fn neq(&self, right: Object) -> Self { Self::Bool(!self.equals(&right)) }
+
+    /// Lox equality. Values of differing types are never equal, all other
+    /// values than numbers, strings, booleans and nil are compared by
+    /// identity.
+    pub fn equals(&self, right: &Object) -> bool
     {
         match (self, right)
         {
-            (Self::Num(left), Self::Num(right)) => Self::Bool(*left == right),
-            (Self::Str(left), Self::Str(right)) => Self::Bool(*left == right),
-            (Self::Bool(left), Self::Bool(right)) => Self::Bool(*left == right),
-            (Self::Nil, Self::Nil) => Self::Bool(true),
-            (Self::Nil, _) | (_, Self::Nil) => Self::Bool(false),
-
-            _ => Self::ComparisonError,
+            (Self::Num(left), Self::Num(right)) => left == right,
+            (Self::Str(left), Self::Str(right)) => left == right,
+            (Self::Bool(left), Self::Bool(right)) => left == right,
+            (Self::Nil, Self::Nil) => true,
+            (Self::Func(left), Self::Func(right)) => left == right,
+            (Self::List(left), Self::List(right)) => Rc::ptr_eq(left, right),
+            (Self::Record(left), Self::Record(right)) => Rc::ptr_eq(left, right),
+            (Self::Closure(left), Self::Closure(right)) => Rc::ptr_eq(left, right),
+            (Self::Module(left), Self::Module(right)) => Rc::ptr_eq(left, right),
+            (Self::Error(left), Self::Error(right)) => Rc::ptr_eq(left, right),
+            _ => false,
         }
     }
+}
 
-    /// Test if `self` and `right` aren't equal
-    pub fn neq(&self, right: Object) -> Self
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_list_inside_itself()
     {
-        match (self, right)
+        let list = Object::list(vec![Object::Num(1.0)]);
+        let Object::List(items) = &list
+        else
         {
-            (Self::Num(left), Self::Num(right)) => Self::Bool(*left != right),
-            (Self::Str(left), Self::Str(right)) => Self::Bool(*left != right),
-            (Self::Bool(left), Self::Bool(right)) => Self::Bool(*left != right),
-            (Self::Nil, Self::Nil) => Self::Bool(false),
-            (Self::Nil, _) | (_, Self::Nil) => Self::Bool(true),
-            _ => Self::ComparisonError,
-        }
+            unreachable!()
+        };
+        items.borrow_mut().push(Object::list(vec![list.clone()]));
+        assert_eq!(list.to_string(), "[1, [[...]]]");
+
+        // Breaks the cycle, so the list is freed
+        items.borrow_mut().clear();
     }
 }
@@ -0,0 +1,430 @@
+use std::rc::Rc;
+
+use crate::error::*;
+use crate::expr::*;
+use crate::interpreter::Interpreter;
+use crate::object::*;
+use crate::stmt::*;
+use crate::tokens::*;
+
+/// An optional pass over the syntax tree, run between parsing and
+/// interpreting. It folds operations on literals into a single literal,
+/// removes `if` branches that can never run and drops groupings, which only
+/// matter to the parser.
+///
+/// Operations are folded with the interpreter's own rules. Those that fail are
+/// left in the tree, so the error is still raised when, and if, they run.
+pub struct Optimizer<'a>
+{
+    interpreter: &'a Interpreter,
+}
+
+impl<'a> Optimizer<'a>
+{
+    pub fn new(interpreter: &'a Interpreter) -> Self { Self { interpreter } }
+
+    /// Optimize a program, returning the new tree
+    pub fn optimize(&self, statements: &[Stmt]) -> Result<Vec<Stmt>, LoxResult>
+    {
+        let mut optimized = Vec::with_capacity(statements.len());
+        for statement in statements
+        {
+            optimized.extend(statement.accept(self)?);
+        }
+        Ok(optimized)
+    }
+
+    /// Optimize a statement that's required by its parent, replacing one that
+    /// was removed by an empty block
+    fn required(&self, stmt: &Stmt) -> Result<Box<Stmt>, LoxResult>
+    {
+        let optimized = stmt.accept(self)?;
+        Ok(Box::new(optimized.unwrap_or_else(|| {
+            Stmt::Block(BlockStmt {
+                statements: Vec::new(),
+                line: stmt.line(),
+            })
+        })))
+    }
+
+    fn expr(&self, expr: &Expr) -> Result<Expr, LoxResult> { expr.accept(self) }
+
+    fn boxed(&self, expr: &Expr) -> Result<Box<Expr>, LoxResult> { Ok(Box::new(self.expr(expr)?)) }
+}
+
+fn literal(value: Object) -> Expr { Expr::Literal(LiteralExpr { value: Some(value) }) }
+
+/// The value of an expression, if it's a literal
+fn value(expr: &Expr) -> Option<&Object>
+{
+    match expr
+    {
+        Expr::Literal(LiteralExpr { value }) => value.as_ref(),
+        _ => None,
+    }
+}
+
+impl StmtVisitor<Option<Stmt>> for Optimizer<'_>
+{
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Block(BlockStmt {
+            statements: self.optimize(&stmt.statements)?,
+            line: stmt.line,
+        })))
+    }
+
+    fn visit_break_stmt(&self, stmt: &BreakStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Break(BreakStmt {
+            token: stmt.token.clone(),
+        })))
+    }
+
+    fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Continue(ContinueStmt {
+            token: stmt.token.clone(),
+        })))
+    }
+
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Expression(ExpressionStmt {
+            expression: self.expr(&stmt.expression)?,
+            line: stmt.line,
+        })))
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Function(FunctionStmt {
+            name: stmt.name.clone(),
+            params: Rc::clone(&stmt.params),
+            body: Rc::new(self.optimize(&stmt.body)?),
+            variable: stmt.variable.clone(),
+            layout: Rc::clone(&stmt.layout),
+        })))
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        let condition = self.expr(&stmt.condition)?;
+
+        // Only the branch that will be taken is kept
+        if let Some(value) = value(&condition)
+        {
+            return match (self.interpreter.is_truthy(value), &stmt.else_branch)
+            {
+                (true, _) => stmt.then_branch.accept(self),
+                (false, Some(else_branch)) => else_branch.accept(self),
+                (false, None) => Ok(None),
+            };
+        }
+
+        let else_branch = match &stmt.else_branch
+        {
+            Some(else_branch) => else_branch.accept(self)?.map(Box::new),
+            None => None,
+        };
+        Ok(Some(Stmt::If(IfStmt {
+            condition,
+            then_branch: self.required(&stmt.then_branch)?,
+            else_branch,
+            line: stmt.line,
+        })))
+    }
+
+    fn visit_import_stmt(&self, stmt: &ImportStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Import(ImportStmt {
+            keyword: stmt.keyword.clone(),
+            path: stmt.path.clone(),
+            alias: stmt.alias.clone(),
+            names: stmt.names.clone(),
+            variables: stmt.variables.clone(),
+        })))
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Print(PrintStmt {
+            expression: self.expr(&stmt.expression)?,
+            line: stmt.line,
+        })))
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Return(ReturnStmt {
+            keyword: stmt.keyword.clone(),
+            value: stmt
+                .value
+                .as_ref()
+                .map(|value| self.expr(value))
+                .transpose()?,
+        })))
+    }
+
+    fn visit_throw_stmt(&self, stmt: &ThrowStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Throw(ThrowStmt {
+            keyword: stmt.keyword.clone(),
+            value: self.expr(&stmt.value)?,
+        })))
+    }
+
+    fn visit_try_stmt(&self, stmt: &TryStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        let catch_body = match &stmt.catch_body
+        {
+            Some(body) => Some(self.optimize(body)?),
+            None => None,
+        };
+        let finally_body = match &stmt.finally_body
+        {
+            Some(body) => Some(Rc::new(self.optimize(body)?)),
+            None => None,
+        };
+
+        Ok(Some(Stmt::Try(TryStmt {
+            keyword: stmt.keyword.clone(),
+            body: self.optimize(&stmt.body)?,
+            catch_name: stmt.catch_name.clone(),
+            catch_body,
+            finally_body,
+            catch_variable: stmt.catch_variable.clone(),
+        })))
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::Var(VarStmt {
+            name: stmt.name.clone(),
+            initializer: stmt
+                .initializer
+                .as_ref()
+                .map(|value| self.expr(value))
+                .transpose()?,
+            variable: stmt.variable.clone(),
+        })))
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<Option<Stmt>, LoxResult>
+    {
+        Ok(Some(Stmt::While(WhileStmt {
+            condition: self.expr(&stmt.condition)?,
+            body: self.required(&stmt.body)?,
+            increment: stmt
+                .increment
+                .as_ref()
+                .map(|value| self.expr(value))
+                .transpose()?,
+            line: stmt.line,
+        })))
+    }
+}
+
+impl ExprVisitor<Expr> for Optimizer<'_>
+{
+    fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<Expr, LoxResult>
+    {
+        Ok(Expr::Assign(AssignExpr {
+            name: expr.name.clone(),
+            value: self.boxed(&expr.value)?,
+            variable: expr.variable.clone(),
+        }))
+    }
+
+    fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<Expr, LoxResult>
+    {
+        let left = self.expr(&expr.left)?;
+        let right = self.expr(&expr.right)?;
+
+        if let (Some(a), Some(b)) = (value(&left), value(&right))
+        {
+            let folded = self
+                .interpreter
+                .binary(expr.operator.token_type(), a.clone(), b.clone());
+            if let Ok(value) = folded
+            {
+                return Ok(literal(value));
+            }
+        }
+
+        Ok(Expr::Binary(BinaryExpr {
+            left: Box::new(left),
+            operator: expr.operator.clone(),
+            right: Box::new(right),
+        }))
+    }
+
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<Expr, LoxResult>
+    {
+        Ok(Expr::Call(CallExpr {
+            callee: Rc::new(self.expr(&expr.callee)?),
+            paren: expr.paren.clone(),
+            arguments: expr
+                .arguments
+                .iter()
+                .map(|argument| self.expr(argument))
+                .collect::<Result<_, _>>()?,
+        }))
+    }
+
+    fn visit_get_expr(&self, expr: &GetExpr) -> Result<Expr, LoxResult>
+    {
+        Ok(Expr::Get(GetExpr {
+            object: self.boxed(&expr.object)?,
+            name: expr.name.clone(),
+        }))
+    }
+
+    fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<Expr, LoxResult>
+    {
+        // The tree already reflects the grouping
+        self.expr(&expr.expression)
+    }
+
+    fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<Expr, LoxResult>
+    {
+        Ok(Expr::Literal(LiteralExpr {
+            value: expr.value.clone(),
+        }))
+    }
+
+    fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<Expr, LoxResult>
+    {
+        let left = self.expr(&expr.left)?;
+        let right = self.expr(&expr.right)?;
+
+        // A literal on the left decides which side is the result
+        if let Some(value) = value(&left)
+        {
+            let short_circuits =
+                self.interpreter.is_truthy(value) == expr.operator.is(TokenType::Or);
+            return Ok(if short_circuits { left } else { right });
+        }
+
+        Ok(Expr::Logical(LogicalExpr {
+            left: Box::new(left),
+            operator: expr.operator.clone(),
+            right: Box::new(right),
+        }))
+    }
+
+    fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<Expr, LoxResult>
+    {
+        let right = self.expr(&expr.right)?;
+
+        if let Some(value) = value(&right)
+        {
+            match expr.operator.token_type()
+            {
+                TokenType::Bang =>
+                {
+                    return Ok(literal(Object::Bool(!self.interpreter.is_truthy(value))))
+                }
+                TokenType::Minus =>
+                {
+                    if let Ok(value) = -value.clone()
+                    {
+                        return Ok(literal(value));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Expr::Unary(UnaryExpr {
+            operator: expr.operator.clone(),
+            right: Box::new(right),
+        }))
+    }
+
+    fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<Expr, LoxResult>
+    {
+        Ok(Expr::Variable(VariableExpr {
+            name: expr.name.clone(),
+            variable: expr.variable.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::tests::parse;
+
+    fn optimize(interpreter: &Interpreter, source: &str) -> Vec<Stmt>
+    {
+        Optimizer::new(interpreter)
+            .optimize(&parse(source))
+            .unwrap()
+    }
+
+    /// The value printed by a single `print` statement, if it was folded
+    fn printed(statements: &[Stmt]) -> Option<&Object>
+    {
+        match statements
+        {
+            [Stmt::Print(PrintStmt { expression, .. })] => value(expression),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_constant_folding()
+    {
+        let i = Interpreter::new();
+        let cases = [
+            ("print 2 * 3 + 1;", Object::Num(7.0)),
+            ("print -(2 * (3 + 1));", Object::Num(-8.0)),
+            ("print \"a\" + \"b\";", Object::Str("ab".into())),
+            ("print !(1 < 2) == false;", Object::Bool(true)),
+            ("print nil or \"x\";", Object::Str("x".into())),
+            ("print false and 1 / 0;", Object::Bool(false)),
+        ];
+
+        for (source, expected) in cases
+        {
+            assert_eq!(printed(&optimize(&i, source)), Some(&expected), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_errors_are_not_folded()
+    {
+        let i = Interpreter::new();
+        assert_eq!(printed(&optimize(&i, "print 1 - \"x\";")), None);
+        assert_eq!(printed(&optimize(&i, "print -\"x\";")), None);
+        assert_eq!(
+            printed(&optimize(&i, "print 1 + \"a\";")),
+            Some(&Object::Str("1a".into()))
+        );
+
+        i.set_strict(true);
+        assert_eq!(printed(&optimize(&i, "print 1 + \"a\";")), None);
+    }
+
+    #[test]
+    fn test_dead_branches()
+    {
+        let i = Interpreter::new();
+        assert!(optimize(&i, "if (false) print 1;").is_empty());
+        assert_eq!(
+            printed(&optimize(&i, "if (nil) print 1; else print 2;")),
+            Some(&Object::Num(2.0))
+        );
+        assert_eq!(
+            printed(&optimize(&i, "if (1 < 2) print 1;")),
+            Some(&Object::Num(1.0))
+        );
+        assert!(matches!(
+            optimize(&i, "while (true) if (false) break;")[..],
+            [Stmt::While(WhileStmt { ref body, .. })]
+                if matches!(**body, Stmt::Block(ref block) if block.statements.is_empty())
+        ));
+    }
+}
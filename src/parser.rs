@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{cell::Cell, rc::Rc};
 
 use crate::error::*;
 use crate::expr::*;
@@ -63,6 +63,7 @@ impl<'a> Parser<'a>
                 return Ok(Expr::Assign(AssignExpr {
                     name: expr.name,
                     value: Box::new(value),
+                    variable: Cell::default(),
                 }));
             }
             self.error(&equals, "Invalid assignment target.".to_string())
@@ -117,6 +118,14 @@ impl<'a> Parser<'a>
         {
             self.var_declaration()
         }
+        else if self.is_match(&[TokenType::Import])
+        {
+            self.import_declaration()
+        }
+        else if self.is_match(&[TokenType::From])
+        {
+            self.selective_import_declaration()
+        }
         else
         {
             self.statement()
@@ -129,6 +138,47 @@ impl<'a> Parser<'a>
         res
     }
 
+    /// `import "path" as name;`
+    fn import_declaration(&mut self) -> Result<Stmt, LoxResult>
+    {
+        let keyword = self.previous().clone();
+        let path = self.consume(TokenType::String, "Expect module path after 'import'.")?;
+        self.consume(TokenType::As, "Expect 'as' after module path.")?;
+        let alias = self.consume(TokenType::Identifier, "Expect module name after 'as'.")?;
+        self.consume(TokenType::Semicolon, "Expect ';' after import.")?;
+
+        Ok(Stmt::Import(ImportStmt {
+            keyword,
+            path,
+            alias: Some(alias),
+            names: Vec::new(),
+            variables: vec![Cell::default()],
+        }))
+    }
+
+    /// `from "path" import name, ...;`
+    fn selective_import_declaration(&mut self) -> Result<Stmt, LoxResult>
+    {
+        let keyword = self.previous().clone();
+        let path = self.consume(TokenType::String, "Expect module path after 'from'.")?;
+        self.consume(TokenType::Import, "Expect 'import' after module path.")?;
+
+        let mut names = vec![self.consume(TokenType::Identifier, "Expect name to import.")?];
+        while self.is_match(&[TokenType::Comma])
+        {
+            names.push(self.consume(TokenType::Identifier, "Expect name to import.")?);
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after import.")?;
+
+        Ok(Stmt::Import(ImportStmt {
+            keyword,
+            path,
+            alias: None,
+            variables: vec![Cell::default(); names.len()],
+            names,
+        }))
+    }
+
     fn function(&mut self, kind: &str) -> Result<Stmt, LoxResult>
     {
         let name = self.consume(TokenType::Identifier, &format!("Expect {kind} name"))?;
@@ -171,6 +221,8 @@ impl<'a> Parser<'a>
             name,
             params: Rc::new(params),
             body,
+            variable: Cell::default(),
+            layout: Rc::default(),
         }))
     }
 
@@ -182,6 +234,12 @@ impl<'a> Parser<'a>
             self.consume(TokenType::Semicolon, "Expect ';' after break statement.")?;
             Ok(Stmt::Break(BreakStmt { token: peek }))
         }
+        else if self.is_match(&[TokenType::Continue])
+        {
+            let token = self.previous().clone();
+            self.consume(TokenType::Semicolon, "Expect ';' after continue statement.")?;
+            Ok(Stmt::Continue(ContinueStmt { token }))
+        }
         else if self.is_match(&[TokenType::If])
         {
             self.if_statement()
@@ -194,6 +252,14 @@ impl<'a> Parser<'a>
         {
             self.return_statement()
         }
+        else if self.is_match(&[TokenType::Throw])
+        {
+            self.throw_statement()
+        }
+        else if self.is_match(&[TokenType::Try])
+        {
+            self.try_statement()
+        }
         else if self.is_match(&[TokenType::While])
         {
             self.while_statement()
@@ -204,8 +270,10 @@ impl<'a> Parser<'a>
         }
         else if self.is_match(&[TokenType::LeftBrace])
         {
+            let line = self.previous().line;
             Ok(Stmt::Block(BlockStmt {
                 statements: self.block()?,
+                line,
             }))
         }
         else
@@ -216,6 +284,7 @@ impl<'a> Parser<'a>
 
     fn for_statement(&mut self) -> Result<Stmt, LoxResult>
     {
+        let line = self.previous().line;
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
         let initializer = if self.is_match(&[TokenType::Semicolon])
         {
@@ -251,21 +320,10 @@ impl<'a> Parser<'a>
         };
 
         self.consume(TokenType::RightParen, "Expect ')' after 'for' clauses")?;
-        let mut body = self.statement()?;
-
-        if let Some(increment) = increment
-        {
-            body = Stmt::Block(BlockStmt {
-                statements: vec![
-                    body,
-                    Stmt::Expression(ExpressionStmt {
-                        expression: increment,
-                    }),
-                ],
-            })
-        }
+        let body = self.statement()?;
 
-        body = Stmt::While(WhileStmt {
+        // The increment is kept apart from the body so that `continue` doesn't skip it
+        let mut body = Stmt::While(WhileStmt {
             condition: if let Some(cond) = condition
             {
                 cond
@@ -277,12 +335,15 @@ impl<'a> Parser<'a>
                 })
             },
             body: Box::new(body),
+            increment,
+            line,
         });
 
         if let Some(init) = initializer
         {
             body = Stmt::Block(BlockStmt {
                 statements: vec![init, body],
+                line,
             })
         }
 
@@ -291,6 +352,7 @@ impl<'a> Parser<'a>
 
     fn if_statement(&mut self) -> Result<Stmt, LoxResult>
     {
+        let line = self.previous().line;
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
 
         let condition = self.expression()?;
@@ -311,6 +373,7 @@ impl<'a> Parser<'a>
             condition,
             then_branch: Box::new(then_branch),
             else_branch,
+            line,
         }))
     }
 
@@ -330,9 +393,13 @@ impl<'a> Parser<'a>
 
     fn print_statement(&mut self) -> Result<Stmt, LoxResult>
     {
+        let line = self.previous().line;
         let value = self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Print(PrintStmt { expression: value }))
+        Ok(Stmt::Print(PrintStmt {
+            expression: value,
+            line,
+        }))
     }
 
     fn return_statement(&mut self) -> Result<Stmt, LoxResult>
@@ -352,6 +419,65 @@ impl<'a> Parser<'a>
         Ok(Stmt::Return(ReturnStmt { keyword, value }))
     }
 
+    fn throw_statement(&mut self) -> Result<Stmt, LoxResult>
+    {
+        let keyword = self.previous().clone();
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.")?;
+
+        Ok(Stmt::Throw(ThrowStmt { keyword, value }))
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt, LoxResult>
+    {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+
+        let (catch_name, catch_body) = if self.is_match(&[TokenType::Catch])
+        {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+            let name = self.consume(TokenType::Identifier, "Expect exception variable name.")?;
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after exception variable.",
+            )?;
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.")?;
+            (Some(name), Some(self.block()?))
+        }
+        else
+        {
+            (None, None)
+        };
+
+        let finally_body = if self.is_match(&[TokenType::Finally])
+        {
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.")?;
+            Some(Rc::new(self.block()?))
+        }
+        else
+        {
+            None
+        };
+
+        if catch_body.is_none() && finally_body.is_none()
+        {
+            return Err(self.error(
+                &self.peek().clone(),
+                "Expect 'catch' or 'finally' after try block.".to_string(),
+            ));
+        }
+
+        Ok(Stmt::Try(TryStmt {
+            keyword,
+            body,
+            catch_name,
+            catch_body,
+            finally_body,
+            catch_variable: Cell::default(),
+        }))
+    }
+
     fn var_declaration(&mut self) -> Result<Stmt, LoxResult>
     {
         let name = self.consume(TokenType::Identifier, "Expected variable name")?;
@@ -370,23 +496,37 @@ impl<'a> Parser<'a>
             "Expected ';' after variable decalaration.",
         )?;
 
-        Ok(Stmt::Var(VarStmt { name, initializer }))
+        Ok(Stmt::Var(VarStmt {
+            name,
+            initializer,
+            variable: Cell::default(),
+        }))
     }
 
     fn while_statement(&mut self) -> Result<Stmt, LoxResult>
     {
+        let line = self.previous().line;
         self.consume(TokenType::LeftParen, "Expect '(' after while.")?;
         let condition = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after while.")?;
         let body = Box::new(self.statement()?);
-        Ok(Stmt::While(WhileStmt { condition, body }))
+        Ok(Stmt::While(WhileStmt {
+            condition,
+            body,
+            increment: None,
+            line,
+        }))
     }
 
     fn expression_statement(&mut self) -> Result<Stmt, LoxResult>
     {
+        let line = self.peek().line;
         let expr = self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Expression(ExpressionStmt { expression: expr }))
+        Ok(Stmt::Expression(ExpressionStmt {
+            expression: expr,
+            line,
+        }))
     }
 
     /// The equality rule.
@@ -501,6 +641,15 @@ impl<'a> Parser<'a>
             {
                 expr = self.finish_call(&Rc::new(expr))?;
             }
+            else if self.is_match(&[TokenType::Dot])
+            {
+                let name =
+                    self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
+                expr = Expr::Get(GetExpr {
+                    object: Box::new(expr),
+                    name,
+                });
+            }
             else
             {
                 break;
@@ -593,6 +742,7 @@ impl<'a> Parser<'a>
         {
             Ok(Expr::Variable(VariableExpr {
                 name: self.previous().clone(),
+                variable: Cell::default(),
             }))
         }
         else
@@ -641,6 +791,10 @@ impl<'a> Parser<'a>
                     | TokenType::While
                     | TokenType::Print
                     | TokenType::Return
+                    | TokenType::Throw
+                    | TokenType::Try
+                    | TokenType::Import
+                    | TokenType::From
             )
             {
                 return;
@@ -690,3 +844,27 @@ impl<'a> Parser<'a>
 
     fn previous(&self) -> &Token { self.tokens.get(self.current - 1).unwrap() }
 }
+
+impl Stmt
+{
+    /// The line a statement starts on
+    pub fn line(&self) -> usize
+    {
+        match self
+        {
+            Stmt::Block(stmt) => stmt.line,
+            Stmt::Break(stmt) => stmt.token.line,
+            Stmt::Continue(stmt) => stmt.token.line,
+            Stmt::Expression(stmt) => stmt.line,
+            Stmt::Function(stmt) => stmt.name.line,
+            Stmt::If(stmt) => stmt.line,
+            Stmt::Import(stmt) => stmt.keyword.line,
+            Stmt::Print(stmt) => stmt.line,
+            Stmt::Return(stmt) => stmt.keyword.line,
+            Stmt::Throw(stmt) => stmt.keyword.line,
+            Stmt::Try(stmt) => stmt.keyword.line,
+            Stmt::Var(stmt) => stmt.name.line,
+            Stmt::While(stmt) => stmt.line,
+        }
+    }
+}
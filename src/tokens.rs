@@ -1,4 +1,5 @@
 use crate::object::Object;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
 pub enum TokenType
@@ -57,6 +58,14 @@ pub enum TokenType
     While,
     For,
     Break,
+    Continue,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Import,
+    From,
+    As,
 
     /// End of file
     Eof,
@@ -68,7 +77,7 @@ pub struct Token
 {
     /// The type of the token
     ttype: TokenType,
-    pub lexeme: String,
+    pub lexeme: Rc<str>,
     pub literal: Option<Object>,
 
     /// The line the token was found on
@@ -77,14 +86,19 @@ pub struct Token
 
 impl Token
 {
-    pub fn get_identifier(&self) -> String { self.lexeme.to_string() }
+    pub fn get_identifier(&self) -> Rc<str> { Rc::clone(&self.lexeme) }
 
     /// Create a new `Token`
-    pub fn new(ttype: TokenType, lexeme: String, literal: Option<Object>, line: usize) -> Self
+    pub fn new(
+        ttype: TokenType,
+        lexeme: impl Into<Rc<str>>,
+        literal: Option<Object>,
+        line: usize,
+    ) -> Self
     {
         Self {
             ttype,
-            lexeme,
+            lexeme: lexeme.into(),
             literal,
             line,
         }
@@ -98,7 +112,7 @@ impl Token
     {
         Token {
             ttype: TokenType::Eof,
-            lexeme: "".to_string(),
+            lexeme: "".into(),
             literal: None,
             line,
         }
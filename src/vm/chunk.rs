@@ -0,0 +1,195 @@
+use crate::object::Object;
+use std::rc::Rc;
+
+/// Defines `OpCode` along with the conversion from its byte representation,
+/// so the two can't get out of sync.
+macro_rules! opcodes {
+    ($($(#[$doc:meta])* $name:ident,)*) => {
+        /// A bytecode instruction. Operands follow the opcode in the chunk, and
+        /// are documented on each instruction. Two byte operands are big endian.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum OpCode
+        {
+            $($(#[$doc])* $name,)*
+        }
+
+        impl OpCode
+        {
+            /// Decode an opcode, returning `None` for bytes that aren't one
+            pub fn from_byte(byte: u8) -> Option<Self>
+            {
+                const OPCODES: &[OpCode] = &[$(OpCode::$name,)*];
+                OPCODES.get(byte as usize).copied()
+            }
+        }
+    };
+}
+
+opcodes! {
+    /// `index: u16`: push a constant
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    /// `slot: u8`: push a local variable of the current frame
+    GetLocal,
+    /// `slot: u8`: assign the top of the stack to a local variable
+    SetLocal,
+    /// `name: u16`
+    GetGlobal,
+    /// `name: u16`: pop a value into a new global variable
+    DefineGlobal,
+    /// `name: u16`
+    SetGlobal,
+    /// `index: u8`: push a variable captured by the current closure
+    GetUpvalue,
+    /// `index: u8`
+    SetUpvalue,
+    /// `name: u16`: replace an object with one of its properties
+    GetProperty,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// `offset: u16`: jump forwards
+    Jump,
+    /// `offset: u16`: jump forwards if the top of the stack is falsey, leaving
+    /// it on the stack
+    JumpIfFalse,
+    /// `offset: u16`: jump backwards
+    Loop,
+    /// `arguments: u8`: call the value below the arguments
+    Call,
+    /// `prototype: u16`, then an `is_local: u8` and `index: u8` pair for each
+    /// of the prototype's upvalues: push a new closure
+    Closure,
+    /// Pop a local variable, moving it into the closures that captured it
+    CloseUpvalue,
+    Return,
+    /// Pop a value and throw it
+    Throw,
+    /// `offset: u16`: handle catchable errors by jumping forwards, with the
+    /// error's value pushed
+    PushCatch,
+    /// `offset: u16`: handle any error by jumping forwards to a `finally`
+    /// block, holding the error until `Rethrow`
+    PushFinally,
+    /// Remove the innermost error handler
+    PopHandler,
+    /// Throw the error held by the innermost `finally` block again
+    Rethrow,
+    /// Discard the error held by the innermost `finally` block, when the block
+    /// jumps out instead of finishing
+    DropError,
+    /// `path: u16`: push a module, loading it if needed
+    Import,
+    /// `path: u16`, `name: u16`: replace a module with one of its definitions
+    ImportName,
+    /// `name: u16`: push the placeholder for a local variable that isn't
+    /// defined yet, which closures declared before it see until it is
+    Undefined,
+}
+
+/// A sequence of bytecode along with the data it refers to
+#[derive(Debug, Default)]
+pub struct Chunk
+{
+    pub code: Vec<u8>,
+
+    /// The source line of each byte of code
+    pub lines: Vec<usize>,
+
+    /// The literals, and the names of variables and properties, referred to by
+    /// the code
+    pub constants: Vec<Object>,
+
+    /// The functions declared in the code
+    pub prototypes: Vec<Rc<Prototype>>,
+}
+
+impl Chunk
+{
+    pub fn write(&mut self, byte: u8, line: usize)
+    {
+        self.code.push(byte);
+        self.lines.push(line);
+    }
+
+    /// Read a two byte operand at `offset`
+    pub fn read_u16(&self, offset: usize) -> u16
+    {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Add a constant, reusing an identical one if there is one, and return
+    /// its index
+    pub fn add_constant(&mut self, value: Object) -> usize
+    {
+        let existing = self.constants.iter().position(|constant| {
+            // `equals` treats `0` and `-0` as the same number
+            match (constant, &value)
+            {
+                (Object::Num(a), Object::Num(b)) => a.to_bits() == b.to_bits(),
+                (a, b) => a.equals(b),
+            }
+        });
+
+        existing.unwrap_or_else(|| {
+            self.constants.push(value);
+            self.constants.len() - 1
+        })
+    }
+}
+
+/// A compiled function, from which closures are created at runtime
+#[derive(Debug, Default)]
+pub struct Prototype
+{
+    pub name: String,
+    pub arity: usize,
+
+    /// The number of variables captured from enclosing functions
+    pub upvalues: usize,
+
+    pub chunk: Chunk,
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_opcode_round_trip()
+    {
+        for byte in 0..=u8::MAX
+        {
+            if let Some(op) = OpCode::from_byte(byte)
+            {
+                assert_eq!(op as u8, byte);
+            }
+        }
+        assert_eq!(OpCode::from_byte(OpCode::Undefined as u8 + 1), None);
+    }
+
+    #[test]
+    fn test_constants_are_reused()
+    {
+        let mut chunk = Chunk::default();
+        let a = chunk.add_constant(Object::Str("a".into()));
+        chunk.add_constant(Object::Num(0.0));
+
+        assert_eq!(chunk.add_constant(Object::Str("a".into())), a);
+        assert_eq!(chunk.add_constant(Object::Num(-0.0)), 2);
+    }
+}
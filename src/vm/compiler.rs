@@ -0,0 +1,989 @@
+//! Compiles the syntax tree to bytecode for the VM. Local variables are
+//! resolved to stack slots and upvalues here, so only globals are looked up by
+//! name at runtime.
+use super::chunk::{OpCode, Prototype};
+use crate::{
+    error::LoxResult,
+    expr::*,
+    object::Object,
+    stmt::*,
+    tokens::{Token, TokenType},
+};
+use std::{cell::RefCell, rc::Rc};
+
+/// A local variable, living in a stack slot of its function's frame
+struct Local
+{
+    name: Rc<str>,
+    depth: usize,
+
+    /// Whether a closure has captured the variable, in which case it has to
+    /// be moved off the stack when it goes out of scope
+    captured: bool,
+
+    /// Variables and functions get their slot when their block starts, but
+    /// are only declared once their declaration is reached. Until then only
+    /// the functions declared before them can refer to them.
+    declared: bool,
+}
+
+/// A variable captured by a function, either from the enclosing function's
+/// locals or from the enclosing function's own upvalues
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue
+{
+    index: u8,
+    is_local: bool,
+}
+
+/// A loop being compiled, for `break` and `continue`
+struct Loop
+{
+    scope_depth: usize,
+
+    /// The number of `try` statements enclosing the loop
+    try_depth: usize,
+
+    /// Jumps to patch to the end of the loop
+    breaks: Vec<usize>,
+
+    /// Jumps to patch to the loop's increment
+    continues: Vec<usize>,
+}
+
+/// A `try` statement enclosing the code being compiled, which has to be
+/// cleaned up after by anything jumping out of it
+enum TryRegion
+{
+    /// The `try` or `catch` block, with `handlers` installed and a `finally`
+    /// block to run on the way out
+    Protected
+    {
+        handlers: usize,
+        finally: Option<Rc<Vec<Stmt>>>,
+    },
+
+    /// A `finally` block run because of an error, which is held until the
+    /// block finishes
+    Finally,
+}
+
+/// The function being compiled, along with everything needed to resolve its
+/// variables
+struct FunctionState
+{
+    prototype: Prototype,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    tries: Vec<TryRegion>,
+}
+
+impl FunctionState
+{
+    fn new(name: String, arity: usize) -> Self
+    {
+        Self {
+            prototype: Prototype {
+                name,
+                arity,
+                ..Prototype::default()
+            },
+            // The first slot holds the function being called
+            locals: vec![Local {
+                name: "".into(),
+                depth: 0,
+                captured: false,
+                declared: true,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            tries: Vec::new(),
+        }
+    }
+}
+
+/// Compiles a program to a prototype for the VM
+pub struct Compiler
+{
+    /// The function being compiled, and the functions enclosing it
+    functions: RefCell<Vec<FunctionState>>,
+
+    /// The line of the last token seen, which emitted code is attributed to
+    line: RefCell<usize>,
+}
+
+impl Compiler
+{
+    /// Compile a program, returning the prototype of its top level script
+    pub fn compile(statements: &[Stmt]) -> Result<Rc<Prototype>, LoxResult>
+    {
+        let compiler = Self {
+            functions: RefCell::new(vec![FunctionState::new("script".to_string(), 0)]),
+            line: RefCell::new(1),
+        };
+
+        for statement in statements
+        {
+            compiler.statement(statement)?;
+        }
+        compiler.emit_op(OpCode::Nil);
+        compiler.emit_op(OpCode::Return);
+
+        let script = compiler.functions.into_inner().pop().unwrap();
+        Ok(Rc::new(script.prototype))
+    }
+
+    fn statement(&self, stmt: &Stmt) -> Result<(), LoxResult> { stmt.accept(self) }
+
+    fn expression(&self, expr: &Expr) -> Result<(), LoxResult> { expr.accept(self) }
+
+    /// Attribute the code emitted next to `token`'s line
+    fn at(&self, token: &Token) { *self.line.borrow_mut() = token.line; }
+
+    fn error(&self, message: &str) -> LoxResult { LoxResult::error(*self.line.borrow(), message) }
+
+    /// Run `f` on the function being compiled
+    fn current<T>(&self, f: impl FnOnce(&mut FunctionState) -> T) -> T
+    {
+        f(self.functions.borrow_mut().last_mut().unwrap())
+    }
+
+    fn code_len(&self) -> usize { self.current(|f| f.prototype.chunk.code.len()) }
+
+    fn emit(&self, byte: u8)
+    {
+        let line = *self.line.borrow();
+        self.current(|f| f.prototype.chunk.write(byte, line));
+    }
+
+    fn emit_op(&self, op: OpCode) { self.emit(op as u8); }
+
+    fn emit_u16(&self, operand: u16)
+    {
+        for byte in operand.to_be_bytes()
+        {
+            self.emit(byte);
+        }
+    }
+
+    /// Add a constant to the current chunk, returning its index
+    fn constant(&self, value: Object) -> Result<u16, LoxResult>
+    {
+        let index = self.current(|f| f.prototype.chunk.add_constant(value));
+        u16::try_from(index).map_err(|_| self.error("Too many constants in one chunk."))
+    }
+
+    /// The constant holding an identifier's name
+    fn name(&self, name: &Token) -> Result<u16, LoxResult>
+    {
+        self.constant(Object::Str(name.lexeme.clone()))
+    }
+
+    /// Emit a jump with a placeholder offset, returning the offset's position
+    /// for `patch_jump`
+    fn emit_jump(&self, op: OpCode) -> usize
+    {
+        self.emit_op(op);
+        self.emit_u16(u16::MAX);
+        self.code_len() - 2
+    }
+
+    /// Point the jump at `operand` to the next instruction
+    fn patch_jump(&self, operand: usize) -> Result<(), LoxResult>
+    {
+        let jump = self.code_len() - operand - 2;
+        let jump = u16::try_from(jump).map_err(|_| self.error("Too much code to jump over."))?;
+        self.current(|f| {
+            f.prototype.chunk.code[operand..operand + 2].copy_from_slice(&jump.to_be_bytes())
+        });
+        Ok(())
+    }
+
+    /// Emit a jump backwards to `start`
+    fn emit_loop(&self, start: usize) -> Result<(), LoxResult>
+    {
+        self.emit_op(OpCode::Loop);
+        let jump = self.code_len() - start + 2;
+        let jump = u16::try_from(jump).map_err(|_| self.error("Loop body too large."))?;
+        self.emit_u16(jump);
+        Ok(())
+    }
+
+    fn begin_scope(&self) { self.current(|f| f.scope_depth += 1); }
+
+    fn end_scope(&self)
+    {
+        self.current(|f| f.scope_depth -= 1);
+        let depth = self.current(|f| f.scope_depth);
+        self.discard_locals(depth);
+        self.current(|f| f.locals.retain(|local| local.depth <= depth));
+    }
+
+    /// Emit code popping the locals deeper than `depth`, without forgetting
+    /// them. Used when jumping out of their scope.
+    fn discard_locals(&self, depth: usize)
+    {
+        let captured: Vec<bool> = self.current(|f| {
+            f.locals
+                .iter()
+                .rev()
+                .take_while(|local| local.depth > depth)
+                .map(|local| local.captured)
+                .collect()
+        });
+
+        for captured in captured
+        {
+            self.emit_op(
+                if captured
+                {
+                    OpCode::CloseUpvalue
+                }
+                else
+                {
+                    OpCode::Pop
+                },
+            );
+        }
+    }
+
+    /// Add a local for the value on top of the stack
+    fn declare_local(&self, name: &Token) -> Result<(), LoxResult>
+    {
+        self.add_local(name.get_identifier(), name.line, true)
+    }
+
+    /// Add a local declared on `line`
+    fn add_local(&self, name: Rc<str>, line: usize, declared: bool) -> Result<(), LoxResult>
+    {
+        if self.current(|f| f.locals.len()) > u8::MAX as usize
+        {
+            return Err(LoxResult::error(
+                line,
+                "Too many local variables in function.",
+            ));
+        }
+
+        self.current(|f| {
+            let depth = f.scope_depth;
+            f.locals.push(Local {
+                name,
+                depth,
+                captured: false,
+                declared,
+            })
+        });
+        Ok(())
+    }
+
+    /// Give each variable and function declared directly in `statements` a
+    /// slot, holding a placeholder until its declaration is reached, so the
+    /// functions declared before them can refer to them
+    fn hoist(&self, statements: &[Stmt]) -> Result<(), LoxResult>
+    {
+        for statement in statements
+        {
+            let name = match statement
+            {
+                Stmt::Var(stmt) => &stmt.name,
+                Stmt::Function(stmt) => &stmt.name,
+                _ => continue,
+            };
+            let constant = self.name(name)?;
+            self.emit_op(OpCode::Undefined);
+            self.emit_u16(constant);
+            self.add_local(name.get_identifier(), name.line, false)?;
+        }
+        Ok(())
+    }
+
+    /// Declare the variable `name` in the slot it was given when its block
+    /// started, returning the slot, if it has one
+    fn declare_hoisted(&self, name: &Token) -> Option<u8>
+    {
+        self.current(|f| {
+            let depth = f.scope_depth;
+            let slot = f.locals.iter().position(|local| {
+                !local.declared && local.depth == depth && local.name == name.lexeme
+            })?;
+            f.locals[slot].declared = true;
+            Some(slot as u8)
+        })
+    }
+
+    /// Move the value on top of the stack into a local's slot
+    fn store_local(&self, slot: u8)
+    {
+        self.emit_op(OpCode::SetLocal);
+        self.emit(slot);
+        self.emit_op(OpCode::Pop);
+    }
+
+    /// Store the value on top of the stack in a new variable: a local inside
+    /// a scope, and a global otherwise
+    fn define_variable(&self, name: &Token) -> Result<(), LoxResult>
+    {
+        if self.current(|f| f.scope_depth) > 0
+        {
+            match self.declare_hoisted(name)
+            {
+                Some(slot) =>
+                {
+                    self.store_local(slot);
+                    Ok(())
+                }
+                None => self.declare_local(name),
+            }
+        }
+        else
+        {
+            let name = self.name(name)?;
+            self.emit_op(OpCode::DefineGlobal);
+            self.emit_u16(name);
+            Ok(())
+        }
+    }
+
+    /// The slot of the local `name` in the function at `function`. Locals
+    /// that aren't declared yet are only found when `captured`, as the
+    /// functions declared before them can refer to them.
+    fn resolve_local(&self, function: usize, name: &str, captured: bool) -> Option<u8>
+    {
+        self.functions.borrow()[function]
+            .locals
+            .iter()
+            .rposition(|local| *local.name == *name && (local.declared || captured))
+            .map(|slot| slot as u8)
+    }
+
+    /// The index of the upvalue capturing `name` in the function at
+    /// `function`, adding one if the variable belongs to an enclosing function
+    fn resolve_upvalue(&self, function: usize, name: &str) -> Result<Option<u8>, LoxResult>
+    {
+        if function == 0
+        {
+            return Ok(None);
+        }
+
+        if let Some(slot) = self.resolve_local(function - 1, name, true)
+        {
+            self.functions.borrow_mut()[function - 1].locals[slot as usize].captured = true;
+            return self.add_upvalue(function, slot, true).map(Some);
+        }
+
+        match self.resolve_upvalue(function - 1, name)?
+        {
+            Some(index) => self.add_upvalue(function, index, false).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn add_upvalue(&self, function: usize, index: u8, is_local: bool) -> Result<u8, LoxResult>
+    {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.functions.borrow_mut()[function].upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue)
+        {
+            return Ok(existing as u8);
+        }
+        if upvalues.len() > u8::MAX as usize
+        {
+            return Err(self.error("Too many closure variables in function."));
+        }
+
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    /// Emit code to get a variable, or to set it to the value on top of the
+    /// stack
+    fn variable(&self, name: &Token, set: bool) -> Result<(), LoxResult>
+    {
+        self.at(name);
+        let function = self.functions.borrow().len() - 1;
+
+        if let Some(slot) = self.resolve_local(function, &name.lexeme, false)
+        {
+            self.emit_op(
+                if set
+                {
+                    OpCode::SetLocal
+                }
+                else
+                {
+                    OpCode::GetLocal
+                },
+            );
+            self.emit(slot);
+        }
+        else if let Some(index) = self.resolve_upvalue(function, &name.lexeme)?
+        {
+            self.emit_op(
+                if set
+                {
+                    OpCode::SetUpvalue
+                }
+                else
+                {
+                    OpCode::GetUpvalue
+                },
+            );
+            self.emit(index);
+        }
+        else
+        {
+            let name = self.name(name)?;
+            self.emit_op(
+                if set
+                {
+                    OpCode::SetGlobal
+                }
+                else
+                {
+                    OpCode::GetGlobal
+                },
+            );
+            self.emit_u16(name);
+        }
+        Ok(())
+    }
+
+    /// Compile statements in a new scope
+    fn block(&self, statements: &[Stmt]) -> Result<(), LoxResult>
+    {
+        self.begin_scope();
+        self.hoist(statements)?;
+        for statement in statements
+        {
+            self.statement(statement)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    /// Emit the clean up for jumping out of the `try` statements nested deeper
+    /// than `depth`, running their `finally` blocks
+    fn exit_tries(&self, depth: usize) -> Result<(), LoxResult>
+    {
+        let innermost = self.current(|f| f.tries.len());
+        for index in (depth..innermost).rev()
+        {
+            let (handlers, finally) = self.current(|f| {
+                match &f.tries[index]
+                {
+                    TryRegion::Protected { handlers, finally } =>
+                    {
+                        (Some(*handlers), finally.clone())
+                    }
+                    TryRegion::Finally => (None, None),
+                }
+            });
+
+            let Some(handlers) = handlers
+            else
+            {
+                self.emit_op(OpCode::DropError);
+                continue;
+            };
+
+            for _ in 0..handlers
+            {
+                self.emit_op(OpCode::PopHandler);
+            }
+
+            if let Some(finally) = finally
+            {
+                // Jumps out of the `finally` block itself only run the blocks
+                // outside of it
+                let inner = self.current(|f| f.tries.split_off(index));
+                let result = self.block(&finally);
+                self.current(|f| f.tries.extend(inner));
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile a function declaration, leaving a closure on the stack
+    fn function(&self, stmt: &FunctionStmt) -> Result<(), LoxResult>
+    {
+        if stmt.params.len() > u8::MAX as usize
+        {
+            return Err(LoxResult::parse_error(
+                &stmt.name,
+                "Can't have more than 255 parameters.",
+            ));
+        }
+
+        self.functions.borrow_mut().push(FunctionState::new(
+            stmt.name.lexeme.to_string(),
+            stmt.params.len(),
+        ));
+        self.begin_scope();
+        for param in stmt.params.iter()
+        {
+            self.declare_local(param)?;
+        }
+        self.hoist(&stmt.body)?;
+        for statement in stmt.body.iter()
+        {
+            self.statement(statement)?;
+        }
+        self.emit_op(OpCode::Nil);
+        self.emit_op(OpCode::Return);
+
+        let mut function = self.functions.borrow_mut().pop().unwrap();
+        function.prototype.upvalues = function.upvalues.len();
+
+        let index = self.current(|f| {
+            f.prototype
+                .chunk
+                .prototypes
+                .push(Rc::new(function.prototype));
+            f.prototype.chunk.prototypes.len() - 1
+        });
+        let index =
+            u16::try_from(index).map_err(|_| self.error("Too many functions in one chunk."))?;
+
+        self.at(&stmt.name);
+        self.emit_op(OpCode::Closure);
+        self.emit_u16(index);
+        for upvalue in function.upvalues
+        {
+            self.emit(upvalue.is_local as u8);
+            self.emit(upvalue.index);
+        }
+        Ok(())
+    }
+
+    /// Emit a jump out of the innermost loop, for `break` and `continue`
+    fn loop_jump(&self, token: &Token, message: &str, is_break: bool) -> Result<(), LoxResult>
+    {
+        self.at(token);
+        let Some((scope_depth, try_depth)) =
+            self.current(|f| f.loops.last().map(|l| (l.scope_depth, l.try_depth)))
+        else
+        {
+            return Err(LoxResult::parse_error(token, message));
+        };
+
+        self.exit_tries(try_depth)?;
+        self.discard_locals(scope_depth);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.current(|f| {
+            let innermost = f.loops.last_mut().unwrap();
+            if is_break
+            {
+                innermost.breaks.push(jump)
+            }
+            else
+            {
+                innermost.continues.push(jump)
+            }
+        });
+        Ok(())
+    }
+}
+
+impl StmtVisitor<()> for Compiler
+{
+    fn visit_block_stmt(&self, stmt: &BlockStmt) -> Result<(), LoxResult>
+    {
+        self.block(&stmt.statements)
+    }
+
+    fn visit_break_stmt(&self, stmt: &BreakStmt) -> Result<(), LoxResult>
+    {
+        self.loop_jump(&stmt.token, "Cannot break outside of loop", true)
+    }
+
+    fn visit_continue_stmt(&self, stmt: &ContinueStmt) -> Result<(), LoxResult>
+    {
+        self.loop_jump(&stmt.token, "Cannot continue outside of loop", false)
+    }
+
+    fn visit_expression_stmt(&self, stmt: &ExpressionStmt) -> Result<(), LoxResult>
+    {
+        self.expression(&stmt.expression)?;
+        self.emit_op(OpCode::Pop);
+        Ok(())
+    }
+
+    fn visit_function_stmt(&self, stmt: &FunctionStmt) -> Result<(), LoxResult>
+    {
+        if self.current(|f| f.scope_depth) > 0
+        {
+            // Declare the local first, so the function can refer to itself
+            match self.declare_hoisted(&stmt.name)
+            {
+                Some(slot) =>
+                {
+                    self.function(stmt)?;
+                    self.store_local(slot);
+                    Ok(())
+                }
+                None =>
+                {
+                    self.declare_local(&stmt.name)?;
+                    self.function(stmt)
+                }
+            }
+        }
+        else
+        {
+            self.function(stmt)?;
+            self.define_variable(&stmt.name)
+        }
+    }
+
+    fn visit_if_stmt(&self, stmt: &IfStmt) -> Result<(), LoxResult>
+    {
+        self.expression(&stmt.condition)?;
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement(&stmt.then_branch)?;
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump)?;
+        self.emit_op(OpCode::Pop);
+        if let Some(else_branch) = &stmt.else_branch
+        {
+            self.statement(else_branch)?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn visit_import_stmt(&self, stmt: &ImportStmt) -> Result<(), LoxResult>
+    {
+        self.at(&stmt.keyword);
+        let path = self.constant(stmt.path.literal.clone().unwrap())?;
+
+        if let Some(alias) = &stmt.alias
+        {
+            self.emit_op(OpCode::Import);
+            self.emit_u16(path);
+            self.define_variable(alias)?;
+        }
+
+        for name in &stmt.names
+        {
+            self.at(name);
+            self.emit_op(OpCode::Import);
+            self.emit_u16(path);
+            self.emit_op(OpCode::ImportName);
+            self.emit_u16(path);
+            self.emit_u16(self.name(name)?);
+            self.define_variable(name)?;
+        }
+        Ok(())
+    }
+
+    fn visit_print_stmt(&self, stmt: &PrintStmt) -> Result<(), LoxResult>
+    {
+        self.expression(&stmt.expression)?;
+        self.emit_op(OpCode::Print);
+        Ok(())
+    }
+
+    fn visit_return_stmt(&self, stmt: &ReturnStmt) -> Result<(), LoxResult>
+    {
+        self.at(&stmt.keyword);
+        if self.functions.borrow().len() == 1
+        {
+            return Err(LoxResult::parse_error(
+                &stmt.keyword,
+                "Can't return from top-level code.",
+            ));
+        }
+
+        match &stmt.value
+        {
+            Some(value) => self.expression(value)?,
+            None => self.emit_op(OpCode::Nil),
+        }
+
+        if self.current(|f| f.tries.is_empty())
+        {
+            self.emit_op(OpCode::Return);
+            return Ok(());
+        }
+
+        // Keep the value in a hidden local while the `finally` blocks run
+        self.add_local("".into(), stmt.keyword.line, true)?;
+        let slot = self.current(|f| f.locals.len() - 1) as u8;
+        self.exit_tries(0)?;
+        self.at(&stmt.keyword);
+        self.emit_op(OpCode::GetLocal);
+        self.emit(slot);
+        self.emit_op(OpCode::Return);
+        self.current(|f| f.locals.pop());
+        Ok(())
+    }
+
+    fn visit_throw_stmt(&self, stmt: &ThrowStmt) -> Result<(), LoxResult>
+    {
+        self.expression(&stmt.value)?;
+        self.at(&stmt.keyword);
+        self.emit_op(OpCode::Throw);
+        Ok(())
+    }
+
+    fn visit_try_stmt(&self, stmt: &TryStmt) -> Result<(), LoxResult>
+    {
+        self.at(&stmt.keyword);
+        let finally_handler = stmt
+            .finally_body
+            .as_ref()
+            .map(|_| self.emit_jump(OpCode::PushFinally));
+        let catch_handler = stmt
+            .catch_body
+            .as_ref()
+            .map(|_| self.emit_jump(OpCode::PushCatch));
+
+        let handlers = finally_handler.iter().count() + catch_handler.iter().count();
+        self.current(|f| {
+            f.tries.push(TryRegion::Protected {
+                handlers,
+                finally: stmt.finally_body.clone(),
+            })
+        });
+        let result = self.block(&stmt.body);
+        self.current(|f| f.tries.pop());
+        result?;
+
+        if let (Some(catch_handler), Some(name), Some(catch_body)) =
+            (catch_handler, &stmt.catch_name, &stmt.catch_body)
+        {
+            self.emit_op(OpCode::PopHandler);
+            let skip_catch = self.emit_jump(OpCode::Jump);
+            self.patch_jump(catch_handler)?;
+
+            // The handler pushes the error, which becomes the catch variable
+            self.current(|f| {
+                f.tries.push(TryRegion::Protected {
+                    handlers: finally_handler.iter().count(),
+                    finally: stmt.finally_body.clone(),
+                })
+            });
+            self.begin_scope();
+            let result = self
+                .declare_local(name)
+                .and_then(|_| self.hoist(catch_body))
+                .and_then(|_| {
+                    catch_body
+                        .iter()
+                        .try_for_each(|statement| self.statement(statement))
+                });
+            self.end_scope();
+            self.current(|f| f.tries.pop());
+            result?;
+
+            self.patch_jump(skip_catch)?;
+        }
+
+        if let (Some(finally_handler), Some(finally_body)) = (finally_handler, &stmt.finally_body)
+        {
+            self.emit_op(OpCode::PopHandler);
+            self.block(finally_body)?;
+            let skip_finally = self.emit_jump(OpCode::Jump);
+
+            // The same block again, for when there's an error to throw after it
+            self.patch_jump(finally_handler)?;
+            self.current(|f| f.tries.push(TryRegion::Finally));
+            let result = self.block(finally_body);
+            self.current(|f| f.tries.pop());
+            result?;
+            self.emit_op(OpCode::Rethrow);
+
+            self.patch_jump(skip_finally)?;
+        }
+        Ok(())
+    }
+
+    fn visit_var_stmt(&self, stmt: &VarStmt) -> Result<(), LoxResult>
+    {
+        match &stmt.initializer
+        {
+            Some(initializer) => self.expression(initializer)?,
+            None => self.emit_op(OpCode::Nil),
+        }
+
+        // The variable is only declared now, so the initializer refers to any
+        // variable it shadows
+        self.at(&stmt.name);
+        self.define_variable(&stmt.name)
+    }
+
+    fn visit_while_stmt(&self, stmt: &WhileStmt) -> Result<(), LoxResult>
+    {
+        let start = self.code_len();
+        self.expression(&stmt.condition)?;
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+
+        self.current(|f| {
+            let (scope_depth, try_depth) = (f.scope_depth, f.tries.len());
+            f.loops.push(Loop {
+                scope_depth,
+                try_depth,
+                breaks: Vec::new(),
+                continues: Vec::new(),
+            })
+        });
+        let result = self.statement(&stmt.body);
+        let innermost = self.current(|f| f.loops.pop().unwrap());
+        result?;
+
+        for jump in innermost.continues
+        {
+            self.patch_jump(jump)?;
+        }
+        if let Some(increment) = &stmt.increment
+        {
+            self.expression(increment)?;
+            self.emit_op(OpCode::Pop);
+        }
+        self.emit_loop(start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_op(OpCode::Pop);
+        for jump in innermost.breaks
+        {
+            self.patch_jump(jump)?;
+        }
+        Ok(())
+    }
+}
+
+impl ExprVisitor<()> for Compiler
+{
+    fn visit_assign_expr(&self, expr: &AssignExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.value)?;
+        self.variable(&expr.name, true)
+    }
+
+    fn visit_binary_expr(&self, expr: &BinaryExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.left)?;
+        self.expression(&expr.right)?;
+
+        self.at(&expr.operator);
+        match expr.operator.token_type()
+        {
+            TokenType::Minus => self.emit_op(OpCode::Subtract),
+            TokenType::Slash => self.emit_op(OpCode::Divide),
+            TokenType::Star => self.emit_op(OpCode::Multiply),
+            TokenType::Plus => self.emit_op(OpCode::Add),
+            TokenType::Greater => self.emit_op(OpCode::Greater),
+            TokenType::GreaterEqual => self.emit_op(OpCode::GreaterEqual),
+            TokenType::Less => self.emit_op(OpCode::Less),
+            TokenType::LessEqual => self.emit_op(OpCode::LessEqual),
+            TokenType::Equal => self.emit_op(OpCode::Equal),
+            TokenType::BangEqual =>
+            {
+                self.emit_op(OpCode::Equal);
+                self.emit_op(OpCode::Not);
+            }
+            _ => unreachable!("Invalid binary operator"),
+        }
+        Ok(())
+    }
+
+    fn visit_call_expr(&self, expr: &CallExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.callee)?;
+        if expr.arguments.len() > u8::MAX as usize
+        {
+            return Err(LoxResult::parse_error(
+                &expr.paren,
+                "Can't have more than 255 arguments.",
+            ));
+        }
+        for argument in &expr.arguments
+        {
+            self.expression(argument)?;
+        }
+
+        self.at(&expr.paren);
+        self.emit_op(OpCode::Call);
+        self.emit(expr.arguments.len() as u8);
+        Ok(())
+    }
+
+    fn visit_get_expr(&self, expr: &GetExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.object)?;
+        self.at(&expr.name);
+        self.emit_op(OpCode::GetProperty);
+        self.emit_u16(self.name(&expr.name)?);
+        Ok(())
+    }
+
+    fn visit_grouping_expr(&self, expr: &GroupingExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.expression)
+    }
+
+    fn visit_literal_expr(&self, expr: &LiteralExpr) -> Result<(), LoxResult>
+    {
+        match &expr.value
+        {
+            None | Some(Object::Nil) => self.emit_op(OpCode::Nil),
+            Some(Object::Bool(true)) => self.emit_op(OpCode::True),
+            Some(Object::Bool(false)) => self.emit_op(OpCode::False),
+            Some(value) =>
+            {
+                let index = self.constant(value.clone())?;
+                self.emit_op(OpCode::Constant);
+                self.emit_u16(index);
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_logical_expr(&self, expr: &LogicalExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.left)?;
+        self.at(&expr.operator);
+
+        if expr.operator.is(TokenType::Or)
+        {
+            let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+            let end_jump = self.emit_jump(OpCode::Jump);
+            self.patch_jump(else_jump)?;
+            self.emit_op(OpCode::Pop);
+            self.expression(&expr.right)?;
+            self.patch_jump(end_jump)
+        }
+        else
+        {
+            let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit_op(OpCode::Pop);
+            self.expression(&expr.right)?;
+            self.patch_jump(end_jump)
+        }
+    }
+
+    fn visit_unary_expr(&self, expr: &UnaryExpr) -> Result<(), LoxResult>
+    {
+        self.expression(&expr.right)?;
+        self.at(&expr.operator);
+        match expr.operator.token_type()
+        {
+            TokenType::Minus => self.emit_op(OpCode::Negate),
+            TokenType::Bang => self.emit_op(OpCode::Not),
+            _ => unreachable!("Invalid unary operator"),
+        }
+        Ok(())
+    }
+
+    fn visit_variable_expr(&self, expr: &VariableExpr) -> Result<(), LoxResult>
+    {
+        self.variable(&expr.name, false)
+    }
+}
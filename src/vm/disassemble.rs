@@ -0,0 +1,153 @@
+//! Prints bytecode in a readable form, in the style of clox's
+//! `disassembleChunk`.
+use super::chunk::{Chunk, OpCode, Prototype};
+use std::fmt::Write;
+
+/// Disassemble a prototype, followed by the prototypes nested in it
+pub fn disassemble(prototype: &Prototype) -> String
+{
+    let mut out = String::new();
+    disassemble_prototype(&mut out, prototype);
+    out
+}
+
+fn disassemble_prototype(out: &mut String, prototype: &Prototype)
+{
+    let _ = writeln!(out, "== {} ==", prototype.name);
+    let chunk = &prototype.chunk;
+    let mut offset = 0;
+    while offset < chunk.code.len()
+    {
+        offset = disassemble_instruction(out, chunk, offset);
+    }
+
+    for nested in &chunk.prototypes
+    {
+        out.push('\n');
+        disassemble_prototype(out, nested);
+    }
+}
+
+/// Disassemble the instruction at `offset`, returning the offset of the next
+/// one
+pub fn disassemble_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize
+{
+    let _ = write!(out, "{offset:04} ");
+    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1]
+    {
+        out.push_str("   | ");
+    }
+    else
+    {
+        let _ = write!(out, "{:4} ", chunk.lines[offset]);
+    }
+
+    let Some(op) = OpCode::from_byte(chunk.code[offset])
+    else
+    {
+        let _ = writeln!(out, "Unknown opcode {}", chunk.code[offset]);
+        return offset + 1;
+    };
+
+    let name = format!("{op:?}");
+    let constant = |index: usize| format!("{index:4} '{}'", chunk.constants[index]);
+    match op
+    {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::Import
+        | OpCode::Undefined =>
+        {
+            let _ = writeln!(
+                out,
+                "{name:<16} {}",
+                constant(chunk.read_u16(offset + 1) as usize)
+            );
+            offset + 3
+        }
+        OpCode::ImportName =>
+        {
+            let _ = writeln!(
+                out,
+                "{name:<16} {} {}",
+                constant(chunk.read_u16(offset + 1) as usize),
+                constant(chunk.read_u16(offset + 3) as usize)
+            );
+            offset + 5
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call =>
+        {
+            let _ = writeln!(out, "{name:<16} {:4}", chunk.code[offset + 1]);
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushCatch | OpCode::PushFinally =>
+        {
+            let target = offset + 3 + chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(out, "{name:<16} {offset:4} -> {target}");
+            offset + 3
+        }
+        OpCode::Loop =>
+        {
+            let target = offset + 3 - chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(out, "{name:<16} {offset:4} -> {target}");
+            offset + 3
+        }
+        OpCode::Closure =>
+        {
+            let index = chunk.read_u16(offset + 1) as usize;
+            let prototype = &chunk.prototypes[index];
+            let _ = writeln!(out, "{name:<16} {index:4} <fn {}>", prototype.name);
+
+            // Each captured variable is an `is_local`, `index` pair
+            let mut offset = offset + 3;
+            for _ in 0..prototype.upvalues
+            {
+                let kind = match chunk.code[offset]
+                {
+                    1 => "local",
+                    _ => "upvalue",
+                };
+                let _ = writeln!(
+                    out,
+                    "{offset:04}    |                     {kind} {}",
+                    chunk.code[offset + 1]
+                );
+                offset += 2;
+            }
+            offset
+        }
+        _ =>
+        {
+            let _ = writeln!(out, "{name}");
+            offset + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{interpreter::tests::parse, vm::compiler::Compiler};
+
+    #[test]
+    fn test_disassemble()
+    {
+        let source = "var a = 1.5;\nfun f(x) {\n  fun g() { return x; }\n  return g;\n}\nprint a;";
+        let output = disassemble(&Compiler::compile(&parse(source)).unwrap());
+
+        assert!(output.starts_with("== script ==\n0000    1 Constant            0 '1.5'\n"));
+        assert!(output.contains("\n== f ==\n"));
+        assert!(output.contains("local 1\n"));
+        assert!(output.contains("   6 GetGlobal"));
+        // The nested functions come after the script
+        assert!(output.find("== f ==") < output.find("== g =="));
+    }
+}
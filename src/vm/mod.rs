@@ -0,0 +1,974 @@
+//! A bytecode backend for the interpreter. The `Compiler` turns the syntax
+//! tree into `Chunk`s of bytecode, which the stack based `Vm` runs. Natives,
+//! globals, modules and limits are shared with the tree-walking `Interpreter`.
+pub mod chunk;
+pub mod compiler;
+pub mod disassemble;
+pub mod serialize;
+
+use crate::{
+    error::LoxResult,
+    interpreter::{
+        environment::{entry_size, Environment},
+        Interpreter,
+    },
+    lexer::Scanner,
+    object::{callable::LoxCallable, Object},
+    parser::Parser,
+    tokens::{Token, TokenType},
+};
+use chunk::{Chunk, OpCode, Prototype};
+use compiler::Compiler;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+/// The deepest the VM's calls can be nested
+const MAX_FRAMES: usize = 4096;
+
+/// A variable captured by a closure. It refers to the variable's stack slot
+/// while the variable is in scope, and holds the value itself afterwards.
+#[derive(Debug)]
+enum Upvalue
+{
+    Open(usize),
+    Closed(Object),
+}
+
+/// A function compiled for the VM, along with the variables it captured
+#[derive(Debug)]
+pub struct Closure
+{
+    pub prototype: Rc<Prototype>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+
+    /// The globals of the module the function was declared in
+    globals: Rc<RefCell<Environment>>,
+}
+
+/// Closures are only equal to themselves
+impl PartialEq for Closure
+{
+    fn eq(&self, other: &Self) -> bool { std::ptr::eq(self, other) }
+}
+
+impl std::fmt::Display for Closure
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "<fn {}>", self.prototype.name)
+    }
+}
+
+/// A call in progress
+struct Frame
+{
+    closure: Rc<Closure>,
+
+    /// The offset of the next instruction
+    ip: usize,
+
+    /// The stack slot holding the function being called, after which its
+    /// locals start
+    base: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HandlerKind
+{
+    Catch,
+    Finally,
+}
+
+/// An installed error handler, for a `catch` or `finally` block
+struct Handler
+{
+    kind: HandlerKind,
+
+    /// The number of frames and stack slots to unwind to
+    frames: usize,
+    stack: usize,
+
+    /// Where the handling block starts in the frame's chunk
+    target: usize,
+}
+
+pub struct Vm<'a>
+{
+    /// The interpreter whose globals, natives and limits the VM shares
+    interpreter: &'a Interpreter,
+
+    /// The environment the script's globals are defined in. This is the
+    /// interpreter's globals for the main script, and a module's own
+    /// environment otherwise.
+    globals: Rc<RefCell<Environment>>,
+
+    stack: Vec<Object>,
+    frames: Vec<Frame>,
+
+    /// The stack slots of local variables and the bytes they're counted as
+    /// against the memory limit, in slot order
+    locals: Vec<(usize, usize)>,
+
+    /// The counter of the memory used by variables, shared with the
+    /// interpreter's globals
+    memory: Rc<Cell<usize>>,
+
+    /// The upvalues still referring to stack slots
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+
+    handlers: Vec<Handler>,
+
+    /// The errors held while `finally` blocks run
+    held_errors: Vec<LoxResult>,
+
+    /// The offset of the instruction being run, for error reporting
+    instruction: usize,
+
+    strict: bool,
+}
+
+impl<'a> Vm<'a>
+{
+    pub fn new(interpreter: &'a Interpreter, globals: Rc<RefCell<Environment>>) -> Self
+    {
+        Self {
+            interpreter,
+            globals,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            locals: Vec::new(),
+            memory: interpreter.globals.borrow().memory_counter(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            held_errors: Vec::new(),
+            instruction: 0,
+            strict: interpreter.is_strict(),
+        }
+    }
+
+    /// Run a compiled script with the interpreter's globals, natives and
+    /// limits
+    pub fn interpret(interpreter: &Interpreter, script: Rc<Prototype>) -> Result<(), LoxResult>
+    {
+        interpreter.sandboxed(|| Vm::new(interpreter, Rc::clone(&interpreter.globals)).run(script))
+    }
+
+    /// Run a script to completion
+    pub fn run(&mut self, script: Rc<Prototype>) -> Result<(), LoxResult>
+    {
+        let closure = Rc::new(Closure {
+            prototype: script,
+            upvalues: Vec::new(),
+            globals: Rc::clone(&self.globals),
+        });
+        self.stack.push(Object::Closure(Rc::clone(&closure)));
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base: 0,
+        });
+
+        loop
+        {
+            match self.execute_instruction()
+            {
+                Ok(true) => return Ok(()),
+                Ok(false) => (),
+                Err(error) => self.handle_error(error)?,
+            }
+        }
+    }
+
+    fn frame(&self) -> &Frame { self.frames.last().unwrap() }
+
+    fn chunk(&self) -> &Chunk { &self.frame().closure.prototype.chunk }
+
+    fn read_byte(&mut self) -> u8
+    {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = frame.closure.prototype.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16
+    {
+        let frame = self.frames.last_mut().unwrap();
+        let operand = frame.closure.prototype.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        operand
+    }
+
+    fn read_constant(&mut self) -> Object
+    {
+        let index = self.read_u16() as usize;
+        self.chunk().constants[index].clone()
+    }
+
+    /// Read a constant holding a name
+    fn read_name(&mut self) -> Rc<str>
+    {
+        match self.read_constant()
+        {
+            Object::Str(name) => name,
+            other => unreachable!("Expected a name constant, got {other}"),
+        }
+    }
+
+    fn pop(&mut self) -> Object
+    {
+        let value = self.stack.pop().expect("Stack underflow");
+        self.release_locals();
+        value
+    }
+
+    /// Remove the stack slots from `len` onwards
+    fn truncate(&mut self, len: usize)
+    {
+        self.stack.truncate(len);
+        self.release_locals();
+    }
+
+    fn peek(&self, distance: usize) -> &Object { &self.stack[self.stack.len() - 1 - distance] }
+
+    /// A runtime error at the current instruction
+    fn error(&self, message: String) -> LoxResult
+    {
+        LoxResult::VmError {
+            line: self.chunk().lines[self.instruction],
+            message,
+        }
+    }
+
+    /// Check that `bytes` can be allocated without exceeding the memory limit
+    fn reserve(&self, bytes: usize) -> Result<(), LoxResult>
+    {
+        self.interpreter
+            .reserve(bytes)
+            .map_err(|message| self.error(message))
+    }
+
+    /// Count the value in a local variable's slot against the memory limit,
+    /// in place of the slot's previous value
+    fn count_local(&mut self, slot: usize)
+    {
+        let bytes = std::mem::size_of::<Object>() + self.stack[slot].heap_size();
+        self.memory.set(self.memory.get() + bytes);
+
+        let position = self.locals.iter().rposition(|&(s, _)| s <= slot);
+        match position
+        {
+            Some(i) if self.locals[i].0 == slot =>
+            {
+                let old = std::mem::replace(&mut self.locals[i].1, bytes);
+                self.memory.set(self.memory.get().saturating_sub(old));
+            }
+            Some(i) => self.locals.insert(i + 1, (slot, bytes)),
+            None => self.locals.insert(0, (slot, bytes)),
+        }
+    }
+
+    /// Stop counting the local variables in slots the stack no longer has
+    fn release_locals(&mut self)
+    {
+        while let Some(&(slot, bytes)) = self.locals.last()
+        {
+            if slot < self.stack.len()
+            {
+                break;
+            }
+            self.locals.pop();
+            self.memory.set(self.memory.get().saturating_sub(bytes));
+        }
+    }
+
+    /// Run the next instruction, returning whether the script has finished
+    fn execute_instruction(&mut self) -> Result<bool, LoxResult>
+    {
+        self.interpreter.step()?;
+        self.instruction = self.frame().ip;
+        let byte = self.read_byte();
+        let op =
+            OpCode::from_byte(byte).ok_or_else(|| self.error(format!("Invalid opcode {byte}")))?;
+
+        match op
+        {
+            OpCode::Constant =>
+            {
+                let value = self.read_constant();
+                self.stack.push(value);
+            }
+            OpCode::Nil => self.stack.push(Object::Nil),
+            OpCode::Undefined =>
+            {
+                let name = self.read_name();
+                self.stack.push(Object::Undefined(name));
+            }
+            OpCode::True => self.stack.push(Object::Bool(true)),
+            OpCode::False => self.stack.push(Object::Bool(false)),
+            OpCode::Pop =>
+            {
+                self.pop();
+            }
+            OpCode::GetLocal =>
+            {
+                let slot = self.frame().base + self.read_byte() as usize;
+                self.stack.push(self.stack[slot].clone());
+            }
+            OpCode::SetLocal =>
+            {
+                let slot = self.frame().base + self.read_byte() as usize;
+                let value = self.peek(0).clone();
+                self.reserve(value.heap_size())?;
+                self.stack[slot] = value;
+                self.count_local(slot);
+            }
+            OpCode::GetGlobal =>
+            {
+                let name = self.read_name();
+                let value = self.frame().closure.globals.borrow().lookup(&name);
+                match value
+                {
+                    Some(value) => self.stack.push(value),
+                    None => return Err(self.error(format!("Undefined variable '{name}'."))),
+                }
+            }
+            OpCode::DefineGlobal =>
+            {
+                let name = self.read_name();
+                let value = self.pop();
+                self.reserve(entry_size(&name, &value))?;
+                self.frame()
+                    .closure
+                    .globals
+                    .borrow_mut()
+                    .define(name, value);
+            }
+            OpCode::SetGlobal =>
+            {
+                let name = self.read_name();
+                let value = self.peek(0).clone();
+                self.reserve(entry_size(&name, &value))?;
+                if !self.frame().closure.globals.borrow_mut().set(&name, value)
+                {
+                    return Err(self.error(format!("Undefined variable '{name}'.")));
+                }
+            }
+            OpCode::GetUpvalue =>
+            {
+                let index = self.read_byte() as usize;
+                let value = match &*self.frame().closure.upvalues[index].borrow()
+                {
+                    Upvalue::Open(slot) => self.stack.get(*slot).cloned(),
+                    Upvalue::Closed(value) => Some(value.clone()),
+                };
+                match value.ok_or_else(|| self.missing_slot())?
+                {
+                    Object::Undefined(name) =>
+                    {
+                        return Err(self.error(format!("Undefined variable '{name}'.")))
+                    }
+                    value => self.stack.push(value),
+                }
+            }
+            OpCode::SetUpvalue =>
+            {
+                let index = self.read_byte() as usize;
+                let value = self.peek(0).clone();
+                self.reserve(value.heap_size())?;
+                let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                let mut upvalue = upvalue.borrow_mut();
+                let current = match &*upvalue
+                {
+                    Upvalue::Open(slot) => self.stack.get(*slot),
+                    Upvalue::Closed(closed) => Some(closed),
+                };
+                if let Some(Object::Undefined(name)) = current
+                {
+                    return Err(self.error(format!("Undefined variable '{name}'.")));
+                }
+                match &mut *upvalue
+                {
+                    Upvalue::Open(slot) if *slot < self.stack.len() =>
+                    {
+                        self.stack[*slot] = value;
+                        self.count_local(*slot);
+                    }
+                    Upvalue::Open(_) => return Err(self.missing_slot()),
+                    Upvalue::Closed(closed) => *closed = value,
+                };
+            }
+            OpCode::GetProperty =>
+            {
+                let name = self.read_name();
+                let value = match self.pop()
+                {
+                    Object::Error(error) => error.get(&name),
+                    Object::Record(record) => record.get(&name),
+                    Object::Module(module) => module.get(&name),
+                    _ =>
+                    {
+                        return Err(self.error(
+                            "Only modules, records and error objects have properties.".to_string(),
+                        ))
+                    }
+                };
+                match value
+                {
+                    Some(value) => self.stack.push(value),
+                    None => return Err(self.error(format!("Undefined property '{name}'."))),
+                }
+            }
+            OpCode::Equal =>
+            {
+                let (right, left) = (self.pop(), self.pop());
+                self.stack.push(Object::Bool(left.equals(&right)));
+            }
+            OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide =>
+            {
+                let (right, left) = (self.pop(), self.pop());
+                let result = match op
+                {
+                    OpCode::Greater => left.greater(right),
+                    OpCode::GreaterEqual => left.greater_eq(right),
+                    OpCode::Less => left.less(right),
+                    OpCode::LessEqual => left.less_eq(right),
+                    OpCode::Add if self.strict => left.add_strict(right),
+                    OpCode::Add => left + right,
+                    OpCode::Subtract => left - right,
+                    OpCode::Multiply => left * right,
+                    _ => left / right,
+                }
+                .map_err(|e| self.error(e.message))?;
+
+                self.reserve(result.heap_size())?;
+                self.stack.push(result);
+            }
+            OpCode::Not =>
+            {
+                let value = self.pop();
+                self.stack
+                    .push(Object::Bool(!self.interpreter.is_truthy(&value)));
+            }
+            OpCode::Negate =>
+            {
+                let value = (-self.pop()).map_err(|e| self.error(e.message))?;
+                self.stack.push(value);
+            }
+            OpCode::Print => println!("{}", self.pop()),
+            OpCode::Jump =>
+            {
+                let offset = self.read_u16() as usize;
+                self.frames.last_mut().unwrap().ip += offset;
+            }
+            OpCode::JumpIfFalse =>
+            {
+                let offset = self.read_u16() as usize;
+                if !self.interpreter.is_truthy(self.peek(0))
+                {
+                    self.frames.last_mut().unwrap().ip += offset;
+                }
+            }
+            OpCode::Loop =>
+            {
+                let offset = self.read_u16() as usize;
+                self.frames.last_mut().unwrap().ip -= offset;
+            }
+            OpCode::Call =>
+            {
+                let arguments = self.read_byte() as usize;
+                self.call(arguments)?;
+            }
+            OpCode::Closure =>
+            {
+                let index = self.read_u16() as usize;
+                let prototype = Rc::clone(&self.chunk().prototypes[index]);
+
+                let mut upvalues = Vec::with_capacity(prototype.upvalues);
+                for _ in 0..prototype.upvalues
+                {
+                    let is_local = self.read_byte() == 1;
+                    let index = self.read_byte() as usize;
+                    upvalues.push(
+                        if is_local
+                        {
+                            self.capture_upvalue(self.frame().base + index)
+                        }
+                        else
+                        {
+                            Rc::clone(&self.frame().closure.upvalues[index])
+                        },
+                    );
+                }
+
+                self.stack.push(Object::Closure(Rc::new(Closure {
+                    prototype,
+                    upvalues,
+                    globals: Rc::clone(&self.frame().closure.globals),
+                })));
+            }
+            OpCode::CloseUpvalue =>
+            {
+                self.close_upvalues(self.stack.len() - 1)?;
+                self.pop();
+            }
+            OpCode::Return =>
+            {
+                let result = self.pop();
+                let frame = self.frames.pop().unwrap();
+                self.close_upvalues(frame.base)?;
+                self.truncate(frame.base);
+
+                // Handlers left behind by the function can no longer be reached
+                while self
+                    .handlers
+                    .last()
+                    .is_some_and(|handler| handler.frames > self.frames.len())
+                {
+                    self.handlers.pop();
+                }
+
+                if self.frames.is_empty()
+                {
+                    return Ok(true);
+                }
+                self.stack.push(result);
+            }
+            OpCode::Throw =>
+            {
+                let value = self.pop();
+                let line = self.chunk().lines[self.instruction];
+                return Err(LoxResult::Throw {
+                    token: Token::new(TokenType::Throw, "throw".to_string(), None, line),
+                    value,
+                });
+            }
+            OpCode::PushCatch | OpCode::PushFinally =>
+            {
+                let offset = self.read_u16() as usize;
+                self.handlers.push(Handler {
+                    kind: if op == OpCode::PushCatch
+                    {
+                        HandlerKind::Catch
+                    }
+                    else
+                    {
+                        HandlerKind::Finally
+                    },
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    target: self.frame().ip + offset,
+                });
+            }
+            OpCode::PopHandler =>
+            {
+                self.handlers.pop();
+            }
+            OpCode::Rethrow =>
+            {
+                return Err(self
+                    .held_errors
+                    .pop()
+                    .unwrap_or_else(|| self.error("No error to rethrow".to_string())));
+            }
+            OpCode::DropError =>
+            {
+                self.held_errors.pop();
+            }
+            OpCode::Import =>
+            {
+                let path = self.read_name();
+                let module = self.interpreter.load_module(
+                    &path,
+                    |message| self.error(message),
+                    |source| self.run_module(source, &path),
+                )?;
+                self.stack.push(Object::Module(module));
+            }
+            OpCode::ImportName =>
+            {
+                let path = self.read_name();
+                let name = self.read_name();
+                let value = match self.pop()
+                {
+                    Object::Module(module) => module.get(&name),
+                    _ => None,
+                };
+
+                match value
+                {
+                    Some(value) => self.stack.push(value),
+                    None =>
+                    {
+                        return Err(
+                            self.error(format!("Module \"{path}\" has no definition '{name}'."))
+                        )
+                    }
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Call the value below the top `arguments` values on the stack
+    fn call(&mut self, arguments: usize) -> Result<(), LoxResult>
+    {
+        let callee = self.peek(arguments).clone();
+        let (name, arity) = match &callee
+        {
+            Object::Closure(closure) => (closure.prototype.name.clone(), closure.prototype.arity),
+            Object::Func(function) => (function.func.to_string(), function.func.arity()),
+            _ => return Err(self.error("Can only call functions and classes".to_string())),
+        };
+
+        if arguments != arity
+        {
+            return Err(self.error(format!(
+                "Expected {arity} arguments to '{name}' but got {arguments}"
+            )));
+        }
+
+        match callee
+        {
+            Object::Closure(closure) =>
+            {
+                if self.frames.len() == MAX_FRAMES
+                {
+                    return Err(self.error("Stack overflow.".to_string()));
+                }
+                let base = self.stack.len() - arguments - 1;
+                self.frames.push(Frame {
+                    closure,
+                    ip: 0,
+                    base,
+                });
+                for slot in base + 1..self.stack.len()
+                {
+                    self.count_local(slot);
+                }
+            }
+            Object::Func(function) =>
+            {
+                let arguments = self.stack.split_off(self.stack.len() - arguments);
+                self.pop();
+                let result = function.call(self.interpreter, arguments).map_err(|e| {
+                    match e
+                    {
+                        LoxResult::NativeError { message } => self.error(message),
+                        e => e,
+                    }
+                })?;
+                self.stack.push(result);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// An upvalue for the variable in `slot`, shared with any other closure
+    /// that has captured it
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>>
+    {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(&*upvalue.borrow(), Upvalue::Open(s) if *s == slot));
+        if let Some(upvalue) = existing
+        {
+            return Rc::clone(upvalue);
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Move the variables in slots from `start` onwards into the upvalues
+    /// that captured them
+    fn close_upvalues(&mut self, start: usize) -> Result<(), LoxResult>
+    {
+        let missing = self.open_upvalues.iter().any(
+            |upvalue| matches!(*upvalue.borrow(), Upvalue::Open(slot) if slot >= self.stack.len()),
+        );
+        if missing
+        {
+            return Err(self.missing_slot());
+        }
+
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue
+            {
+                Upvalue::Open(slot) if slot >= start =>
+                {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+        Ok(())
+    }
+
+    /// The error for an upvalue whose slot has been removed from the stack,
+    /// which only code that wasn't produced by the compiler can cause
+    fn missing_slot(&self) -> LoxResult
+    {
+        self.error("Upvalue refers to a missing stack slot.".to_string())
+    }
+
+    /// Unwind to the innermost handler for `error`, or return the error if
+    /// there isn't one
+    fn handle_error(&mut self, error: LoxResult) -> Result<(), LoxResult>
+    {
+        let exception = error.exception();
+        while let Some(handler) = self.handlers.pop()
+        {
+            // Errors outside of the script's control only run `finally` blocks
+            if handler.kind == HandlerKind::Catch && exception.is_none()
+            {
+                continue;
+            }
+
+            self.close_upvalues(handler.stack)?;
+            self.frames.truncate(handler.frames);
+            self.truncate(handler.stack);
+            self.frames.last_mut().unwrap().ip = handler.target;
+
+            match handler.kind
+            {
+                HandlerKind::Catch => self.stack.push(exception.unwrap()),
+                HandlerKind::Finally => self.held_errors.push(error),
+            }
+            return Ok(());
+        }
+
+        Err(error)
+    }
+
+    /// Compile and run a module's source in a fresh environment, returning
+    /// that environment
+    fn run_module(&self, source: String, path: &str)
+        -> Result<Rc<RefCell<Environment>>, LoxResult>
+    {
+        // The errors themselves have already been reported
+        let invalid = || self.error(format!("Module \"{path}\" has errors."));
+
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().map_err(|_| invalid())?;
+        let mut parser = Parser::new(tokens);
+        let statements = parser.parse().map_err(|_| invalid())?;
+        if !parser.success()
+        {
+            return Err(invalid());
+        }
+        let statements = self.interpreter.optimize(statements)?;
+        let script = Compiler::compile(&statements).map_err(|_| invalid())?;
+
+        let environment = self.interpreter.module_environment();
+        Vm::new(self.interpreter, Rc::clone(&environment)).run(script)?;
+        Ok(environment)
+    }
+}
+
+impl Drop for Vm<'_>
+{
+    fn drop(&mut self)
+    {
+        let bytes: usize = self.locals.iter().map(|&(_, bytes)| bytes).sum();
+        self.memory.set(self.memory.get().saturating_sub(bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::interpreter::{
+        sandbox::Limits,
+        tests::{global, parse},
+    };
+
+    /// Compile and run `source`, returning the interpreter holding its globals
+    fn run(source: &str) -> (Interpreter, Result<(), LoxResult>)
+    {
+        let i = Interpreter::new();
+        let res = run_in(&i, source);
+        (i, res)
+    }
+
+    /// Compile `source` and run it with the globals of `i`
+    fn run_in(i: &Interpreter, source: &str) -> Result<(), LoxResult>
+    {
+        let script = Compiler::compile(&parse(source)).unwrap();
+        Vm::interpret(i, script)
+    }
+
+    #[test]
+    fn test_closures_share_variables()
+    {
+        let (i, res) = run(
+            "fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; } var c = \
+             counter(); c(); var a = c(); var d = counter(); var b = d();",
+        );
+        res.unwrap();
+        assert_eq!(global(&i, "a"), Object::Num(2.0));
+        assert_eq!(global(&i, "b"), Object::Num(1.0));
+    }
+
+    #[test]
+    fn test_finally_runs_on_return_and_break()
+    {
+        let (i, res) = run(
+            "var log = \"\"; fun f() { try { return 1; } finally { log = log + \"f\"; } } var a = \
+             f(); while (true) { try { break; } finally { log = log + \"b\"; } }",
+        );
+        res.unwrap();
+        assert_eq!(global(&i, "a"), Object::Num(1.0));
+        assert_eq!(global(&i, "log"), Object::Str("fb".into()));
+    }
+
+    #[test]
+    fn test_catch_unwinds_calls()
+    {
+        let (i, res) = run(
+            "fun f(n) { if (n == 0) return 1 - \"x\"; return f(n - 1); } var line; try { f(3); } \
+             catch (e) { line = e.line; }",
+        );
+        res.unwrap();
+        assert_eq!(global(&i, "line"), Object::Num(1.0));
+    }
+
+    #[test]
+    fn test_uncaught_error_line()
+    {
+        let (_, res) = run("var a = 1;\nprint a + nil;");
+        match res
+        {
+            Err(LoxResult::VmError { line, message }) =>
+            {
+                assert_eq!(line, 2);
+                assert_eq!(
+                    message,
+                    "Operands to '+' must be two numbers or two strings, got number and nil"
+                );
+            }
+            res => panic!("Expected a runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_break_outside_loop()
+    {
+        assert!(Compiler::compile(&parse("break;")).is_err());
+    }
+
+    #[test]
+    fn test_too_many_locals_line()
+    {
+        // The function's own slot and 255 variables fill its slots, so the
+        // variable declared on line 257 is one too many
+        let declarations: String = (0..300).map(|n| format!("var v{n} = {n};\n")).collect();
+        let source = format!("fun f() {{\n{declarations}}}");
+        match Compiler::compile(&parse(&source))
+        {
+            Err(LoxResult::LoxError { line, message }) =>
+            {
+                assert_eq!(line, 257);
+                assert_eq!(message, "Too many local variables in function.");
+            }
+            res => panic!("Expected a compile error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_memory_limit()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 10_000),
+            ..Limits::default()
+        });
+
+        run_in(
+            &i,
+            "var s = \"x\"; var caught = false; try { while (true) s = s + s; } catch (e) { \
+             caught = e.message; }",
+        )
+        .unwrap();
+        match global(&i, "caught")
+        {
+            Object::Str(message) => assert!(message.starts_with("Out of memory")),
+            other => panic!("Expected an error message, got {other}"),
+        }
+
+        assert!(matches!(
+            run_in(&i, "var t = repeat(\"x\", 20000);"),
+            Err(LoxResult::VmError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_memory_limit_counts_locals()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 200_000),
+            ..Limits::default()
+        });
+
+        assert!(matches!(
+            run_in(
+                &i,
+                "fun f() { var a = repeat(\"x\", 60000); var b = repeat(\"x\", 60000); var c = \
+                 repeat(\"x\", 60000); var d = repeat(\"x\", 60000); var e = repeat(\"x\", \
+                 60000); } f();"
+            ),
+            Err(LoxResult::VmError { .. })
+        ));
+
+        // The locals are freed once the function returns, or an error unwinds
+        // the stack
+        run_in(
+            &i,
+            "fun f() { var a = repeat(\"x\", 60000); } f(); f(); f(); f();",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_memory_limit_counts_lists()
+    {
+        let i = Interpreter::new();
+        i.set_limits(Limits {
+            max_memory: Some(i.globals.borrow().memory_used() + 100_000),
+            ..Limits::default()
+        });
+
+        assert!(matches!(
+            run_in(&i, "var parts = split(repeat(\"x,\", 30000), \",\");"),
+            Err(LoxResult::VmError { .. })
+        ));
+
+        run_in(
+            &i,
+            "var parts = split(repeat(\"x\", 30000) + \",\" + repeat(\"y\", 30000), \",\");",
+        )
+        .unwrap();
+        assert!(matches!(
+            run_in(&i, "print join(parts, repeat(\"-\", 50000));"),
+            Err(LoxResult::VmError { .. })
+        ));
+    }
+}
@@ -0,0 +1,693 @@
+//! The `.loxc` format for compiled scripts. A file is laid out as:
+//!
+//! - the magic bytes `LOXC` and a `u16` format version
+//! - the script's prototype, each prototype being its name, arity, upvalue
+//!   count, code, line table, constants and nested prototypes in turn
+//! - a 64-bit FNV-1a checksum of everything before it
+//!
+//! Integers are little endian, and lengths are `u32`s. Lines are stored as
+//! runs of `(line, length)` pairs. Files are fully validated when loaded, so
+//! the VM can trust the code it runs.
+use super::chunk::{Chunk, OpCode, Prototype};
+use crate::{error::LoxResult, object::Object};
+use std::{collections::BTreeSet, rc::Rc};
+
+const MAGIC: &[u8; 4] = b"LOXC";
+
+/// The current format version. Files from other versions are rejected.
+pub const VERSION: u16 = 1;
+
+/// How deeply functions may be nested in a file
+const MAX_DEPTH: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+
+fn checksum(bytes: &[u8]) -> u64
+{
+    bytes.iter().fold(0xCBF29CE484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
+
+/// Serialize a compiled script
+pub fn serialize(script: &Prototype) -> Vec<u8>
+{
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    write_prototype(&mut bytes, script);
+
+    let checksum = checksum(&bytes);
+    bytes.extend(checksum.to_le_bytes());
+    bytes
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) { bytes.extend((value as u32).to_le_bytes()); }
+
+fn write_str(bytes: &mut Vec<u8>, s: &str)
+{
+    write_u32(bytes, s.len());
+    bytes.extend(s.as_bytes());
+}
+
+fn write_prototype(bytes: &mut Vec<u8>, prototype: &Prototype)
+{
+    write_str(bytes, &prototype.name);
+    write_u32(bytes, prototype.arity);
+    write_u32(bytes, prototype.upvalues);
+
+    let chunk = &prototype.chunk;
+    write_u32(bytes, chunk.code.len());
+    bytes.extend(&chunk.code);
+
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for line in &chunk.lines
+    {
+        match runs.last_mut()
+        {
+            Some((last, length)) if last == line => *length += 1,
+            _ => runs.push((*line, 1)),
+        }
+    }
+    write_u32(bytes, runs.len());
+    for (line, length) in runs
+    {
+        write_u32(bytes, line);
+        write_u32(bytes, length);
+    }
+
+    write_u32(bytes, chunk.constants.len());
+    for constant in &chunk.constants
+    {
+        match constant
+        {
+            Object::Nil => bytes.push(TAG_NIL),
+            Object::Bool(false) => bytes.push(TAG_FALSE),
+            Object::Bool(true) => bytes.push(TAG_TRUE),
+            Object::Num(n) =>
+            {
+                bytes.push(TAG_NUMBER);
+                bytes.extend(n.to_le_bytes());
+            }
+            Object::Str(s) =>
+            {
+                bytes.push(TAG_STRING);
+                write_str(bytes, s);
+            }
+            other =>
+            {
+                unreachable!(
+                    "The compiler doesn't create {} constants",
+                    other.type_name()
+                )
+            }
+        }
+    }
+
+    write_u32(bytes, chunk.prototypes.len());
+    for nested in &chunk.prototypes
+    {
+        write_prototype(bytes, nested);
+    }
+}
+
+/// Load a compiled script, checking that it's well formed
+pub fn deserialize(bytes: &[u8]) -> Result<Rc<Prototype>, LoxResult>
+{
+    if bytes.len() < MAGIC.len() + 2 + 8 || &bytes[..MAGIC.len()] != MAGIC
+    {
+        return Err(invalid("not a compiled Lox file"));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION
+    {
+        return Err(invalid(&format!(
+            "unsupported format version {version}, expected {VERSION}"
+        )));
+    }
+
+    let (body, stored) = bytes.split_at(bytes.len() - 8);
+    if checksum(body) != u64::from_le_bytes(stored.try_into().unwrap())
+    {
+        return Err(invalid("checksum mismatch, the file is corrupt"));
+    }
+
+    let mut reader = Reader {
+        bytes: body,
+        offset: MAGIC.len() + 2,
+    };
+    let script = reader.prototype(0)?;
+    if reader.offset != body.len()
+    {
+        return Err(invalid("unexpected data after the script"));
+    }
+
+    Ok(Rc::new(script))
+}
+
+fn invalid(message: &str) -> LoxResult
+{
+    LoxResult::BytecodeError {
+        message: message.to_string(),
+    }
+}
+
+struct Reader<'a>
+{
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_>
+{
+    fn take(&mut self, length: usize) -> Result<&[u8], LoxResult>
+    {
+        let end = self
+            .offset
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of file"))?;
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoxResult> { Ok(self.take(1)?[0]) }
+
+    fn u32(&mut self) -> Result<usize, LoxResult>
+    {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn str(&mut self) -> Result<String, LoxResult>
+    {
+        let length = self.u32()?;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| invalid("invalid UTF-8 string"))
+    }
+
+    fn prototype(&mut self, depth: usize) -> Result<Prototype, LoxResult>
+    {
+        if depth > MAX_DEPTH
+        {
+            return Err(invalid("functions are nested too deeply"));
+        }
+
+        let name = self.str()?;
+        let arity = self.u32()?;
+        let upvalues = self.u32()?;
+        if arity > u8::MAX as usize || upvalues > u8::MAX as usize + 1
+        {
+            return Err(invalid(&format!(
+                "function '{name}' has too many variables"
+            )));
+        }
+
+        let length = self.u32()?;
+        let code = self.take(length)?.to_vec();
+
+        let mut lines = Vec::with_capacity(code.len());
+        for _ in 0..self.u32()?
+        {
+            let (line, length) = (self.u32()?, self.u32()?);
+            if lines.len() + length > code.len()
+            {
+                return Err(invalid("line table is longer than the code"));
+            }
+            lines.extend(std::iter::repeat_n(line, length));
+        }
+        if lines.len() != code.len()
+        {
+            return Err(invalid("line table doesn't match the code"));
+        }
+
+        let mut constants = Vec::new();
+        for _ in 0..self.u32()?
+        {
+            constants.push(match self.u8()?
+            {
+                TAG_NIL => Object::Nil,
+                TAG_FALSE => Object::Bool(false),
+                TAG_TRUE => Object::Bool(true),
+                TAG_NUMBER => Object::Num(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                TAG_STRING => Object::Str(self.str()?.into()),
+                tag => return Err(invalid(&format!("unknown constant tag {tag}"))),
+            });
+        }
+
+        let mut prototypes = Vec::new();
+        for _ in 0..self.u32()?
+        {
+            prototypes.push(Rc::new(self.prototype(depth + 1)?));
+        }
+
+        let prototype = Prototype {
+            name,
+            arity,
+            upvalues,
+            chunk: Chunk {
+                code,
+                lines,
+                constants,
+                prototypes,
+            },
+        };
+        verify(&prototype)?;
+        Ok(prototype)
+    }
+}
+
+/// Check that a prototype's code only refers to things that exist: every
+/// instruction is complete, operands index into the constants, prototypes and
+/// upvalues, jumps land on instructions within the code, and the stack holds
+/// the values each instruction uses.
+fn verify(prototype: &Prototype) -> Result<(), LoxResult>
+{
+    let chunk = &prototype.chunk;
+    let error = |offset: usize, message: &str| {
+        invalid(&format!(
+            "in function '{}' at offset {offset}: {message}",
+            prototype.name
+        ))
+    };
+
+    // The offset of each instruction, and of the one after it
+    let mut starts = Vec::new();
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len()
+    {
+        let op = OpCode::from_byte(chunk.code[offset])
+            .ok_or_else(|| error(offset, &format!("invalid opcode {}", chunk.code[offset])))?;
+
+        let operand_length = match op
+        {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => 1,
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::PushCatch
+            | OpCode::PushFinally
+            | OpCode::Import
+            | OpCode::Undefined
+            | OpCode::Closure => 2,
+            OpCode::ImportName => 4,
+            _ => 0,
+        };
+        if offset + operand_length >= chunk.code.len()
+        {
+            return Err(error(offset, "incomplete instruction"));
+        }
+        let operand = || chunk.read_u16(offset + 1) as usize;
+        let next = offset + 1 + operand_length;
+
+        let is_name = |index: usize| matches!(chunk.constants.get(index), Some(Object::Str(_)));
+        match op
+        {
+            OpCode::Constant if operand() >= chunk.constants.len() =>
+            {
+                return Err(error(offset, "constant out of range"))
+            }
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::Import
+            | OpCode::Undefined
+                if !is_name(operand()) =>
+            {
+                return Err(error(offset, "expected a name constant"))
+            }
+            OpCode::ImportName
+                if !is_name(operand()) || !is_name(chunk.read_u16(offset + 3) as usize) =>
+            {
+                return Err(error(offset, "expected a name constant"))
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue
+                if chunk.code[offset + 1] as usize >= prototype.upvalues =>
+            {
+                return Err(error(offset, "upvalue out of range"))
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushCatch | OpCode::PushFinally =>
+            {
+                jumps.push((offset, next + operand()))
+            }
+            OpCode::Loop =>
+            {
+                let target = next
+                    .checked_sub(operand())
+                    .ok_or_else(|| error(offset, "jump out of range"))?;
+                jumps.push((offset, target));
+            }
+            OpCode::Closure =>
+            {
+                let nested = chunk
+                    .prototypes
+                    .get(operand())
+                    .ok_or_else(|| error(offset, "prototype out of range"))?;
+
+                // Each captured variable is an `is_local`, `index` pair
+                let captures = next..next + nested.upvalues * 2;
+                if captures.end > chunk.code.len()
+                {
+                    return Err(error(offset, "incomplete instruction"));
+                }
+                for capture in captures.step_by(2)
+                {
+                    let (is_local, index) = (chunk.code[capture], chunk.code[capture + 1]);
+                    if is_local > 1 || (is_local == 0 && index as usize >= prototype.upvalues)
+                    {
+                        return Err(error(offset, "invalid captured variable"));
+                    }
+                }
+                let end = next + nested.upvalues * 2;
+                starts.push((offset, end));
+                offset = end;
+                continue;
+            }
+            _ => (),
+        }
+        starts.push((offset, next));
+        offset = next;
+    }
+
+    if chunk.code.last() != Some(&(OpCode::Return as u8))
+    {
+        return Err(error(chunk.code.len(), "code doesn't end with a return"));
+    }
+    for (offset, target) in jumps
+    {
+        if starts
+            .binary_search_by_key(&target, |&(start, _)| start)
+            .is_err()
+        {
+            return Err(error(offset, "jump target isn't an instruction"));
+        }
+    }
+    verify_stack(prototype, &starts, error)
+}
+
+/// Check that every path through a prototype's code keeps enough values on
+/// the stack for each instruction, only uses local slots that exist, and
+/// reaches each instruction with the same stack depth. The depth counts the
+/// frame's slots, which start with the function and its arguments. Slots
+/// captured by a closure on any path must be closed with `CloseUpvalue`
+/// rather than popped, or the closure would refer to a slot that's gone.
+fn verify_stack(
+    prototype: &Prototype,
+    starts: &[(usize, usize)],
+    error: impl Fn(usize, &str) -> LoxResult,
+) -> Result<(), LoxResult>
+{
+    let chunk = &prototype.chunk;
+    let mut states: Vec<Option<(usize, BTreeSet<usize>)>> = vec![None; chunk.code.len()];
+    let mut pending = vec![(0, prototype.arity + 1, BTreeSet::new())];
+
+    while let Some((offset, depth, mut captured)) = pending.pop()
+    {
+        match &mut states[offset]
+        {
+            Some((known, _)) if *known != depth =>
+            {
+                return Err(error(offset, "stack depth differs between paths"))
+            }
+            Some((_, known)) if captured.is_subset(known) => continue,
+            Some((_, known)) =>
+            {
+                known.extend(captured);
+                captured = known.clone();
+            }
+            state => *state = Some((depth, captured.clone())),
+        }
+
+        let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+        let next = match starts.binary_search_by_key(&offset, |&(start, _)| start)
+        {
+            Ok(index) => starts[index].1,
+            Err(_) => unreachable!("Only instructions are visited"),
+        };
+        let byte = || chunk.code[offset + 1] as usize;
+        let operand = || chunk.read_u16(offset + 1) as usize;
+
+        // The values each instruction takes from the stack, and leaves on it.
+        // Instructions that only look at the top of the stack take it and
+        // leave it.
+        let (taken, left) = match op
+        {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::Import
+            | OpCode::Undefined => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Throw => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse
+            | OpCode::ImportName => (1, 1),
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide => (2, 1),
+            OpCode::Call => (byte() + 1, 1),
+            OpCode::Jump
+            | OpCode::Loop
+            | OpCode::PushCatch
+            | OpCode::PushFinally
+            | OpCode::PopHandler
+            | OpCode::Rethrow
+            | OpCode::DropError => (0, 0),
+        };
+        if taken > depth
+        {
+            return Err(error(offset, "stack underflow"));
+        }
+        let after = depth - taken + left;
+
+        match op
+        {
+            OpCode::GetLocal | OpCode::SetLocal if byte() >= depth =>
+            {
+                return Err(error(offset, "local slot out of range"))
+            }
+            // A local function can capture itself, from the slot it's about
+            // to be stored in
+            OpCode::Closure
+                if (offset + 3..next).step_by(2).any(|capture| {
+                    chunk.code[capture] == 1 && chunk.code[capture + 1] as usize >= after
+                }) =>
+            {
+                return Err(error(offset, "local slot out of range"))
+            }
+            _ => (),
+        }
+
+        // Returning closes the frame's upvalues, and `CloseUpvalue` the one
+        // it takes
+        if !matches!(op, OpCode::CloseUpvalue | OpCode::Return)
+            && captured.range(depth - taken..).next().is_some()
+        {
+            return Err(error(offset, "captured local removed without closing it"));
+        }
+        captured.retain(|&slot| slot < depth - taken);
+        if op == OpCode::Closure
+        {
+            for capture in (offset + 3..next).step_by(2)
+            {
+                if chunk.code[capture] == 1
+                {
+                    captured.insert(chunk.code[capture + 1] as usize);
+                }
+            }
+        }
+
+        let mut successors = match op
+        {
+            OpCode::Return | OpCode::Throw | OpCode::Rethrow => vec![],
+            OpCode::Jump => vec![(next + operand(), after)],
+            OpCode::Loop => vec![(next - operand(), after)],
+            OpCode::JumpIfFalse | OpCode::PushFinally =>
+            {
+                vec![(next, after), (next + operand(), after)]
+            }
+            // The handler starts with the error's value pushed
+            OpCode::PushCatch => vec![(next, after), (next + operand(), after + 1)],
+            _ => vec![(next, after)],
+        };
+        if successors
+            .iter()
+            .any(|&(target, _)| target >= chunk.code.len())
+        {
+            return Err(error(offset, "code runs past its end"));
+        }
+        pending.extend(
+            successors
+                .drain(..)
+                .map(|(target, depth)| (target, depth, captured.clone())),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{interpreter::tests::parse, vm::compiler::Compiler};
+
+    fn compile(source: &str) -> Rc<Prototype> { Compiler::compile(&parse(source)).unwrap() }
+
+    const SOURCE: &str = "var greeting = \"hi\";\nfun outer(n) {\n  fun inner() { return n + 1.5; \
+                          }\n  return inner;\n}\ntry { print outer(1)(); } catch (e) {} finally { \
+                          print nil; }";
+
+    #[test]
+    fn test_round_trip()
+    {
+        let script = compile(SOURCE);
+        let loaded = deserialize(&serialize(&script)).unwrap();
+
+        assert_eq!(loaded.chunk.code, script.chunk.code);
+        assert_eq!(loaded.chunk.lines, script.chunk.lines);
+        assert_eq!(loaded.chunk.constants, script.chunk.constants);
+
+        let (outer, loaded_outer) = (&script.chunk.prototypes[0], &loaded.chunk.prototypes[0]);
+        assert_eq!(loaded_outer.name, "outer");
+        assert_eq!(loaded_outer.arity, 1);
+        assert_eq!(
+            loaded_outer.chunk.prototypes[0].upvalues,
+            outer.chunk.prototypes[0].upvalues
+        );
+    }
+
+    #[test]
+    fn test_corruption_is_detected()
+    {
+        let bytes = serialize(&compile(SOURCE));
+
+        let mut flipped = bytes.clone();
+        flipped[20] ^= 1;
+        assert!(deserialize(&flipped).is_err());
+
+        assert!(deserialize(&bytes[..bytes.len() - 1]).is_err());
+        assert!(deserialize(b"print 1;").is_err());
+
+        let mut version = bytes.clone();
+        version[4] = 99;
+        assert!(deserialize(&version).is_err());
+    }
+
+    #[test]
+    fn test_invalid_code_is_rejected()
+    {
+        let mut script = Prototype::default();
+        script.chunk.write(OpCode::Constant as u8, 1);
+        script.chunk.write(0, 1);
+        script.chunk.write(5, 1);
+        script.chunk.write(OpCode::Return as u8, 1);
+
+        // The checksum is valid, but the constant doesn't exist
+        assert!(deserialize(&serialize(&script)).is_err());
+
+        script.chunk.constants.push(Object::Num(1.0));
+        script.chunk.code[2] = 0;
+        deserialize(&serialize(&script)).unwrap();
+    }
+
+    /// A script whose code is `ops`, all on line 1
+    fn script(ops: &[u8]) -> Prototype
+    {
+        let mut script = Prototype::default();
+        for &op in ops
+        {
+            script.chunk.write(op, 1);
+        }
+        script
+    }
+
+    #[test]
+    fn test_missing_local_is_rejected()
+    {
+        let get = script(&[OpCode::GetLocal as u8, 200, OpCode::Return as u8]);
+        assert!(matches!(
+            deserialize(&serialize(&get)),
+            Err(LoxResult::BytecodeError { .. })
+        ));
+
+        // Slot 0 holds the script itself
+        let get = script(&[OpCode::GetLocal as u8, 0, OpCode::Return as u8]);
+        deserialize(&serialize(&get)).unwrap();
+    }
+
+    #[test]
+    fn test_stack_underflow_is_rejected()
+    {
+        let pops = script(&[OpCode::Pop as u8, OpCode::Pop as u8, OpCode::Return as u8]);
+        assert!(matches!(
+            deserialize(&serialize(&pops)),
+            Err(LoxResult::BytecodeError { .. })
+        ));
+
+        // A loop that pushes a value on each iteration
+        let growing = script(&[
+            OpCode::Nil as u8,
+            OpCode::Loop as u8,
+            0,
+            4,
+            OpCode::Return as u8,
+        ]);
+        assert!(deserialize(&serialize(&growing)).is_err());
+    }
+
+    #[test]
+    fn test_popping_captured_local_is_rejected()
+    {
+        let source = "{ var a = 1; var b = 2; fun f() { return a; } print f(); }";
+        let mut script = Rc::into_inner(compile(source)).unwrap();
+        deserialize(&serialize(&script)).unwrap();
+
+        // Capture `b` instead of `a`. The block's end pops `b` rather than
+        // closing it, as it doesn't know it's captured.
+        let code = &mut script.chunk.code;
+        let closure = code
+            .windows(5)
+            .position(|ops| ops[0] == OpCode::Closure as u8 && ops[3..] == [1, 1])
+            .unwrap();
+        code[closure + 4] = 2;
+        assert!(matches!(
+            deserialize(&serialize(&script)),
+            Err(LoxResult::BytecodeError { .. })
+        ));
+    }
+}
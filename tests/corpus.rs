@@ -0,0 +1,185 @@
+//! Runs every script in `tests/corpus` with each backend, checking its output
+//! against the expectations written in its comments:
+//!
+//! - `// expect: <line>`: the next line printed to standard output
+//! - `// expect runtime error: <message>`: the script fails with `<message>`
+//! - `// expect compile error: <message>`: the script is rejected with
+//!   `<message>` before it runs
+//!
+//! The `compiled` backend runs each script from a `.loxc` file instead, and the
+//! `optimized` one runs it with `-O`.
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+
+/// What running a script should produce
+struct Expectation
+{
+    stdout: Vec<String>,
+
+    /// The exit code and part of the message of the error the script fails
+    /// with
+    error: Option<(i32, String)>,
+}
+
+impl Expectation
+{
+    fn parse(source: &str) -> Self
+    {
+        let mut expectation = Self {
+            stdout: Vec::new(),
+            error: None,
+        };
+
+        for line in source.lines()
+        {
+            if let Some((_, expected)) = line.split_once("// expect: ")
+            {
+                expectation.stdout.push(expected.to_string());
+            }
+            else if let Some((_, message)) = line.split_once("// expect runtime error: ")
+            {
+                expectation.error = Some((70, message.to_string()));
+            }
+            else if let Some((_, message)) = line.split_once("// expect compile error: ")
+            {
+                expectation.error = Some((65, message.to_string()));
+            }
+        }
+
+        expectation
+    }
+}
+
+/// Run `script` with `backend`, describing how its output differs from what
+/// was expected
+fn check(backend: &str, script: &Path) -> Result<(), String>
+{
+    let expectation = Expectation::parse(&fs::read_to_string(script).unwrap());
+    let output = run(backend, script);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout: Vec<&str> = stdout.lines().collect();
+    if stdout != expectation.stdout
+    {
+        return Err(format!(
+            "expected output {:?}, got {stdout:?}\n{stderr}",
+            expectation.stdout
+        ));
+    }
+
+    match (&expectation.error, output.status.code())
+    {
+        (None, Some(0)) => Ok(()),
+        (Some((expected, message)), Some(code))
+            if code == *expected && stderr.contains(message.as_str()) =>
+        {
+            Ok(())
+        }
+        (Some((expected, message)), code) =>
+        {
+            Err(format!(
+                "expected exit code {expected} with \"{message}\", got exit code {code:?} with \
+                 {stderr}"
+            ))
+        }
+        (None, code) =>
+        {
+            Err(format!(
+                "expected success, got exit code {code:?} with {stderr}"
+            ))
+        }
+    }
+}
+
+/// Run `script` with `backend`. The `compiled` backend compiles the script to
+/// a `.loxc` file first, and runs that, unless compiling fails.
+fn run(backend: &str, script: &Path) -> Output
+{
+    let mut command = Command::new(env!("CARGO_BIN_EXE_lox"));
+    match backend
+    {
+        "compiled" => (),
+        // The tree backend with the optimizer enabled
+        "optimized" =>
+        {
+            return command.arg("-O").arg(script).output().unwrap();
+        }
+        _ =>
+        {
+            return command
+                .arg(format!("--backend={backend}"))
+                .arg(script)
+                .output()
+                .unwrap();
+        }
+    }
+
+    let compiled = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(script.file_name().unwrap())
+        .with_extension("loxc");
+    let output = Command::new(env!("CARGO_BIN_EXE_lox"))
+        .arg("compile")
+        .arg(script)
+        .arg("-o")
+        .arg(&compiled)
+        .output()
+        .unwrap();
+    if !output.status.success()
+    {
+        return output;
+    }
+
+    // Imports are still resolved relative to the original script
+    let directory = script.parent().unwrap();
+    command
+        .arg(format!("--module-path={}", directory.display()))
+        .arg("run")
+        .arg(compiled);
+    command.output().unwrap()
+}
+
+fn run_corpus(backend: &str)
+{
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut scripts: Vec<_> = fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "lox"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty());
+
+    let failures: Vec<String> = scripts
+        .iter()
+        .filter_map(|script| {
+            check(backend, script)
+                .err()
+                .map(|e| format!("{}: {e}", script.display()))
+        })
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed with the {backend} backend:\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_tree_backend() { run_corpus("tree"); }
+
+#[test]
+fn test_vm_backend() { run_corpus("vm"); }
+
+#[test]
+fn test_compiled_backend() { run_corpus("compiled"); }
+
+#[test]
+fn test_optimized_backend() { run_corpus("optimized"); }
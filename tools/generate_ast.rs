@@ -19,19 +19,22 @@ pub fn generate(output_dir: &str) -> io::Result<()>
         "Expr",
         &[
             "crate::error::*",
+            "crate::interpreter::resolver::Variable",
             "crate::object::*",
             "crate::tokens::*",
+            "std::cell::Cell",
             "std::rc::Rc",
         ],
         &[
-            "Assign   : Token name, Box<Expr> value",
+            "Assign   : Token name, Box<Expr> value, Cell<Variable> variable",
             "Binary   : Box<Expr> left, Token operator, Box<Expr> right",
             "Call     : Rc<Expr> callee, Token paren, Vec<Expr> arguments",
+            "Get      : Box<Expr> object, Token name",
             "Grouping : Box<Expr> expression",
             "Literal  : Option<Object> value",
             "Logical  : Box<Expr> left, Token operator, Box<Expr> right",
             "Unary    : Token operator, Box<Expr> right",
-            "Variable : Token name",
+            "Variable : Token name, Cell<Variable> variable",
         ],
     )?;
 
@@ -41,19 +44,30 @@ pub fn generate(output_dir: &str) -> io::Result<()>
         &[
             "crate::error::*",
             "crate::expr::*",
+            "crate::interpreter::resolver::{FunctionLayout, Variable}",
             "crate::tokens::*",
+            "std::cell::Cell",
             "std::rc::Rc",
         ],
         &[
-            "Block      : Vec<Stmt> statements",
+            "Block      : Vec<Stmt> statements, usize line",
             "Break      : Token token",
-            "Expression : Expr expression",
-            "Function   : Token name, Rc<Vec<Token>> params, Rc<Vec<Stmt>> body",
-            "If         : Expr condition, Box<Stmt> then_branch, Option<Box<Stmt>> else_branch",
-            "Print      : Expr expression",
+            "Continue   : Token token",
+            "Expression : Expr expression, usize line",
+            "Function   : Token name, Rc<Vec<Token>> params, Rc<Vec<Stmt>> body, Cell<Variable> \
+             variable, Rc<FunctionLayout> layout",
+            "If         : Expr condition, Box<Stmt> then_branch, Option<Box<Stmt>> else_branch, \
+             usize line",
+            "Import     : Token keyword, Token path, Option<Token> alias, Vec<Token> names, \
+             Vec<Cell<Variable>> variables",
+            "Print      : Expr expression, usize line",
             "Return     : Token keyword, Option<Expr> value",
-            "Var        : Token name, Option<Expr> initializer",
-            "While      : Expr condition, Box<Stmt> body",
+            "Throw      : Token keyword, Expr value",
+            "Try        : Token keyword, Vec<Stmt> body, Option<Token> catch_name, \
+             Option<Vec<Stmt>> catch_body, Option<Rc<Vec<Stmt>>> finally_body, Cell<Variable> \
+             catch_variable",
+            "Var        : Token name, Option<Expr> initializer, Cell<Variable> variable",
+            "While      : Expr condition, Box<Stmt> body, Option<Expr> increment, usize line",
         ],
     )?;
 